@@ -0,0 +1,379 @@
+//! Relay for devices that can't reach each other directly, e.g. on different subnets where mDNS doesn't reach.
+//!
+//! Devices keep a control connection open to the relay, announcing their device id. A device that wants to
+//! reach another asks the relay to connect it, the relay tells the other device over its control connection,
+//! and that device opens a second connection to accept. The relay then copies bytes between the two connections.
+//! Everything after that is the devices' own Noise handshake and encrypted frames, which the relay can't read,
+//! so it never holds any plaintext clipboard contents.
+//!
+//! Every connection starts with `"PCR1" | op: u8 | len: u8 | value`, where the value is a device id for
+//! `LISTEN` and `CONNECT` and a session token for `ACCEPT`. The relay answers `CONNECT` and `ACCEPT` with a
+//! single status byte, and sends listeners `len: u8 | token` for every incoming session, or a lone zero byte
+//! as a keepalive. This must match `src/sync/relay.rs` in the app.
+//!
+//! Nothing proves a device id belongs to the device listening for it, so the first device to listen keeps the id
+//! until its control connection closes, and a second `LISTEN` for it is refused. Otherwise anyone who can reach the
+//! relay could knock a device off it.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const MAGIC: &[u8; 4] = b"PCR1";
+const OP_LISTEN: u8 = 1;
+const OP_CONNECT: u8 = 2;
+const OP_ACCEPT: u8 = 3;
+
+const STATUS_OK: u8 = 0;
+const STATUS_NOT_FOUND: u8 = 1;
+const STATUS_TIMEOUT: u8 = 2;
+
+const DEFAULT_ADDRESS: &str = "0.0.0.0:7878";
+/// How long to wait for the first bytes of a new connection.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a device has to accept a session before the one connecting to it is told it timed out.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Relay {
+    /// Control connections of the devices listening, by device id.
+    listeners: Mutex<HashMap<String, Arc<Mutex<TcpStream>>>>,
+    /// Sessions waiting for the device being connected to, by token.
+    pending: Mutex<HashMap<String, mpsc::Sender<TcpStream>>>,
+    tokens: RandomState,
+    accept_timeout: Duration,
+}
+
+impl Relay {
+    fn new(accept_timeout: Duration) -> Self {
+        Self {
+            listeners: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            tokens: RandomState::new(),
+            accept_timeout,
+        }
+    }
+
+    /// Tokens only need to be hard to guess, a hijacked session still fails the devices' own handshake.
+    fn token(&self) -> String {
+        let mut hasher = self.tokens.build_hasher();
+        hasher.write_u64(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+fn main() {
+    let mut address = DEFAULT_ADDRESS.to_string();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => match args.next() {
+                Some(value) => address = value,
+                None => exit_with_usage(),
+            },
+            _ => exit_with_usage(),
+        }
+    }
+
+    let listener = match TcpListener::bind(&address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", address, e);
+            std::process::exit(1);
+        }
+    };
+    println!("relay listening on {}", address);
+    serve(listener, Arc::new(Relay::new(ACCEPT_TIMEOUT)));
+}
+
+fn serve(listener: TcpListener, relay: Arc<Relay>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let relay = Arc::clone(&relay);
+                std::thread::spawn(move || {
+                    let peer = stream.peer_addr();
+                    if let Err(e) = handle(stream, &relay) {
+                        println!("connection from {:?} ended: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Could not accept connection: {}", e),
+        }
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("usage: purple-relay [--listen <address:port>]  (default {})", DEFAULT_ADDRESS);
+    std::process::exit(2);
+}
+
+fn handle(mut stream: TcpStream, relay: &Arc<Relay>) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut magic = [0u8; 4];
+    stream.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a relay client"));
+    }
+    let mut op = [0u8; 2];
+    stream.read_exact(&mut op)?;
+    let mut value = vec![0u8; op[1] as usize];
+    stream.read_exact(&mut value)?;
+    let value = String::from_utf8(value)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "request is not utf-8"))?;
+
+    match op[0] {
+        OP_LISTEN => listen(stream, relay, value),
+        OP_CONNECT => connect(stream, relay, &value),
+        OP_ACCEPT => accept(stream, relay, &value),
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown op {}", other),
+        )),
+    }
+}
+
+/// Keep a device's control connection until it goes away. Refused if the device id is already listening.
+fn listen(mut stream: TcpStream, relay: &Relay, device_id: String) -> io::Result<()> {
+    // a keepalive that can't be written in time means the device is gone, and frees its id
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let control = Arc::new(Mutex::new(stream.try_clone()?));
+    {
+        let mut listeners = relay.listeners.lock().unwrap();
+        if listeners.contains_key(&device_id) {
+            _ = stream.shutdown(Shutdown::Both);
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already listening", device_id),
+            ));
+        }
+        listeners.insert(device_id.clone(), Arc::clone(&control));
+    }
+    println!("{} is listening", device_id);
+
+    let keepalive = Arc::clone(&control);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(KEEPALIVE_INTERVAL);
+        let mut control = keepalive.lock().unwrap();
+        if control.write_all(&[0]).is_err() {
+            _ = control.shutdown(Shutdown::Both);
+            break;
+        }
+    });
+
+    // clients never send anything else on this connection, so reading only returns once it closes
+    stream.set_read_timeout(None)?;
+    let result = io::copy(&mut stream, &mut io::sink()).map(|_| ());
+    _ = stream.shutdown(Shutdown::Both);
+
+    relay.listeners.lock().unwrap().remove(&device_id);
+    println!("{} stopped listening", device_id);
+    result
+}
+
+/// Ask a listening device to accept a session, then join the two connections.
+fn connect(mut stream: TcpStream, relay: &Relay, device_id: &str) -> io::Result<()> {
+    let control = relay
+        .listeners
+        .lock()
+        .unwrap()
+        .get(device_id)
+        .cloned();
+    let Some(control) = control else {
+        return stream.write_all(&[STATUS_NOT_FOUND]);
+    };
+
+    let token = relay.token();
+    let (tx, rx) = mpsc::channel();
+    relay.pending.lock().unwrap().insert(token.clone(), tx);
+
+    let mut notification = vec![token.len() as u8];
+    notification.extend_from_slice(token.as_bytes());
+    let notified = control.lock().unwrap().write_all(&notification);
+
+    let accepted = notified
+        .ok()
+        .and_then(|_| rx.recv_timeout(relay.accept_timeout).ok());
+    relay.pending.lock().unwrap().remove(&token);
+    let Some(mut other) = accepted else {
+        return stream.write_all(&[STATUS_TIMEOUT]);
+    };
+
+    stream.write_all(&[STATUS_OK])?;
+    other.write_all(&[STATUS_OK])?;
+    println!("joined a session to {}", device_id);
+    splice(stream, other)
+}
+
+fn accept(mut stream: TcpStream, relay: &Relay, token: &str) -> io::Result<()> {
+    let waiting = relay.pending.lock().unwrap().remove(token);
+    match waiting {
+        Some(tx) => {
+            if let Err(mpsc::SendError(mut stream)) = tx.send(stream) {
+                stream.write_all(&[STATUS_TIMEOUT])?;
+            }
+            Ok(())
+        }
+        None => stream.write_all(&[STATUS_NOT_FOUND]),
+    }
+}
+
+/// Copy bytes both ways until either side closes.
+fn splice(a: TcpStream, b: TcpStream) -> io::Result<()> {
+    a.set_read_timeout(None)?;
+    b.set_read_timeout(None)?;
+    let (mut a_read, mut b_write) = (a.try_clone()?, b.try_clone()?);
+    let forward = std::thread::spawn(move || {
+        let copied = io::copy(&mut a_read, &mut b_write);
+        _ = a_read.shutdown(Shutdown::Both);
+        _ = b_write.shutdown(Shutdown::Both);
+        copied
+    });
+    let (mut b_read, mut a_write) = (b, a);
+    let back = io::copy(&mut b_read, &mut a_write);
+    _ = b_read.shutdown(Shutdown::Both);
+    _ = a_write.shutdown(Shutdown::Both);
+
+    let forward = forward.join().unwrap_or(Ok(0));
+    println!(
+        "session closed after {} and {} encrypted bytes",
+        forward.as_ref().unwrap_or(&0),
+        back.as_ref().unwrap_or(&0)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A relay on a free port on localhost, with a short accept timeout.
+    fn start() -> (String, Arc<Relay>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let relay = Arc::new(Relay::new(Duration::from_millis(200)));
+        let serving = Arc::clone(&relay);
+        std::thread::spawn(move || serve(listener, serving));
+        (address, relay)
+    }
+
+    fn request(address: &str, op: u8, value: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut request = MAGIC.to_vec();
+        request.extend([op, value.len() as u8]);
+        request.extend_from_slice(value.as_bytes());
+        stream.write_all(&request).unwrap();
+        stream
+    }
+
+    fn status(stream: &mut TcpStream) -> u8 {
+        let mut status = [0u8; 1];
+        stream.read_exact(&mut status).unwrap();
+        status[0]
+    }
+
+    /// Listen as `device_id`, returning once the relay has taken the control connection.
+    fn listen(address: &str, relay: &Relay, device_id: &str) -> TcpStream {
+        let control = request(address, OP_LISTEN, device_id);
+        let deadline = Instant::now() + TIMEOUT;
+        while !relay.listeners.lock().unwrap().contains_key(device_id) {
+            assert!(Instant::now() < deadline, "{} never got to listen", device_id);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        control
+    }
+
+    fn token(control: &mut TcpStream) -> String {
+        let mut len = [0u8; 1];
+        control.read_exact(&mut len).unwrap();
+        let mut token = vec![0u8; len[0] as usize];
+        control.read_exact(&mut token).unwrap();
+        String::from_utf8(token).unwrap()
+    }
+
+    fn closed(stream: &mut TcpStream) -> bool {
+        matches!(stream.read(&mut [0u8; 1]), Ok(0) | Err(_))
+    }
+
+    #[test]
+    fn splices_both_ways() {
+        let (address, relay) = start();
+        let mut control = listen(&address, &relay, "device-b");
+
+        let mut a = request(&address, OP_CONNECT, "device-b");
+        let mut b = request(&address, OP_ACCEPT, &token(&mut control));
+        assert_eq!(status(&mut b), STATUS_OK);
+        assert_eq!(status(&mut a), STATUS_OK);
+
+        a.write_all(b"from a").unwrap();
+        let mut buf = [0u8; 6];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"from a");
+        b.write_all(b"from b").unwrap();
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"from b");
+
+        // either side closing ends the session for the other
+        a.shutdown(Shutdown::Both).unwrap();
+        assert!(closed(&mut b));
+    }
+
+    #[test]
+    fn device_not_listening() {
+        let (address, _) = start();
+        let mut a = request(&address, OP_CONNECT, "nobody");
+        assert_eq!(status(&mut a), STATUS_NOT_FOUND);
+    }
+
+    #[test]
+    fn unknown_token() {
+        let (address, _) = start();
+        let mut b = request(&address, OP_ACCEPT, "0123456789abcdef");
+        assert_eq!(status(&mut b), STATUS_NOT_FOUND);
+    }
+
+    #[test]
+    fn session_not_accepted_in_time() {
+        let (address, relay) = start();
+        let mut control = listen(&address, &relay, "device-b");
+        let mut a = request(&address, OP_CONNECT, "device-b");
+        let token = token(&mut control);
+        assert_eq!(status(&mut a), STATUS_TIMEOUT);
+        // too late to accept
+        let mut b = request(&address, OP_ACCEPT, &token);
+        assert_eq!(status(&mut b), STATUS_NOT_FOUND);
+    }
+
+    #[test]
+    fn listener_cant_be_taken_over() {
+        let (address, relay) = start();
+        let mut control = listen(&address, &relay, "device-b");
+        let mut impostor = request(&address, OP_LISTEN, "device-b");
+        assert!(closed(&mut impostor));
+
+        // sessions still go to the device that was listening first
+        let _a = request(&address, OP_CONNECT, "device-b");
+        token(&mut control);
+
+        // once it goes away, the id is free again
+        control.shutdown(Shutdown::Both).unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        while relay.listeners.lock().unwrap().contains_key("device-b") {
+            assert!(Instant::now() < deadline, "the listener was never dropped");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        listen(&address, &relay, "device-b");
+    }
+}
@@ -1,9 +1,18 @@
 use gtk::gdk::Atom;
 use gtk::glib::error::BoolError;
 use gtk::{gdk::SELECTION_CLIPBOARD, Clipboard, TargetEntry, TargetFlags};
+use std::ffi::OsString;
+use std::io::Read;
+use std::os::unix::ffi::OsStringExt;
 use std::path::PathBuf;
+use wl_clipboard_rs::{copy, paste};
 
 use crate::clipboard_files::ClipboardError;
+use crate::wayland;
+
+const URI_LIST: &str = "text/uri-list";
+/// What GNOME's and other GTK file managers paste files from.
+const GNOME_FILES: &str = "x-special/gnome-copied-files";
 
 impl From<BoolError> for ClipboardError {
     fn from(e: BoolError) -> Self {
@@ -12,6 +21,9 @@ impl From<BoolError> for ClipboardError {
 }
 
 pub(crate) fn read_clipboard() -> Result<Vec<PathBuf>, ClipboardError> {
+    if wayland::active() {
+        return read_wayland();
+    }
     gtk::init()?;
     let cb = Clipboard::get(&SELECTION_CLIPBOARD);
     let paths = cb.wait_for_uris();
@@ -25,6 +37,9 @@ pub(crate) fn read_clipboard() -> Result<Vec<PathBuf>, ClipboardError> {
 }
 
 pub(crate) fn write_clipboard(paths: &Vec<PathBuf>) -> Result<(), ClipboardError> {
+    if wayland::active() {
+        return write_wayland(paths);
+    }
     gtk::init()?;
     let uri_list = paths
         .iter()
@@ -45,3 +60,81 @@ pub(crate) fn write_clipboard(paths: &Vec<PathBuf>) -> Result<(), ClipboardError
         )),
     }
 }
+
+fn read_wayland() -> Result<Vec<PathBuf>, ClipboardError> {
+    let mut pipe = match paste::get_contents(
+        paste::ClipboardType::Regular,
+        paste::Seat::Unspecified,
+        paste::MimeType::Specific(URI_LIST),
+    ) {
+        Ok((pipe, _)) => pipe,
+        Err(paste::Error::NoSeats | paste::Error::ClipboardEmpty | paste::Error::NoMimeType) => {
+            return Err(ClipboardError::NoFiles)
+        }
+        Err(e) => return Err(ClipboardError::SystemError(e.to_string())),
+    };
+    let mut uri_list = String::new();
+    pipe.read_to_string(&mut uri_list)
+        .map_err(|e| ClipboardError::SystemError(format!("Could not read file paths: {e}")))?;
+    let paths = uri_list
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.trim().strip_prefix("file://").map(unescape))
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        return Err(ClipboardError::NoFiles);
+    }
+    Ok(paths)
+}
+
+/// The paths are offered until something else is copied, from a thread that answers the apps pasting them.
+fn write_wayland(paths: &[PathBuf]) -> Result<(), ClipboardError> {
+    let uris = paths
+        .iter()
+        .map(|p| format!("file://{}", p.to_string_lossy()))
+        .collect::<Vec<_>>();
+    let source = |mime_type: &str, contents: String| copy::MimeSource {
+        source: copy::Source::Bytes(contents.into_bytes().into()),
+        mime_type: copy::MimeType::Specific(mime_type.to_string()),
+    };
+    let sources = vec![
+        source(URI_LIST, uris.join("\r\n")),
+        source(GNOME_FILES, format!("copy\n{}", uris.join("\n"))),
+    ];
+
+    let mut options = copy::Options::new();
+    options.foreground(true);
+    let prepared = options
+        .prepare_copy_multi(sources)
+        .map_err(|e| ClipboardError::SystemError(e.to_string()))?;
+    std::thread::spawn(move || {
+        if let Err(e) = prepared.serve() {
+            log::error!("Stopped offering file paths on the clipboard: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Undo the percent-encoding file managers put on the paths in a URI.
+fn unescape(path: &str) -> PathBuf {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(decoded))
+}
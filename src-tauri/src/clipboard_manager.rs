@@ -1,11 +1,18 @@
 use serde_json::json;
-use tauri::{AppHandle, Emitter};
+use tauri::{AppHandle, Emitter, Manager as TauriManager};
+use tauri_plugin_clipboard_manager::ClipboardExt;
 
-use crate::contents::{load_pinned, store_pinned, Contents};
-use std::collections::{HashSet, VecDeque};
-use std::sync::Arc;
+use crate::contents::{load_pin_set, load_pinned, store_pin_set, store_pinned, Contents, ItemMeta};
+use crate::hlc;
+use crate::pinned::{pin_id, PinSet, PinState};
+use crate::sync::{BoardView, SyncManager};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
 
 const HISTORY_LEN: usize = 20;
+/// How many received item uids to remember when filtering out duplicates.
+const SEEN_LEN: usize = 256;
 
 struct ContentsStore {
     store: HashSet<Arc<Contents>>
@@ -31,10 +38,155 @@ impl ContentsStore {
     }
 }
 
-pub struct ClipboardManager {
+/// An item in history or the pinned list with where and when it was copied. Equal items share their contents in
+/// the store, so for history this is kept here rather than taken from the item.
+#[derive(Debug, Clone, serde::Serialize)]
+struct HistoryEntry {
+    #[serde(flatten)]
+    item: Arc<Contents>,
+    #[serde(flatten)]
+    meta: ItemMeta,
+}
+
+impl HistoryEntry {
+    fn new(item: Arc<Contents>) -> Self {
+        let meta = item.meta().clone();
+        Self { item, meta }
+    }
+}
+
+/// What keeps items received from other devices from going round in a loop: one that arrives twice is only taken
+/// in once, and writing one to the clipboard isn't taken for a new copy to share.
+struct Echoes {
+    /// uids of the items most recently received from other devices
+    seen: VecDeque<Uuid>,
+    /// a received item that was just written to the system clipboard. The clipboard change it causes is its echo,
+    /// and must not be ingested or shared again as if it were a new copy.
+    expected: Option<Arc<Contents>>,
+}
+
+impl Echoes {
+    fn new() -> Self {
+        Self { seen: VecDeque::with_capacity(SEEN_LEN), expected: None }
+    }
+
+    /// Note that an item was received, returning whether it should be taken in. An item pushed on purpose always is.
+    fn receive(&mut self, uid: Uuid, pushed: bool) -> bool {
+        if self.seen.contains(&uid) && !pushed {
+            return false;
+        }
+        if self.seen.len() == SEEN_LEN {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(uid);
+        true
+    }
+
+    fn written(&mut self, item: Arc<Contents>) {
+        self.expected = Some(item);
+    }
+
+    /// Whether a clipboard change is the echo of writing a received item. Only the first change after writing one
+    /// can be, anything later is a real copy.
+    fn is_echo(&mut self, item: &Contents) -> bool {
+        self.expected.take().is_some_and(|echo| *echo == *item)
+    }
+
+    fn clear(&mut self) {
+        self.seen.clear();
+        self.expected = None;
+    }
+}
+
+/// History and what goes into it, apart from the system clipboard and the app: items copied here, and items
+/// received from other devices with their echoes filtered out.
+pub(crate) struct History {
     store: ContentsStore,
-    history: VecDeque<Arc<Contents>>,
+    /// newest first, by when each item was copied on whichever device it was copied on
+    entries: VecDeque<HistoryEntry>,
+    echoes: Echoes,
+}
+
+impl History {
+    pub(crate) fn new() -> Self {
+        Self { store: ContentsStore::new(), entries: VecDeque::with_capacity(HISTORY_LEN), echoes: Echoes::new() }
+    }
+
+    /// Take in what was found on the clipboard after it changed. Returns the item to share, or `None` if the change
+    /// was the echo of writing a received item.
+    pub(crate) fn copied(&mut self, item: Contents) -> Option<Arc<Contents>> {
+        if self.echoes.is_echo(&item) {
+            log::debug!("ignoring clipboard change caused by writing a received item");
+            return None;
+        }
+        Some(self.push(item))
+    }
+
+    /// Take in an item another device sent us, which the caller then writes to the clipboard if `write_to_clipboard`
+    /// is set. Returns the item as stored, or `None` if it had already been received.
+    pub(crate) fn received(
+        &mut self,
+        item: Arc<Contents>,
+        write_to_clipboard: bool,
+        pushed: bool,
+    ) -> Option<Arc<Contents>> {
+        let uid = item.meta().uid;
+        if !self.echoes.receive(uid, pushed) {
+            log::debug!("already received item {}, ignoring", uid);
+            return None;
+        }
+
+        let item = Arc::unwrap_or_clone(item);
+        hlc::observe(item.meta().time);
+        // don't leave an older copy of the same item further down
+        self.entries.retain(|e| *e.item != item);
+        let item = self.push(item);
+        if write_to_clipboard {
+            self.echoes.written(Arc::clone(&item));
+        }
+        Some(item)
+    }
+
+    /// Add an item to history where its timestamp puts it, which for an item received late can be below items
+    /// that were copied after it. Returns the item with the meta it came with.
+    fn push(&mut self, item: Contents) -> Arc<Contents> {
+        let meta = item.meta().clone();
+        let stored = Arc::clone(self.store.add(item));
+        let key = (meta.time, meta.uid);
+        let index = self
+            .entries
+            .iter()
+            .position(|e| (e.meta.time, e.meta.uid) < key)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(index, HistoryEntry { item: Arc::clone(&stored), meta: meta.clone() });
+
+        if self.entries.len() > HISTORY_LEN {
+            self.entries.pop_back().unwrap();
+            self.store.prune();
+        }
+
+        // an equal item that was already stored has the uid and time of when it was copied before
+        if *stored.meta() == meta {
+            stored
+        } else {
+            Arc::new(Arc::unwrap_or_clone(stored).with_meta(meta))
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.echoes.clear();
+    }
+}
+
+pub struct ClipboardManager {
+    history: History,
+    /// pinned items in list order, the ones in `pins` that we have the contents of
     pinned: Vec<Arc<Contents>>,
+    /// which items are pinned and in what order, merged with the other trusted devices
+    pins: PinSet,
+    /// contents of pinned items by pin id
+    pin_contents: HashMap<Uuid, Arc<Contents>>,
     app: AppHandle,
 }
 
@@ -44,8 +196,7 @@ impl ClipboardManager {
 
         let app = app_handle.clone();
 
-        let mut store = ContentsStore::new();
-        let mut history = VecDeque::with_capacity(HISTORY_LEN);
+        let mut history = History::new();
 
         let pinned = load_pinned(&app)
             .unwrap_or_else(|e| {
@@ -54,18 +205,26 @@ impl ClipboardManager {
             })
             .into_iter()
             .map(|item| {
-                Arc::clone(store.add(item))
+                Arc::clone(history.store.add(item))
             })
-            .collect();
+            .collect::<Vec<_>>();
+        let pin_contents = pinned.iter().map(|item| (pin_id(item), Arc::clone(item))).collect::<HashMap<_, _>>();
+        let pins = load_pin_set(&app)
+            .unwrap_or_else(|e| {
+                log::error!("Unable to load pinned list replica: {:#}", e);
+                None
+            })
+            .unwrap_or_else(|| PinSet::from_ids(pinned.iter().map(|item| pin_id(item))));
 
         if let Some(item) = Contents::try_from_clipboard(&app) {
-            history.push_front(Arc::clone(store.add(item)));
+            history.entries.push_front(HistoryEntry::new(Arc::clone(history.store.add(item))));
         }
 
         Self {
-            store,
             history,
             pinned,
+            pins,
+            pin_contents,
             app,
         }
     }
@@ -73,54 +232,122 @@ impl ClipboardManager {
     pub fn emit(&self) {
         let _ = self
             .app
-            .emit(
-                "update",
-                json!({
-                    "history": self.history,
-                    "pinned": self.pinned
-                }),
-            )
+            .emit("update", self.snapshot())
             .map_err(|e| log::error!("Could not emit pinned event {}", e));
     }
 
+    /// History, pinned items and boards, as sent to the window on every update.
+    pub fn snapshot(&self) -> serde_json::Value {
+        let pinned = self.pinned.iter().map(|item| HistoryEntry::new(Arc::clone(item))).collect::<Vec<_>>();
+        json!({
+            "history": self.history.entries,
+            "pinned": pinned,
+            "boards": self.boards()
+        })
+    }
+
+    /// History entries that came from `from`, a device id or one of `local`, `web` and `cli`.
+    pub fn history_from(&self, from: &str) -> serde_json::Value {
+        json!(self.history.entries.iter().filter(|e| e.meta.source.is(from)).collect::<Vec<_>>())
+    }
+
     // when this is called, we already know the current clipboard contents are outdated
     pub fn check(&mut self) {
         if let Some(new_item) = Contents::try_from_clipboard(&self.app) {
-            if self.history.len() == HISTORY_LEN {
-                self.history.pop_back().unwrap();
-                self.store.prune();
-            }
-    
-            self.history.push_front(Arc::clone(self.store.add(new_item)));
+            let Some(item) = self.history.copied(new_item) else {
+                return;
+            };
+            self.share(&item);
+        }
+        self.emit();
+    }
+
+    /// Add an item that wasn't copied to this device's clipboard, like one pasted into the web client, to history
+    /// as if it had been copied here. Its meta says how it really got here.
+    pub fn add(&mut self, item: Contents) -> Arc<Contents> {
+        let item = self.history.push(item);
+        self.share(&item);
+        self.emit();
+        item
+    }
+
+    /// Add an item that another device sent us to history, optionally making it the current clipboard
+    /// contents too. Returns the item as stored, or `None` if it had already been received. An item the other
+    /// device pushed to us on purpose is always taken in.
+    pub fn receive(
+        &mut self,
+        item: Arc<Contents>,
+        write_to_clipboard: bool,
+        pushed: bool,
+    ) -> Option<Arc<Contents>> {
+        let item = self.history.received(item, write_to_clipboard, pushed)?;
+        if write_to_clipboard {
+            item.try_to_clipboard(&self.app);
         }
         self.emit();
+        Some(item)
+    }
+
+    /// An item in history, the pinned list, or on a board we subscribe to.
+    pub fn get(&self, id: u32) -> Option<Arc<Contents>> {
+        self.history.store.get_by_id(id).cloned().or_else(|| self.with_sync(|s| s.board_item(id)).flatten())
+    }
+
+    fn share(&self, item: &Arc<Contents>) {
+        let state = self.app.state::<Mutex<Option<SyncManager>>>();
+        match state.lock() {
+            Ok(sync) => {
+                if let Some(Err(e)) = sync.as_ref().map(|s| s.broadcast(item)) {
+                    log::warn!("Not sharing item: {:#}", e);
+                }
+            }
+            Err(e) => log::error!("Couldn't access sync manager: {}", e),
+        };
+    }
+
+    /// Boards we subscribe to, shown after the pinned items
+    fn boards(&self) -> Vec<BoardView> {
+        self.with_sync(|s| s.board_views()).unwrap_or_default()
+    }
+
+    // the sync manager is managed after this, so it may not be there yet
+    fn with_sync<T>(&self, f: impl FnOnce(&SyncManager) -> T) -> Option<T> {
+        let state = self.app.try_state::<Mutex<Option<SyncManager>>>()?;
+        let sync = state
+            .lock()
+            .map_err(|e| log::error!("Couldn't access sync manager: {}", e))
+            .ok()?;
+        sync.as_ref().map(f)
     }
 
     pub fn copy(&mut self, id: u32) {
         if let Some((index, _)) = self
             .history
+            .entries
             .iter()
             .enumerate()
-            .find(|(_, c)| id == c.id())
+            .find(|(_, e)| id == e.item.id())
         {
             if index == 0 {
                 return; // don't copy an item currently in the clipboard
             }
-            self.history.remove(index).unwrap();
+            self.history.entries.remove(index).unwrap();
         } 
 
-        self.store.get_by_id(id).map(|c| c.try_to_clipboard(&self.app));
+        self.get(id).map(|c| c.try_to_clipboard(&self.app));
     }
 
     pub fn pin(&mut self, id: u32) {
-        let Some(item) = self.store.get_by_id(id) else {
+        let Some(item) = self.get(id) else {
             return
         };
+        // an item pinned from a board has to outlive the board
+        let item = Arc::clone(self.history.store.add(Arc::unwrap_or_clone(item)));
 
-        if !self.pinned.contains(item) {
-            self.pinned.insert(0, Arc::clone(item));
-            self.emit();
-            self.save_pinned();
+        let pid = pin_id(&item);
+        if self.pins.pin(pid) {
+            self.pin_contents.insert(pid, item);
+            self.pins_changed(true);
         } else {
             log::warn!("tried to pin an item that was already in the pinned items vec");
             log::warn!("{}, {:?}", id, self.pinned);
@@ -128,25 +355,165 @@ impl ClipboardManager {
     }
 
     pub fn unpin(&mut self, id: u32) {
-        if let Some((index, _)) = self
-            .pinned
-            .iter()
-            .enumerate()
-            .find(|(_, f)| id == f.id())
-        {
-            self.pinned.remove(index);
-            self.store.prune();
-            self.emit();
-            self.save_pinned();
+        if let Some(item) = self.pinned.iter().find(|f| id == f.id()) {
+            self.pins.unpin(&pin_id(item));
+            self.pins_changed(true);
         } else {
             log::warn!("tried to unpin an item that wasnt in the pinned items vec");
             log::warn!("{}, {:?}", id, self.pinned);
         }
     }
 
+    /// Move a pinned item to `index` in the pinned list.
+    pub fn move_pinned(&mut self, id: u32, index: usize) {
+        let Some(pid) = self.pinned.iter().find(|f| id == f.id()).map(|item| pin_id(item)) else {
+            log::warn!("tried to move an item that wasnt in the pinned items vec");
+            return
+        };
+        // the index is into the list as shown, which leaves out pins we don't have the contents of yet, so place
+        // the item in front of whichever shown item should end up right after it
+        let others = self.pins.ids().into_iter().filter(|p| *p != pid).collect::<Vec<_>>();
+        let target = self
+            .pinned
+            .iter()
+            .map(|item| pin_id(item))
+            .filter(|p| *p != pid)
+            .nth(index)
+            .and_then(|next| others.iter().position(|p| *p == next))
+            .unwrap_or(others.len());
+        if self.pins.move_to(&pid, target) {
+            self.pins_changed(true);
+        }
+    }
+
+    pub fn pin_state(&self) -> PinState {
+        self.pins.state().clone()
+    }
+
+    /// Merge the pinned list of another device into ours. Returns whether ours changed, and the pin ids we still
+    /// need the contents of.
+    pub fn merge_pins(&mut self, other: &PinState) -> (bool, Vec<Uuid>) {
+        let changed = self.pins.merge(other);
+        if changed {
+            self.pins_changed(false);
+        }
+        let missing = self
+            .pins
+            .ids()
+            .into_iter()
+            .filter(|id| !self.pin_contents.contains_key(id))
+            .collect();
+        (changed, missing)
+    }
+
+    pub fn pinned_item(&self, pid: &Uuid) -> Option<Arc<Contents>> {
+        self.pin_contents.get(pid).cloned()
+    }
+
+    /// Take in the contents of a pinned item another device has, if it's still pinned and we didn't have them.
+    pub fn receive_pinned(&mut self, item: Contents) {
+        let pid = pin_id(&item);
+        if !self.pins.contains(&pid) || self.pin_contents.contains_key(&pid) {
+            return;
+        }
+        let item = Arc::clone(self.history.store.add(item));
+        self.pin_contents.insert(pid, item);
+        self.pins_changed(false);
+    }
+
+    /// Rebuild the pinned list after the replica or the contents changed, then save and show it. Only changes
+    /// made here are shared, merged ones came from other devices to begin with.
+    fn pins_changed(&mut self, share: bool) {
+        let ids = self.pins.ids();
+        self.pin_contents.retain(|pid, _| ids.contains(pid));
+        self.pinned = ids.iter().filter_map(|pid| self.pin_contents.get(pid).cloned()).collect();
+        self.history.store.prune();
+        self.emit();
+        self.save_pinned();
+        if share {
+            self.share_pins();
+        }
+    }
+
+    fn share_pins(&self) {
+        let state = self.app.state::<Mutex<Option<SyncManager>>>();
+        match state.lock() {
+            Ok(sync) => {
+                if let Some(s) = sync.as_ref() {
+                    s.share_pins(self.pins.state());
+                }
+            }
+            Err(e) => log::error!("Couldn't access sync manager: {}", e),
+        };
+    }
+
+    /// Clear history, pinned items and the clipboard itself, after another device revoked this one and asked for it.
+    /// Saving the now empty pinned list clears the stored images too.
+    pub fn wipe(&mut self) {
+        if let Err(e) = self.app.clipboard().clear() {
+            log::error!("Could not clear the clipboard: {}", e);
+        }
+        self.history.clear();
+        for pid in self.pins.ids() {
+            self.pins.unpin(&pid);
+        }
+        self.pins_changed(false);
+    }
+
     fn save_pinned(&self) {
         if let Err(e) = store_pinned(&self.pinned, &self.app) {
             log::error!("Could not store pinned items: {:#}", e)
         }
+        if let Err(e) = store_pin_set(&self.pins, &self.app) {
+            log::error!("Could not store pinned list replica: {:#}", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Contents {
+        Contents::from_text(text.to_string())
+    }
+
+    #[test]
+    fn items_received_twice_are_taken_in_once() {
+        let mut history = History::new();
+        let item = Arc::new(text("twice"));
+        // sent again after a reconnect, or by two devices that both had it queued
+        assert!(history.received(Arc::clone(&item), true, false).is_some());
+        assert!(history.received(Arc::clone(&item), true, false).is_none());
+        // unless it was pushed on purpose
+        assert!(history.received(item, true, true).is_some());
+        assert_eq!(history.entries.len(), 1);
+    }
+
+    #[test]
+    fn only_the_first_change_is_an_echo() {
+        let mut history = History::new();
+        history.received(Arc::new(text("hello")), true, false).unwrap();
+        assert!(history.copied(text("hello")).is_none());
+        // copying the same text again later is a real copy, and shared
+        assert!(history.copied(text("hello")).is_some());
+    }
+
+    #[test]
+    fn received_items_not_written_have_no_echo() {
+        let mut history = History::new();
+        history.received(Arc::new(text("hello")), false, false).unwrap();
+        assert!(history.copied(text("hello")).is_some());
+    }
+
+    #[test]
+    fn seen_items_are_forgotten_oldest_first() {
+        let mut echoes = Echoes::new();
+        let first = Uuid::new_v4();
+        assert!(echoes.receive(first, false));
+        for _ in 0..SEEN_LEN {
+            assert!(echoes.receive(Uuid::new_v4(), false));
+        }
+        assert!(echoes.receive(first, false));
     }
 }
@@ -1,10 +1,18 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use tauri::{AppHandle, Manager as TauriManager};
 
 use crate::{
     clipboard_manager::ClipboardManager,
+    contents::Contents,
     hook_manager::{send_ctrl_v, HookManager},
+    path_mapping,
+    settings::{store_settings, Settings},
+    sync::{
+        AvailableBoard, Delivery, DeviceStatus, PairingCode, Peer, PublishedBoard, QueuedItem, SendError,
+        SharingPolicy, StaticPeerStatus, SyncManager, TrustedDevice,
+    },
+    web::{self, NetworkInterface, WebClient, WebLink},
 };
 
 #[tauri::command]
@@ -17,12 +25,25 @@ pub async fn paste_item(
         "copying item with id {} from history to clipboard and pasting",
         id
     );
+    paste(&state, id)?;
+    hide(&app);
+    Ok(())
+}
+
+/// Write an item to the clipboard and paste it wherever the cursor is.
+pub fn paste(state: &Mutex<Option<ClipboardManager>>, id: u32) -> Result<(), String> {
     let mut clip = state
         .lock()
         .map_err(|e| format!("Could not access the clipboard handler {}", e))?;
+    let unmapped = clip
+        .as_ref()
+        .and_then(|s| s.get(id))
+        .is_some_and(|item| !path_mapping::unmapped(&item).is_empty());
+    if unmapped {
+        return Err("These paths are from another system, add a path mapping for them in settings".to_string());
+    }
     clip.as_mut().map(|s| s.copy(id));
     send_ctrl_v();
-    hide(&app);
     Ok(())
 }
 
@@ -37,12 +58,31 @@ pub async fn request_update(
     Ok(())
 }
 
+/// History entries that came from a device, given by its id, or from `local`, `web` or `cli`.
+#[tauri::command]
+pub async fn history_from(
+    state: tauri::State<'_, Mutex<Option<ClipboardManager>>>,
+    from: String,
+) -> Result<serde_json::Value, String> {
+    let clip = state
+        .lock()
+        .map_err(|e| format!("Could not access the clipboard handler {}", e))?;
+    Ok(clip
+        .as_ref()
+        .map(|s| s.history_from(&from))
+        .unwrap_or_default())
+}
+
 #[tauri::command]
 pub async fn pin_item(
     state: tauri::State<'_, Mutex<Option<ClipboardManager>>>,
     id: u32,
 ) -> Result<(), String> {
     log::info!("pinning item with id: {}", id);
+    pin(&state, id)
+}
+
+pub fn pin(state: &Mutex<Option<ClipboardManager>>, id: u32) -> Result<(), String> {
     let mut clip = state
         .lock()
         .map_err(|e| format!("Could not access the clipboard handler {}", e))?;
@@ -63,6 +103,469 @@ pub async fn unpin_item(
     Ok(())
 }
 
+#[tauri::command]
+pub async fn move_pinned_item(
+    state: tauri::State<'_, Mutex<Option<ClipboardManager>>>,
+    id: u32,
+    index: usize,
+) -> Result<(), String> {
+    log::info!("moving pinned item with id {} to {}", id, index);
+    let mut clip = state
+        .lock()
+        .map_err(|e| format!("Could not access the clipboard handler {}", e))?;
+    if let Some(s) = clip.as_mut() {
+        s.move_pinned(id, index);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn list_devices(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+) -> Result<Vec<Peer>, String> {
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    Ok(sync.as_ref().map(|s| s.devices()).unwrap_or_default())
+}
+
+#[tauri::command]
+pub async fn pair_device(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    device_id: String,
+    code: Option<String>,
+) -> Result<Option<PairingCode>, String> {
+    log::info!("pairing with device {}", device_id);
+    // the lock is let go of before connecting, which would hold up everything else that needs the sync manager
+    let request = {
+        let sync = state
+            .lock()
+            .map_err(|e| format!("Could not access the sync manager {}", e))?;
+        sync.as_ref()
+            .ok_or_else(|| "Device sync is not running".to_string())?
+            .pair(&device_id, code)
+            .map_err(|e| format!("{:#}", e))?
+    };
+    match request {
+        Some(request) => request.send().map(Some).map_err(|e| format!("{:#}", e)),
+        None => Ok(None),
+    }
+}
+
+#[tauri::command]
+pub async fn list_trusted_devices(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+) -> Result<Vec<TrustedDevice>, String> {
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    match sync.as_ref() {
+        Some(s) => s.trusted_devices().map_err(|e| format!("{:#}", e)),
+        None => Ok(vec![]),
+    }
+}
+
+#[tauri::command]
+pub async fn list_static_peers(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+) -> Result<Vec<StaticPeerStatus>, String> {
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    match sync.as_ref() {
+        Some(s) => s.static_peers().map_err(|e| format!("{:#}", e)),
+        None => Ok(vec![]),
+    }
+}
+
+#[tauri::command]
+pub async fn add_static_peer(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    address: String,
+) -> Result<(), String> {
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    sync.as_ref()
+        .ok_or_else(|| "Device sync is not running".to_string())?
+        .add_static_peer(&address)
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn remove_static_peer(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    address: String,
+) -> Result<(), String> {
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    sync.as_ref()
+        .ok_or_else(|| "Device sync is not running".to_string())?
+        .remove_static_peer(&address)
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn get_device_status(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    device_id: String,
+) -> Result<DeviceStatus, String> {
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    sync.as_ref()
+        .ok_or_else(|| "Device sync is not running".to_string())?
+        .device_status(&device_id)
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn forget_device(
+    clipboard: tauri::State<'_, Mutex<Option<ClipboardManager>>>,
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    device_id: String,
+) -> Result<(), String> {
+    log::info!("forgetting device {}", device_id);
+    {
+        let sync = state
+            .lock()
+            .map_err(|e| format!("Could not access the sync manager {}", e))?;
+        sync.as_ref()
+            .ok_or_else(|| "Device sync is not running".to_string())?
+            .forget(&device_id)
+            .map_err(|e| format!("{:#}", e))?;
+    }
+    // boards from the device are gone too
+    emit_update(&clipboard)
+}
+
+/// Forget a lost or stolen device here and on every device that trusts it, and with `wipe` have it clear its
+/// history and pinned items.
+#[tauri::command]
+pub async fn revoke_device(
+    clipboard: tauri::State<'_, Mutex<Option<ClipboardManager>>>,
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    device_id: String,
+    wipe: bool,
+) -> Result<(), String> {
+    log::info!("revoking device {}", device_id);
+    {
+        let sync = state
+            .lock()
+            .map_err(|e| format!("Could not access the sync manager {}", e))?;
+        sync.as_ref()
+            .ok_or_else(|| "Device sync is not running".to_string())?
+            .revoke(&device_id, wipe)
+            .map_err(|e| format!("{:#}", e))?;
+    }
+    emit_update(&clipboard)
+}
+
+#[tauri::command]
+pub async fn set_device_policy(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    device_id: String,
+    policy: SharingPolicy,
+) -> Result<(), String> {
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    sync.as_ref()
+        .ok_or_else(|| "Device sync is not running".to_string())?
+        .set_policy(&device_id, policy)
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn set_device_muted(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    device_id: String,
+    muted: bool,
+) -> Result<(), String> {
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    sync.as_ref()
+        .ok_or_else(|| "Device sync is not running".to_string())?
+        .set_muted(&device_id, muted)
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn send_item(
+    clipboard: tauri::State<'_, Mutex<Option<ClipboardManager>>>,
+    sync: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    id: u32,
+    device_id: String,
+) -> Result<Delivery, SendError> {
+    log::info!("sending item with id {} to {}", id, device_id);
+    let item = clipboard
+        .lock()
+        .map_err(|e| SendError::Other(format!("Could not access the clipboard handler {}", e)))?
+        .as_ref()
+        .and_then(|c| c.get(id))
+        .ok_or(SendError::ItemNotFound(id))?;
+    let sync = sync
+        .lock()
+        .map_err(|e| SendError::Other(format!("Could not access the sync manager {}", e)))?;
+    sync.as_ref()
+        .ok_or(SendError::NotRunning)?
+        .send_to(&device_id, &item)
+}
+
+#[tauri::command]
+pub async fn list_outbox(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+) -> Result<Vec<QueuedItem>, String> {
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    match sync.as_ref() {
+        Some(s) => s.queued_items().map_err(|e| format!("{:#}", e)),
+        None => Ok(vec![]),
+    }
+}
+
+#[tauri::command]
+pub async fn clear_outbox(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    device_id: Option<String>,
+) -> Result<(), String> {
+    log::info!("clearing outbox for {:?}", device_id);
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    sync.as_ref()
+        .ok_or_else(|| "Device sync is not running".to_string())?
+        .clear_outbox(device_id.as_deref())
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn confirm_transfer(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    transfer_id: String,
+    accept: bool,
+) -> Result<(), String> {
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    sync.as_ref()
+        .ok_or_else(|| "Device sync is not running".to_string())?
+        .confirm_transfer(&transfer_id, accept)
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn publish_board(
+    clipboard: tauri::State<'_, Mutex<Option<ClipboardManager>>>,
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    name: String,
+    item_ids: Vec<u32>,
+) -> Result<String, String> {
+    log::info!("publishing board {} with {} items", name, item_ids.len());
+    let items = board_items(&clipboard, &item_ids)?;
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    sync.as_ref()
+        .ok_or_else(|| "Device sync is not running".to_string())?
+        .publish_board(&name, items)
+        .map(|id| id.to_string())
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn update_board(
+    clipboard: tauri::State<'_, Mutex<Option<ClipboardManager>>>,
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    board_id: String,
+    name: Option<String>,
+    item_ids: Vec<u32>,
+) -> Result<(), String> {
+    log::info!("updating board {} to {} items", board_id, item_ids.len());
+    let items = board_items(&clipboard, &item_ids)?;
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    sync.as_ref()
+        .ok_or_else(|| "Device sync is not running".to_string())?
+        .update_board(&board_id, name.as_deref(), items)
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn delete_board(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    board_id: String,
+) -> Result<(), String> {
+    log::info!("deleting board {}", board_id);
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    sync.as_ref()
+        .ok_or_else(|| "Device sync is not running".to_string())?
+        .delete_board(&board_id)
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn list_published_boards(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+) -> Result<Vec<PublishedBoard>, String> {
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    match sync.as_ref() {
+        Some(s) => s.published_boards().map_err(|e| format!("{:#}", e)),
+        None => Ok(vec![]),
+    }
+}
+
+#[tauri::command]
+pub async fn revoke_board_subscriber(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    board_id: String,
+    device_id: String,
+) -> Result<(), String> {
+    log::info!("revoking {} from board {}", device_id, board_id);
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    sync.as_ref()
+        .ok_or_else(|| "Device sync is not running".to_string())?
+        .revoke_board_subscriber(&board_id, &device_id)
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn list_available_boards(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+) -> Result<Vec<AvailableBoard>, String> {
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    match sync.as_ref() {
+        Some(s) => s.available_boards().map_err(|e| format!("{:#}", e)),
+        None => Ok(vec![]),
+    }
+}
+
+#[tauri::command]
+pub async fn subscribe_board(
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    device_id: String,
+    board_id: String,
+) -> Result<(), String> {
+    log::info!("subscribing to board {} from {}", board_id, device_id);
+    let sync = state
+        .lock()
+        .map_err(|e| format!("Could not access the sync manager {}", e))?;
+    sync.as_ref()
+        .ok_or_else(|| "Device sync is not running".to_string())?
+        .subscribe_board(&device_id, &board_id)
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn unsubscribe_board(
+    clipboard: tauri::State<'_, Mutex<Option<ClipboardManager>>>,
+    state: tauri::State<'_, Mutex<Option<SyncManager>>>,
+    board_id: String,
+) -> Result<(), String> {
+    log::info!("unsubscribing from board {}", board_id);
+    {
+        let sync = state
+            .lock()
+            .map_err(|e| format!("Could not access the sync manager {}", e))?;
+        let subscribed = sync
+            .as_ref()
+            .ok_or_else(|| "Device sync is not running".to_string())?
+            .unsubscribe_board(&board_id)
+            .map_err(|e| format!("{:#}", e))?;
+        if !subscribed {
+            return Err(format!("Not subscribed to board {}", board_id));
+        }
+    }
+    emit_update(&clipboard)
+}
+
+/// Look up the items to put on a board, in the order given.
+fn board_items(
+    clipboard: &Mutex<Option<ClipboardManager>>,
+    item_ids: &[u32],
+) -> Result<Vec<Arc<Contents>>, String> {
+    let clip = clipboard
+        .lock()
+        .map_err(|e| format!("Could not access the clipboard handler {}", e))?;
+    let clip = clip.as_ref().ok_or_else(|| "The clipboard handler is not running".to_string())?;
+    item_ids
+        .iter()
+        .map(|id| clip.get(*id).ok_or_else(|| format!("No item with id {}", id)))
+        .collect()
+}
+
+/// Send the clipboard manager's update again. The sync manager must not be locked when this is called.
+fn emit_update(clipboard: &Mutex<Option<ClipboardManager>>) -> Result<(), String> {
+    let clip = clipboard
+        .lock()
+        .map_err(|e| format!("Could not access the clipboard handler {}", e))?;
+    if let Some(c) = clip.as_ref() {
+        c.emit();
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_settings(state: tauri::State<'_, Mutex<Settings>>) -> Result<Settings, String> {
+    let settings = state
+        .lock()
+        .map_err(|e| format!("Could not access the settings {}", e))?;
+    Ok(settings.clone())
+}
+
+#[tauri::command]
+pub async fn set_settings(
+    app: AppHandle,
+    state: tauri::State<'_, Mutex<Settings>>,
+    settings: Settings,
+) -> Result<(), String> {
+    log::info!("updating settings: {:?}", settings);
+    let mut current = state
+        .lock()
+        .map_err(|e| format!("Could not access the settings {}", e))?;
+    store_settings(&settings, &app).map_err(|e| format!("{:#}", e))?;
+    // restarting the web client signs out every browser, so only do it when its settings change
+    let restart_web = (current.web_client, &current.web_interface, current.web_port)
+        != (settings.web_client, &settings.web_interface, settings.web_port);
+    *current = settings.clone();
+    drop(current);
+    if restart_web {
+        web::apply(&app, &settings);
+    }
+    Ok(())
+}
+
+/// A new one-time link for a browser to open the web client with.
+#[tauri::command]
+pub async fn web_client_link(
+    state: tauri::State<'_, Mutex<Option<WebClient>>>,
+) -> Result<WebLink, String> {
+    let web = state
+        .lock()
+        .map_err(|e| format!("Could not access the web client {}", e))?;
+    web.as_ref()
+        .ok_or_else(|| "The web client is not running".to_string())?
+        .link()
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn list_network_interfaces() -> Result<Vec<NetworkInterface>, String> {
+    web::interfaces().map_err(|e| format!("{:#}", e))
+}
+
 // want to listen to show and hide window events: https://github.com/tauri-apps/tauri/issues/14061
 #[tauri::command]
 pub async fn show_window(app: AppHandle) {
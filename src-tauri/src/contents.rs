@@ -1,4 +1,7 @@
 use crate::clipboard_files;
+use crate::hlc::{self, Timestamp};
+use crate::path_mapping;
+use crate::pinned::PinSet;
 use anyhow::{anyhow, Context};
 use base64::{prelude::BASE64_STANDARD, Engine};
 use image::{imageops::thumbnail, ImageBuffer, ImageFormat, Rgba};
@@ -13,25 +16,87 @@ use tauri::Manager;
 use tauri::{image::Image, AppHandle};
 use tauri_plugin_clipboard_manager::ClipboardExt;
 use tauri_plugin_store::StoreExt;
+use uuid::Uuid;
 
 const THUMBNAIL_HEIGHT: u32 = 300;
 const PINNED_STORE: &str = "pinned.json";
 static NEXT_ID: AtomicU32 = AtomicU32::new(0);
 
+/// How an item got onto this device.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Source {
+    /// Copied in an app on this device.
+    #[default]
+    Local,
+    /// Copied on another device, with the name it had when the item arrived.
+    Device { id: String, name: String },
+    /// Sent from a phone or browser through the web client.
+    Web,
+    /// Given to the command line.
+    Cli,
+}
+
+impl Source {
+    /// Whether the item came from `from`, which is a device id or one of `local`, `web` and `cli`.
+    pub fn is(&self, from: &str) -> bool {
+        match self {
+            Source::Local => from == "local",
+            Source::Device { id, .. } => id == from,
+            Source::Web => from == "web",
+            Source::Cli => from == "cli",
+        }
+    }
+}
+
+/// Where an item came from. Unlike `id`, which is only meaningful to this process, `uid` is shared by every copy of
+/// the item on every device, so a device can tell when an item it already has comes back to it.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ItemMeta {
+    #[serde(skip, default = "Uuid::new_v4")]
+    pub uid: Uuid,
+    /// Device id of the device the item was copied on, `None` if it was copied here.
+    pub origin: Option<String>,
+    /// When the item was copied, on the clock of the device it was copied on.
+    pub time: Timestamp,
+    /// Not sent to other devices, which each fill in the device they had the item from.
+    #[serde(default)]
+    pub source: Source,
+}
+
+impl ItemMeta {
+    pub fn local() -> Self {
+        Self::from_source(Source::Local)
+    }
+
+    /// Meta for an item that is new on this device, without having been copied to its clipboard.
+    pub fn from_source(source: Source) -> Self {
+        Self {
+            uid: Uuid::new_v4(),
+            origin: None,
+            time: hlc::now(),
+            source,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Contents {
     FilePath {
         paths: Vec<PathBuf>,
         id: u32,
+        meta: ItemMeta,
     },
     Image {
         thumbnail: String,
         image: Image<'static>,
         id: u32,
+        meta: ItemMeta,
     },
     Text {
         text: String,
-        id: u32
+        id: u32,
+        meta: ItemMeta,
     },
 }
 
@@ -65,7 +130,7 @@ impl Contents {
     fn _try_from_clipboard(app: &AppHandle) -> Option<Self> {
         log::info!("Clipboard handler checking clipboard");
         match clipboard_files::read() {
-            Ok(paths) => return Some(Self::FilePath { paths, id: NEXT_ID.fetch_add(1, Ordering::Relaxed) }),
+            Ok(paths) => return Some(Self::from_paths(paths)),
             Err(clipboard_files::ClipboardError::NoFiles) => {}
             Err(e) => {
                 log::error!(
@@ -77,12 +142,8 @@ impl Contents {
 
         match app.clipboard().read_image() {
             Ok(image) => {
-                if let Ok(thumbnail) = create_base64_thumbnail(&image) {
-                    return Some(Self::Image {
-                        thumbnail,
-                        image: image.to_owned(),
-                        id: NEXT_ID.fetch_add(1, Ordering::Relaxed) 
-                    });
+                if let Ok(item) = Self::from_image(image.to_owned()) {
+                    return Some(item);
                 }
             }
             // no way to tell if this is because the clipboard has no images in it or because an actual error occured...
@@ -92,7 +153,7 @@ impl Contents {
         }
 
         match app.clipboard().read_text() {
-            Ok(text) => return Some(Self::Text { text, id: NEXT_ID.fetch_add(1, Ordering::Relaxed) }),
+            Ok(text) => return Some(Self::from_text(text)),
             Err(e) => {
                 log::warn!("Possible error pasting text from clipboard: {e}");
             }
@@ -102,6 +163,9 @@ impl Contents {
     }
     pub fn try_to_clipboard(&self, app: &AppHandle) {
         match self {
+            Contents::FilePath { paths, .. } if !path_mapping::unmapped(self).is_empty() => {
+                log::warn!("Not writing file paths from another system to clipboard: {:?}", paths)
+            }
             Contents::FilePath { paths, .. } => match clipboard_files::write(paths) {
                 Err(e) => log::error!("Error writing file paths to clipboard: {}", e),
                 Ok(_) => log::info!("Successfully wrote file paths to clipboard"),
@@ -117,6 +181,36 @@ impl Contents {
         }
     }
 
+    pub fn from_text(text: String) -> Self {
+        Self::Text { text, id: NEXT_ID.fetch_add(1, Ordering::Relaxed), meta: ItemMeta::local() }
+    }
+
+    pub fn from_paths(paths: Vec<PathBuf>) -> Self {
+        Self::FilePath { paths, id: NEXT_ID.fetch_add(1, Ordering::Relaxed), meta: ItemMeta::local() }
+    }
+
+    pub fn from_image(image: Image<'static>) -> Result<Self, anyhow::Error> {
+        let thumbnail = create_base64_thumbnail(&image)?;
+        Ok(Self::Image { thumbnail, image, id: NEXT_ID.fetch_add(1, Ordering::Relaxed), meta: ItemMeta::local() })
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Contents::FilePath { .. } => "paths",
+            Contents::Image { .. } => "image",
+            Contents::Text { .. } => "text",
+        }
+    }
+
+    /// Rough size of the item's data in bytes, as it would be sent to another device.
+    pub fn size(&self) -> usize {
+        match self {
+            Contents::FilePath { paths, .. } => paths.iter().map(|p| p.as_os_str().len()).sum(),
+            Contents::Image { image, .. } => image.rgba().len(),
+            Contents::Text { text, .. } => text.len(),
+        }
+    }
+
     pub fn id(&self) -> u32 {
         match self {
             Contents::FilePath { id, .. } => *id,
@@ -124,6 +218,31 @@ impl Contents {
             Contents::Text { id , ..} => *id
         }
     }
+
+    pub fn meta(&self) -> &ItemMeta {
+        match self {
+            Contents::FilePath { meta, .. } => meta,
+            Contents::Image { meta, .. } => meta,
+            Contents::Text { meta, .. } => meta,
+        }
+    }
+
+    pub fn with_source(self, source: Source) -> Self {
+        let meta = ItemMeta {
+            source,
+            ..self.meta().clone()
+        };
+        self.with_meta(meta)
+    }
+
+    pub fn with_meta(mut self, new_meta: ItemMeta) -> Self {
+        match &mut self {
+            Contents::FilePath { meta, .. } => *meta = new_meta,
+            Contents::Image { meta, .. } => *meta = new_meta,
+            Contents::Text { meta, .. } => *meta = new_meta,
+        }
+        self
+    }
 }
 
 impl serde::Serialize for Contents {
@@ -138,12 +257,13 @@ impl serde::Serialize for Contents {
                 s.serialize_field("kind", "image")?;
                 s.serialize_field("id", id)?;
             }
-            Self::FilePath { paths, id } => {
+            Self::FilePath { paths, id, .. } => {
                 s.serialize_field("content", paths)?;
                 s.serialize_field("kind", "paths")?;
                 s.serialize_field("id", id)?;
+                s.serialize_field("unmapped", &path_mapping::unmapped(self))?;
             }
-            Self::Text { text, id } => {
+            Self::Text { text, id, .. } => {
                 s.serialize_field("content", text)?;
                 s.serialize_field("kind", "text")?;
                 s.serialize_field("id", id)?;
@@ -237,8 +357,8 @@ pub fn store_pinned(contents: &[Arc<Contents>], app: &AppHandle) -> Result<(), a
     let serialized = contents
         .iter()
         .map(|c| match c.as_ref() {
-            Contents::FilePath { paths , ..} => Ok(json!({"type": "paths", "content": paths})),
-            Contents::Text { text, ..} => Ok(json!({"type": "text", "content": text})),
+            Contents::FilePath { paths , ..} => Ok(json!({"type": "paths", "content": paths, "meta": c.meta()})),
+            Contents::Text { text, ..} => Ok(json!({"type": "text", "content": text, "meta": c.meta()})),
             Contents::Image { image, .. } => {
                 image_file_name += 1;
                 let mut image_path = images_directory.clone();
@@ -251,7 +371,7 @@ pub fn store_pinned(contents: &[Arc<Contents>], app: &AppHandle) -> Result<(), a
                     "file": image_file_name.to_string(),
                     "height": image.height(),
                     "width": image.width(),
-                }}))
+                }, "meta": c.meta()}))
             }
         })
         .collect::<Result<Vec<_>, anyhow::Error>>()?;
@@ -262,6 +382,29 @@ pub fn store_pinned(contents: &[Arc<Contents>], app: &AppHandle) -> Result<(), a
     Ok(())
 }
 
+/// Save the pinned list's replica next to the pinned items themselves.
+pub fn store_pin_set(pins: &PinSet, app: &AppHandle) -> Result<(), anyhow::Error> {
+    let store = app
+        .store(PINNED_STORE)
+        .with_context(|| "failed to get pinned items store")?;
+    store.set("pins", serde_json::to_value(pins)?);
+    Ok(())
+}
+
+/// The pinned list's replica, `None` if the pinned items were saved before it existed.
+pub fn load_pin_set(app: &AppHandle) -> Result<Option<PinSet>, anyhow::Error> {
+    let store = app
+        .store(PINNED_STORE)
+        .with_context(|| "failed to get or create pinned items store")?;
+    let pins = store
+        .get("pins")
+        .map(serde_json::from_value)
+        .transpose()
+        .with_context(|| "stored pinned list replica was malformed");
+    store.close_resource();
+    pins
+}
+
 pub fn load_pinned(app: &AppHandle) -> Result<Vec<Contents>, anyhow::Error> {
     let store = app
         .store(PINNED_STORE)
@@ -285,15 +428,19 @@ pub fn load_pinned(app: &AppHandle) -> Result<Vec<Contents>, anyhow::Error> {
                     .get("type").ok_or_else(|| anyhow!("pinned array object did not have 'type' key"))?
                     .as_str().ok_or_else(|| anyhow!("value for 'type' key in pinned array object was not a string"))?;
                 let content_obj = json_obj.get("content").ok_or_else(|| anyhow!("pinned array object did not have 'content' key"))?;
-                match type_str {
+                // items pinned before this was saved with them count as copied here, now
+                let meta = json_obj
+                    .get("meta").cloned().map(serde_json::from_value::<ItemMeta>).transpose()
+                    .with_context(|| "'meta' in pinned array object was malformed")?;
+                let item = match type_str {
                     "text" => {
                         let text = content_obj.as_str().ok_or_else(|| anyhow!("Value for 'content' was not a string for 'text' item"))?.to_string();
-                        Ok(Contents::Text { text, id: NEXT_ID.fetch_add(1, Ordering::Relaxed)  })
+                        Ok(Contents::from_text(text))
                     },
                     "paths" => {
                         let path_arr = content_obj.as_array().ok_or_else(|| anyhow!("Value for 'content' was not an array for 'paths' item"))?;
                         let paths = path_arr.iter().map(|p|p.as_str().map(PathBuf::from)).collect::<Option<Vec<_>>>().ok_or_else(|| anyhow!("Not all items in paths array were strings"))?;
-                        Ok(Contents::FilePath { paths, id: NEXT_ID.fetch_add(1, Ordering::Relaxed)  })
+                        Ok(Contents::from_paths(paths))
                     },
                     "image" => {
                         let image_data_obj = content_obj.as_object().ok_or_else(|| anyhow!("Value for 'content' was not an object for 'image' item"))?;
@@ -311,11 +458,14 @@ pub fn load_pinned(app: &AppHandle) -> Result<Vec<Contents>, anyhow::Error> {
                         image_path.push(file_name);
                         let rgba = fs::read(&image_path).with_context(|| format!("Failed to read image data from {}", image_path.display()))?;
                         let image = Image::new_owned(rgba, width as u32, height as u32);
-                        let thumbnail = create_base64_thumbnail(&image)?;
-                        Ok(Contents::Image { thumbnail, image, id: NEXT_ID.fetch_add(1, Ordering::Relaxed)  })
+                        Contents::from_image(image)
                     },
                     _ => Err(anyhow!("type for pinned object not 'image', 'paths', or 'text'"))
-                }
+                }?;
+                Ok(match meta {
+                    Some(meta) => item.with_meta(meta),
+                    None => item,
+                })
             }).collect::<Result<Vec<Contents>, anyhow::Error>>()
     } else {
         store.close_resource();
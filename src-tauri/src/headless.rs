@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use serde::Deserialize;
+use tauri_plugin_log::{Target, TargetKind};
+
+use crate::settings::Settings;
+
+/// Configuration for running without the popup window, read from a JSON file. Besides `log_level` it takes the
+/// same keys as the stored settings, which headless mode uses in their place.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HeadlessConfig {
+    /// One of off, error, warn, info, debug or trace.
+    pub log_level: String,
+    #[serde(flatten)]
+    pub settings: Settings,
+}
+
+impl Default for HeadlessConfig {
+    fn default() -> Self {
+        Self {
+            log_level: "info".to_string(),
+            settings: Settings::default(),
+        }
+    }
+}
+
+impl HeadlessConfig {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("config file {} was malformed", path.display()))
+    }
+}
+
+/// Run history, pinned items and device sync with no webview window or global shortcut, logging to stdout.
+/// Devices are paired from the desktop app, which shares its identity and trusted devices with headless mode.
+pub fn run(config_path: Option<&Path>) {
+    let config = match config_path {
+        Some(path) => HeadlessConfig::load(path).unwrap_or_else(|e| {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }),
+        None => HeadlessConfig::default(),
+    };
+    let level = config.log_level.parse().unwrap_or_else(|_| {
+        eprintln!("unknown log level {}, using info", config.log_level);
+        log::LevelFilter::Info
+    });
+
+    let mut context = crate::context();
+    context.config_mut().app.windows.clear();
+
+    let settings = config.settings;
+    tauri::Builder::default()
+        .plugin(
+            tauri_plugin_log::Builder::new()
+                .clear_targets()
+                .target(Target::new(TargetKind::Stdout))
+                .level(level)
+                .build(),
+        )
+        .plugin(tauri_plugin_store::Builder::new().build())
+        .plugin(tauri_plugin_os::init())
+        .plugin(tauri_plugin_clipboard_manager::init())
+        .setup(move |app| {
+            log::info!("starting headless clipboard agent with {:?}", settings);
+            crate::start(app, settings);
+            Ok(())
+        })
+        .run(context)
+        .expect("error while running headless clipboard agent");
+}
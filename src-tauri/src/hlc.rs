@@ -0,0 +1,117 @@
+//! Hybrid logical clock for ordering items copied on different devices. A timestamp is the wall clock time in
+//! milliseconds plus a counter for changes within the same millisecond. Seeing a timestamp from another device moves
+//! the clock past it, so an item copied here after one was received always sorts after it, even if this device's
+//! wall clock is a little behind.
+
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How far ahead of our wall clock a remote timestamp may be before it is ignored, so one device with a badly set
+/// clock can't drag every other device's clock along with it.
+const MAX_DRIFT_MS: u64 = 60 * 1000;
+
+static CLOCK: Mutex<Timestamp> = Mutex::new(Timestamp {
+    wall: 0,
+    counter: 0,
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Timestamp {
+    /// Unix time in milliseconds.
+    pub wall: u64,
+    pub counter: u32,
+}
+
+/// A timestamp for something that happened here just now, later than every timestamp handed out or seen before.
+pub fn now() -> Timestamp {
+    let mut last = CLOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let wall = wall_ms();
+    *last = if wall > last.wall {
+        Timestamp { wall, counter: 0 }
+    } else {
+        Timestamp {
+            wall: last.wall,
+            counter: last.counter.saturating_add(1),
+        }
+    };
+    *last
+}
+
+/// Take in a timestamp from another device.
+pub fn observe(remote: Timestamp) {
+    if remote.wall > wall_ms().saturating_add(MAX_DRIFT_MS) {
+        log::warn!(
+            "ignoring timestamp {}ms ahead of our clock",
+            remote.wall - wall_ms()
+        );
+        return;
+    }
+    let mut last = CLOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if remote > *last {
+        *last = remote;
+    }
+}
+
+fn wall_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// The clock is shared by every test in the process, so these only assert what holds whatever else was observed.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn now_is_strictly_increasing() {
+        let stamps = (0..10_000).map(|_| now()).collect::<Vec<_>>();
+        assert!(stamps.windows(2).all(|w| w[0] < w[1]));
+
+        let threads = (0..4)
+            .map(|_| std::thread::spawn(|| (0..1000).map(|_| now()).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        let mut stamps = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect::<Vec<_>>();
+        let count = stamps.len();
+        stamps.sort();
+        stamps.dedup();
+        assert_eq!(stamps.len(), count);
+    }
+
+    #[test]
+    fn observed_stamps_come_before_later_ones() {
+        let remote = Timestamp {
+            wall: wall_ms() + MAX_DRIFT_MS / 2,
+            counter: 7,
+        };
+        let before = now();
+        assert!(before < remote);
+        observe(remote);
+        let after = now();
+        assert!(after > remote);
+        assert!(now() > after);
+
+        // An older stamp doesn't move the clock back.
+        observe(before);
+        assert!(now() > after);
+    }
+
+    #[test]
+    fn stamps_too_far_ahead_are_ignored() {
+        for ahead in [MAX_DRIFT_MS + 1000, MAX_DRIFT_MS * 60] {
+            let remote = Timestamp {
+                wall: wall_ms() + ahead,
+                counter: 0,
+            };
+            observe(remote);
+            let after = now();
+            assert!(after < remote);
+            assert!(after.wall <= wall_ms() + MAX_DRIFT_MS);
+        }
+    }
+}
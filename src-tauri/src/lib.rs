@@ -2,12 +2,23 @@ mod clipboard_files;
 mod clipboard_manager;
 mod commands;
 mod contents;
+mod headless;
+mod hlc;
 mod hook_manager;
+mod limits;
+mod notifications;
+mod path_mapping;
+mod pinned;
 mod position_window;
+mod settings;
+mod sync;
 mod watcher;
+#[cfg(target_os = "linux")]
+mod wayland;
+mod web;
 
 use std::error::Error;
-use std::sync::Mutex;
+use std::sync::{Mutex, OnceLock};
 
 use clipboard_master::Master;
 use tauri::{App, Emitter, Manager as TauriManager};
@@ -18,7 +29,36 @@ use tauri_plugin_updater::UpdaterExt;
 use crate::clipboard_manager::ClipboardManager;
 use crate::commands::*;
 use crate::hook_manager::HookManager;
+use crate::settings::{load_settings, Settings};
+use crate::sync::SyncManager;
 use crate::watcher::Watcher;
+use crate::web::WebClient;
+
+pub use crate::headless::run as run_headless;
+
+static INSTANCE: OnceLock<Option<String>> = OnceLock::new();
+
+/// Name of this instance when several run side by side on one machine, from `--instance <name>` or the
+/// `PURPLE_CLIP_INSTANCE` variable. A named instance has its own identifier, so its own data dir and identity, and
+/// isn't stopped by the single instance check. It also looks for other devices on loopback.
+pub fn instance() -> Option<&'static str> {
+    INSTANCE
+        .get_or_init(|| {
+            let args = std::env::args().collect::<Vec<_>>();
+            let name = args
+                .iter()
+                .position(|a| a == "--instance")
+                .and_then(|i| args.get(i + 1).cloned())
+                .or_else(|| std::env::var("PURPLE_CLIP_INSTANCE").ok())
+                .filter(|name| !name.is_empty())?;
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                eprintln!("instance name {:?} may only have letters, digits and dashes, ignoring it", name);
+                return None;
+            }
+            Some(name)
+        })
+        .as_deref()
+}
 
 async fn update(app: tauri::AppHandle) -> tauri_plugin_updater::Result<()> {
     if let Some(update) = app.updater()?.check().await? {
@@ -97,7 +137,7 @@ fn setup(app: &mut App) -> Result<(), Box<dyn Error>> {
     }
 
     let autostart_manager = app.autolaunch();
-    if !cfg!(dev) {
+    if !cfg!(dev) && instance().is_none() {
         if !autostart_manager.is_enabled()? {
             autostart_manager.enable()?;
             log::info!("enabled autostart")
@@ -111,18 +151,12 @@ fn setup(app: &mut App) -> Result<(), Box<dyn Error>> {
         });
     }
 
-    app.manage(Mutex::new(Some(ClipboardManager::new(app.handle()))));
-    app.manage(Mutex::new(HookManager::new(app.handle())));
-
-    // start clipboard change watcher
-    let mut watcher = Master::new(Watcher::new(app.handle()));
-    std::thread::spawn(move || {
-        if let Err(e) = watcher.run() {
-            log::error!("Clipboard change handler failed to start: {e}");
-        } else {
-            log::info!("Sucessfully started Clipboard change handler");
-        }
+    let settings = load_settings(app.handle()).unwrap_or_else(|e| {
+        log::error!("Unable to load settings: {:#}", e);
+        Default::default()
     });
+    start(app, settings);
+    app.manage(Mutex::new(HookManager::new(app.handle())));
 
     // register global shortcut
     let open_shortcut = Shortcut::new(
@@ -143,14 +177,63 @@ fn setup(app: &mut App) -> Result<(), Box<dyn Error>> {
             .build(),
     )?;
 
-    app.global_shortcut().register(open_shortcut)?;
+    // another instance may already have it
+    if let Err(e) = app.global_shortcut().register(open_shortcut) {
+        log::warn!("Could not register the shortcut to open the window: {}", e);
+    }
 
     show(app.handle());
     Ok(())
 }
 
+/// Start what runs with or without the window: history and pinned items, device sync, the web client and the
+/// clipboard watcher.
+fn start(app: &mut App, settings: Settings) {
+    app.manage(Mutex::new(None::<WebClient>));
+    web::apply(app.handle(), &settings);
+    app.manage(Mutex::new(settings));
+
+    app.manage(Mutex::new(Some(ClipboardManager::new(app.handle()))));
+
+    let sync_manager = SyncManager::new(app.handle())
+        .map_err(|e| log::error!("Could not start device sync: {:#}", e))
+        .ok();
+    app.manage(Mutex::new(sync_manager));
+
+    // start clipboard change watcher, through data control on Wayland, where clipboard-master only sees XWayland
+    let watcher = Watcher::new(app.handle());
+    #[cfg(target_os = "linux")]
+    if wayland::active() {
+        std::thread::spawn(move || {
+            if let Err(e) = wayland::watch(move || watcher.changed()) {
+                log::error!("Clipboard change handler stopped: {:#}", e);
+            }
+        });
+        return;
+    }
+    let mut watcher = Master::new(watcher);
+    std::thread::spawn(move || {
+        if let Err(e) = watcher.run() {
+            log::error!("Clipboard change handler failed to start: {e}");
+        } else {
+            log::info!("Sucessfully started Clipboard change handler");
+        }
+    });
+}
+
+fn context() -> tauri::Context<tauri::Wry> {
+    let mut context = tauri::generate_context!();
+    if let Some(name) = instance() {
+        let identifier = format!("{}.instance-{}", context.config().identifier, name);
+        context.config_mut().identifier = identifier;
+    }
+    context
+}
+
 pub fn run() {
     tauri::Builder::default()
+        // has to come first, so a second copy exits before anything else starts
+        .plugin(tauri_plugin_single_instance::init(|app, _, _| show(app)))
         .plugin(tauri_plugin_log::Builder::new().build())
         .plugin(tauri_plugin_updater::Builder::new().build())
         .plugin(tauri_plugin_autostart::Builder::new().build())
@@ -163,15 +246,45 @@ pub fn run() {
         )
         .plugin(tauri_plugin_clipboard_manager::init())
         .plugin(tauri_plugin_opener::init())
+        .plugin(tauri_plugin_notification::init())
         .setup(setup)
         .invoke_handler(tauri::generate_handler![
             paste_item,
             pin_item,
             unpin_item,
+            move_pinned_item,
             request_update,
+            history_from,
             show_window,
-            hide_window
+            hide_window,
+            list_devices,
+            pair_device,
+            list_trusted_devices,
+            forget_device,
+            revoke_device,
+            get_device_status,
+            list_static_peers,
+            add_static_peer,
+            remove_static_peer,
+            set_device_policy,
+            set_device_muted,
+            send_item,
+            list_outbox,
+            clear_outbox,
+            confirm_transfer,
+            publish_board,
+            update_board,
+            delete_board,
+            list_published_boards,
+            revoke_board_subscriber,
+            list_available_boards,
+            subscribe_board,
+            unsubscribe_board,
+            web_client_link,
+            list_network_interfaces,
+            get_settings,
+            set_settings
         ])
-        .run(tauri::generate_context!())
+        .run(context())
         .expect("error while running tauri application");
 }
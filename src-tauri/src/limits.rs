@@ -0,0 +1,180 @@
+//! Limits on items that come from outside this device, from other devices or the web client, so a huge or
+//! malicious one can't exhaust memory. Items copied here aren't held to them.
+
+use std::collections::HashMap;
+use std::time::Instant;
+use thiserror::Error;
+
+use crate::contents::Contents;
+
+/// 16 megapixels, a bit more than a 5K screen.
+pub const MAX_PIXELS: u64 = 16 * 1024 * 1024;
+/// What decoding an uploaded image may allocate, which is RGBA at the largest size allowed. Formats with more bytes
+/// per pixel run into this before the pixel limit.
+pub const MAX_DECODED_BYTES: u64 = MAX_PIXELS * 4;
+pub const MAX_TEXT_LEN: usize = 8 * 1024 * 1024;
+pub const MAX_PATHS: usize = 10_000;
+/// Items a device may send us in a burst, and how many more it may send each minute after that. A board counts
+/// as one item.
+pub const MAX_ITEMS_BURST: f64 = 30.0;
+pub const MAX_ITEMS_PER_MINUTE: f64 = 60.0;
+/// Bytes of items a device may send us in a burst, and each minute after that.
+pub const MAX_BYTES_BURST: f64 = 256.0 * 1024.0 * 1024.0;
+pub const MAX_BYTES_PER_MINUTE: f64 = 256.0 * 1024.0 * 1024.0;
+
+/// Why an item was turned away.
+#[derive(Debug, Error)]
+pub enum Rejected {
+    #[error("{0}x{1} image is over the limit of {MAX_PIXELS} pixels")]
+    Pixels(u32, u32),
+    #[error("{0} bytes of text is over the limit of {MAX_TEXT_LEN}")]
+    Text(usize),
+    #[error("{0} paths is over the limit of {MAX_PATHS}")]
+    Paths(usize),
+    #[error("sending items faster than {MAX_ITEMS_PER_MINUTE} a minute")]
+    Items,
+    #[error("sending more than {} MB of items a minute", MAX_BYTES_PER_MINUTE / 1024.0 / 1024.0)]
+    Bytes,
+}
+
+/// Check an image's size before anything is made of it.
+pub fn check_image(width: u32, height: u32) -> Result<(), Rejected> {
+    match width as u64 * height as u64 > MAX_PIXELS {
+        true => Err(Rejected::Pixels(width, height)),
+        false => Ok(()),
+    }
+}
+
+pub fn check_text(len: usize) -> Result<(), Rejected> {
+    match len > MAX_TEXT_LEN {
+        true => Err(Rejected::Text(len)),
+        false => Ok(()),
+    }
+}
+
+pub fn check_paths(count: usize) -> Result<(), Rejected> {
+    match count > MAX_PATHS {
+        true => Err(Rejected::Paths(count)),
+        false => Ok(()),
+    }
+}
+
+/// Check an item against the limit for its kind.
+pub fn check_item(item: &Contents) -> Result<(), Rejected> {
+    match item {
+        Contents::Text { text, .. } => check_text(text.len()),
+        Contents::Image { image, .. } => check_image(image.width(), image.height()),
+        Contents::FilePath { paths, .. } => check_paths(paths.len()),
+    }
+}
+
+/// Limits for decoding an image file, which stop a small file that decodes to a huge image, a decompression
+/// bomb, before the memory for it is taken.
+pub fn image_limits() -> image::Limits {
+    let mut limits = image::Limits::default();
+    limits.max_alloc = Some(MAX_DECODED_BYTES);
+    limits
+}
+
+/// Token buckets of the items and bytes each device may send us.
+#[derive(Default)]
+pub struct RateLimits {
+    devices: HashMap<String, Buckets>,
+}
+
+struct Buckets {
+    items: f64,
+    bytes: f64,
+    refilled: Instant,
+}
+
+impl RateLimits {
+    /// Take a frame of `bytes` bytes carrying items from a device's allowance, or reject it if the device has run
+    /// out. A frame counts as one item, however many it carries.
+    pub fn take(&mut self, device_id: &str, bytes: usize) -> Result<(), Rejected> {
+        let buckets = self
+            .devices
+            .entry(device_id.to_string())
+            .or_insert_with(|| Buckets {
+                items: MAX_ITEMS_BURST,
+                bytes: MAX_BYTES_BURST,
+                refilled: Instant::now(),
+            });
+        let minutes = buckets.refilled.elapsed().as_secs_f64() / 60.0;
+        buckets.refilled = Instant::now();
+        buckets.items = (buckets.items + minutes * MAX_ITEMS_PER_MINUTE).min(MAX_ITEMS_BURST);
+        buckets.bytes = (buckets.bytes + minutes * MAX_BYTES_PER_MINUTE).min(MAX_BYTES_BURST);
+
+        if buckets.items < 1.0 {
+            return Err(Rejected::Items);
+        }
+        if buckets.bytes < bytes as f64 {
+            return Err(Rejected::Bytes);
+        }
+        buckets.items -= 1.0;
+        buckets.bytes -= bytes as f64;
+        Ok(())
+    }
+
+    pub fn forget(&mut self, device_id: &str) {
+        self.devices.remove(device_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_sizes() {
+        assert!(check_image(4096, 4096).is_ok());
+        assert!(check_image(0, u32::MAX).is_ok());
+        assert!(matches!(
+            check_image(4097, 4096),
+            Err(Rejected::Pixels(4097, 4096))
+        ));
+        // would overflow if multiplied as u32
+        assert!(check_image(u32::MAX, u32::MAX).is_err());
+        assert!(check_image(65536, 65536).is_err());
+    }
+
+    #[test]
+    fn text_and_paths() {
+        assert!(check_text(MAX_TEXT_LEN).is_ok());
+        assert!(matches!(
+            check_text(MAX_TEXT_LEN + 1),
+            Err(Rejected::Text(_))
+        ));
+        assert!(check_paths(MAX_PATHS).is_ok());
+        assert!(matches!(check_paths(usize::MAX), Err(Rejected::Paths(_))));
+    }
+
+    #[test]
+    fn burst_of_items() {
+        let mut rates = RateLimits::default();
+        for _ in 0..MAX_ITEMS_BURST as usize {
+            rates.take("a", 100).unwrap();
+        }
+        assert!(matches!(rates.take("a", 100), Err(Rejected::Items)));
+        // other devices have allowances of their own
+        rates.take("b", 100).unwrap();
+        rates.forget("a");
+        rates.take("a", 100).unwrap();
+    }
+
+    #[test]
+    fn burst_of_bytes() {
+        let mut rates = RateLimits::default();
+        assert!(matches!(
+            rates.take("a", MAX_BYTES_BURST as usize + 1),
+            Err(Rejected::Bytes)
+        ));
+        // a rejected frame takes nothing
+        rates.take("a", MAX_BYTES_BURST as usize / 2).unwrap();
+        rates.take("a", MAX_BYTES_BURST as usize / 2).unwrap();
+        assert!(matches!(
+            rates.take("a", 16 * 1024 * 1024),
+            Err(Rejected::Bytes)
+        ));
+    }
+}
@@ -16,5 +16,16 @@ fn main() {
         }
     }
 
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.iter().any(|a| a == "--headless") {
+        let config = args
+            .iter()
+            .position(|a| a == "--config")
+            .and_then(|i| args.get(i + 1))
+            .map(std::path::PathBuf::from);
+        purple_lib::run_headless(config.as_deref());
+        return;
+    }
+
     purple_lib::run()
 }
@@ -0,0 +1,125 @@
+//! Desktop notifications for items received from other devices. They go through the notification plugin, except on
+//! Linux, where the plugin's desktop side can't add actions or images, so the notification is sent over D-Bus with
+//! notify-rust directly (which is what the plugin uses there too) to get the Paste and Pin buttons and a thumbnail.
+//!
+//! Elsewhere the notification has no buttons: the plugin only registers action types on mobile, and on Windows and
+//! macOS it shows notifications without reporting back what was clicked. The settings page says so.
+
+use std::sync::Arc;
+use tauri::AppHandle;
+
+use crate::contents::Contents;
+
+/// How much of a text item to show.
+const PREVIEW_CHARS: usize = 120;
+
+/// Let the user know a device sent us an item.
+pub fn item_received(app: &AppHandle, device_name: &str, item: &Arc<Contents>) {
+    let title = format!("From {}", device_name);
+    if let Err(e) = show(app, &title, &preview(item), item) {
+        log::warn!("Could not show notification: {:#}", e);
+    }
+}
+
+fn preview(item: &Contents) -> String {
+    match item {
+        Contents::Text { text, .. } => {
+            let text = text.trim();
+            match text.char_indices().nth(PREVIEW_CHARS) {
+                Some((end, _)) => format!("{}…", &text[..end]),
+                None => text.to_string(),
+            }
+        }
+        Contents::Image { image, .. } => format!("Image, {}×{}", image.width(), image.height()),
+        Contents::FilePath { paths, .. } => match paths.as_slice() {
+            [path] => path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.display().to_string()),
+            paths => format!("{} files", paths.len()),
+        },
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn show(
+    app: &AppHandle,
+    title: &str,
+    body: &str,
+    item: &Arc<Contents>,
+) -> Result<(), anyhow::Error> {
+    use std::sync::Mutex;
+    use tauri::Manager;
+
+    use crate::clipboard_manager::ClipboardManager;
+
+    let mut notification = notify_rust::Notification::new();
+    notification
+        .appname(&app.package_info().name)
+        .summary(title)
+        .body(body)
+        .action("paste", "Paste")
+        .action("pin", "Pin");
+    if let Contents::Image { image, .. } = item.as_ref() {
+        notification.image_data(thumbnail(image)?);
+    }
+    let handle = notification.show()?;
+
+    // waiting blocks until the notification is acted on or closed
+    let app = app.clone();
+    let id = item.id();
+    std::thread::spawn(move || {
+        handle.wait_for_action(|action| {
+            let state = app.state::<Mutex<Option<ClipboardManager>>>();
+            let result = match action {
+                "paste" => crate::commands::paste(&state, id),
+                "pin" => crate::commands::pin(&state, id),
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                log::error!("Could not {} item from notification: {}", action, e);
+            }
+        })
+    });
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn thumbnail(image: &tauri::image::Image) -> Result<notify_rust::Image, anyhow::Error> {
+    use anyhow::anyhow;
+    use image::{imageops, ImageBuffer, Rgba};
+
+    const THUMBNAIL_SIZE: u32 = 128;
+
+    let buffer: ImageBuffer<Rgba<u8>, _> =
+        ImageBuffer::from_raw(image.width(), image.height(), image.rgba().to_vec())
+            .ok_or_else(|| anyhow!("Could not convert image to an image buffer"))?;
+    let scale = THUMBNAIL_SIZE as f32 / image.width().max(image.height()).max(1) as f32;
+    let buffer = match scale < 1.0 {
+        true => imageops::thumbnail(
+            &buffer,
+            ((image.width() as f32 * scale).round() as u32).max(1),
+            ((image.height() as f32 * scale).round() as u32).max(1),
+        ),
+        false => buffer,
+    };
+    Ok(notify_rust::Image::from_rgba(
+        buffer.width() as i32,
+        buffer.height() as i32,
+        buffer.into_raw(),
+    )?)
+}
+
+/// Text and preview only, see the module docs for why there are no Paste and Pin buttons.
+#[cfg(not(target_os = "linux"))]
+fn show(app: &AppHandle, title: &str, body: &str, _: &Arc<Contents>) -> Result<(), anyhow::Error> {
+    use tauri::Manager;
+    use tauri_plugin_notification::Notification;
+
+    // headless mode runs without the plugin
+    let Some(notification) = app.try_state::<Notification<tauri::Wry>>() else {
+        return Ok(());
+    };
+    notification.builder().title(title).body(body).show()?;
+    Ok(())
+}
@@ -0,0 +1,286 @@
+//! Mapping of file paths received from devices that see shared folders under other prefixes, like a Windows machine
+//! opening `\\nas\share` that this Linux machine mounts at `/mnt/share`. Rules work both ways: a path under either
+//! prefix maps to the other one, as long as that one is an absolute path here and exists. Either separator is
+//! understood, and Windows-style prefixes match regardless of case.
+//!
+//! A path that is still in another system's form after mapping can't mean anything here, so the item is flagged
+//! and never written to the clipboard.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Manager};
+
+use crate::contents::Contents;
+use crate::settings::Settings;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathMapping {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Anchor {
+    /// `\\server\share`
+    Unc,
+    /// `/` on Unix, or the current drive on Windows
+    Root,
+    /// a drive letter, which is the first component, or a relative path
+    None,
+}
+
+/// A path split on both separators.
+struct Split<'a> {
+    anchor: Anchor,
+    components: Vec<&'a str>,
+}
+
+impl<'a> Split<'a> {
+    fn new(path: &'a str) -> Self {
+        let is_separator = |c: char| c == '/' || c == '\\';
+        let anchor = match path.chars().take_while(|c| is_separator(*c)).count() {
+            0 => Anchor::None,
+            1 => Anchor::Root,
+            _ => Anchor::Unc,
+        };
+        Self {
+            anchor,
+            components: path.split(is_separator).filter(|c| !c.is_empty()).collect(),
+        }
+    }
+
+    fn windows_style(&self) -> bool {
+        self.anchor == Anchor::Unc
+            || self.components.first().is_some_and(|c| {
+                c.len() == 2 && c.ends_with(':') && c.starts_with(|l: char| l.is_ascii_alphabetic())
+            })
+    }
+
+    /// What is left of the path after `prefix`, if it starts with it.
+    fn strip(&self, prefix: &Split) -> Option<&[&'a str]> {
+        if self.anchor != prefix.anchor || self.components.len() < prefix.components.len() {
+            return None;
+        }
+        let ignore_case = prefix.windows_style();
+        let matches =
+            self.components
+                .iter()
+                .zip(&prefix.components)
+                .all(|(a, b)| match ignore_case {
+                    true => a.eq_ignore_ascii_case(b),
+                    false => a == b,
+                });
+        matches.then(|| &self.components[prefix.components.len()..])
+    }
+}
+
+/// The path under the other prefix of the first rule it falls under that leads somewhere on this device.
+pub fn map_path(rules: &[PathMapping], path: &Path) -> Option<PathBuf> {
+    let path = path.to_string_lossy();
+    let split = Split::new(&path);
+    rules
+        .iter()
+        .flat_map(|rule| [(&rule.from, &rule.to), (&rule.to, &rule.from)])
+        .find_map(|(source, target)| {
+            let target = Path::new(target);
+            if !target.is_absolute() || !target.exists() {
+                return None;
+            }
+            let rest = split.strip(&Split::new(source))?;
+            let mut mapped = target.to_path_buf();
+            mapped.extend(rest);
+            Some(mapped)
+        })
+}
+
+/// Paths of a paths item that can't be used on this device.
+pub fn unmapped(item: &Contents) -> Vec<&PathBuf> {
+    match item {
+        Contents::FilePath { paths, .. } => paths.iter().filter(|p| !p.is_absolute()).collect(),
+        _ => vec![],
+    }
+}
+
+/// Map the paths of a received paths item where a rule allows, leaving the rest as they were. Any other item is
+/// returned as it is.
+pub fn map_item(app: &AppHandle, item: Arc<Contents>) -> Arc<Contents> {
+    let Contents::FilePath { paths, .. } = item.as_ref() else {
+        return item;
+    };
+    let rules = rules(app);
+    if rules.is_empty() {
+        return item;
+    }
+    let mapped = paths
+        .iter()
+        .map(|p| map_path(&rules, p).unwrap_or_else(|| p.clone()))
+        .collect::<Vec<_>>();
+    if mapped == *paths {
+        return item;
+    }
+    Arc::new(with_paths(&item, mapped))
+}
+
+/// A received paths item with every path mapped onto a folder this device has, so its files are already here and
+/// don't need to be copied over. `None` if any path isn't covered by a rule.
+pub fn map_shared(app: &AppHandle, item: &Contents) -> Option<Contents> {
+    let Contents::FilePath { paths, .. } = item else {
+        return None;
+    };
+    let rules = rules(app);
+    let mapped = paths
+        .iter()
+        .map(|p| map_path(&rules, p).filter(|p| p.exists()))
+        .collect::<Option<Vec<_>>>()?;
+    Some(with_paths(item, mapped))
+}
+
+fn with_paths(item: &Contents, paths: Vec<PathBuf>) -> Contents {
+    Contents::from_paths(paths).with_meta(item.meta().clone())
+}
+
+fn rules(app: &AppHandle) -> Vec<PathMapping> {
+    app.state::<Mutex<Settings>>()
+        .lock()
+        .map(|s| s.path_mappings.clone())
+        .unwrap_or_default()
+}
+
+// The mapped-to side has to be an absolute path on this device, so these run where that is a Unix path.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// A folder standing in for where this device mounts the share, removed on drop.
+    struct Mount(PathBuf);
+
+    impl Mount {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("purple-clip-test-{}", Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn path(&self) -> String {
+            self.0.to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for Mount {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn rule(from: &str, to: &str) -> PathMapping {
+        PathMapping {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    fn map(rules: &[PathMapping], path: &str) -> Option<PathBuf> {
+        map_path(rules, Path::new(path))
+    }
+
+    #[test]
+    fn unc_paths_map_onto_a_mount_either_way() {
+        let mount = Mount::new();
+        let expected = mount.0.join("repo").join("a.txt");
+        for rules in [
+            [rule(r"\\nas\share", &mount.path())],
+            [rule(&mount.path(), r"\\nas\share")],
+        ] {
+            assert_eq!(
+                map(&rules, r"\\nas\share\repo\a.txt"),
+                Some(expected.clone())
+            );
+            // A UNC path means nothing here, so a local path is never mapped onto one.
+            assert_eq!(map(&rules, &expected.to_string_lossy()), None);
+        }
+    }
+
+    #[test]
+    fn drive_letters_map_onto_a_mount() {
+        let mount = Mount::new();
+        let rules = [rule(r"C:\Users\me\Shared", &mount.path())];
+        assert_eq!(
+            map(&rules, r"C:\Users\me\Shared\repo\a.txt"),
+            Some(mount.0.join("repo").join("a.txt"))
+        );
+        assert_eq!(map(&rules, r"C:\Users\me\Shared"), Some(mount.0.clone()));
+        assert_eq!(map(&rules, r"D:\Users\me\Shared\repo\a.txt"), None);
+    }
+
+    #[test]
+    fn windows_prefixes_match_regardless_of_case() {
+        let mount = Mount::new();
+        let rules = [
+            rule(r"\\nas\share", &mount.path()),
+            rule(r"C:\Users\me\Shared", &mount.path()),
+        ];
+        assert_eq!(
+            map(&rules, r"\\NAS\Share\Repo\A.txt"),
+            Some(mount.0.join("Repo").join("A.txt"))
+        );
+        assert_eq!(
+            map(&rules, r"c:\users\ME\shared\a.txt"),
+            Some(mount.0.join("a.txt"))
+        );
+
+        let rules = [rule("/Volumes/Share", &mount.path())];
+        assert_eq!(
+            map(&rules, "/Volumes/Share/a.txt"),
+            Some(mount.0.join("a.txt"))
+        );
+        assert_eq!(map(&rules, "/volumes/share/a.txt"), None);
+    }
+
+    #[test]
+    fn either_separator_is_understood() {
+        let mount = Mount::new();
+        let expected = Some(mount.0.join("repo").join("a.txt"));
+        let rules = [rule(r"\\nas\share", &mount.path())];
+        assert_eq!(map(&rules, r"\\nas/share\repo/a.txt"), expected);
+        assert_eq!(map(&rules, "//nas/share/repo/a.txt"), expected);
+
+        let rules = [rule("C:/Users/me/Shared", &mount.path())];
+        assert_eq!(map(&rules, r"C:\Users/me\Shared/repo\a.txt"), expected);
+    }
+
+    #[test]
+    fn prefixes_match_whole_components() {
+        let mount = Mount::new();
+        let rules = [
+            rule("/mnt/share", &mount.path()),
+            rule(r"\\nas\share", &mount.path()),
+        ];
+        assert_eq!(map(&rules, "/mnt/share/a.txt"), Some(mount.0.join("a.txt")));
+        assert_eq!(map(&rules, "/mnt/share2/a.txt"), None);
+        assert_eq!(map(&rules, "/mnt/sha/a.txt"), None);
+        assert_eq!(map(&rules, r"\\nas\share2\a.txt"), None);
+        // A UNC prefix isn't a rooted path with the same components, nor the other way around.
+        assert_eq!(map(&rules, "/nas/share/a.txt"), None);
+        assert_eq!(map(&rules, r"\\mnt\share\a.txt"), None);
+    }
+
+    #[test]
+    fn paths_without_a_rule_are_not_mapped() {
+        let mount = Mount::new();
+        assert_eq!(map(&[], r"\\nas\share\a.txt"), None);
+
+        let rules = [rule(r"\\nas\share", &mount.path())];
+        assert_eq!(map(&rules, r"\\other\share\a.txt"), None);
+        assert_eq!(map(&rules, r"C:\share\a.txt"), None);
+        assert_eq!(map(&rules, "share/a.txt"), None);
+
+        // Nor when the rule leads to a folder this device doesn't have.
+        let missing = mount.0.join("missing").to_string_lossy().into_owned();
+        assert_eq!(
+            map(&[rule(r"\\nas\share", &missing)], r"\\nas\share\a.txt"),
+            None
+        );
+    }
+}
@@ -0,0 +1,504 @@
+//! Pinned items kept as a CRDT, so every trusted device can change its pins and they all still end up with the
+//! same list. Which items are pinned is an add-wins observed-remove set: a pin adds a unique tag, an unpin
+//! tombstones the tags it has seen, so a pin made at the same time as an unpin elsewhere survives it. Each pinned
+//! item also has a sort key that the last reorder wins. Merging is a union, so it doesn't matter in what order
+//! devices see each other's changes.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+
+use crate::contents::Contents;
+
+/// Digits of a sort key, in ascending ASCII order so keys compare as plain strings.
+const KEY_DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// One change made by one replica, ordered by its clock and then by replica to break ties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Dot {
+    pub counter: u64,
+    pub replica: Uuid,
+}
+
+/// Where an item sorts in the pinned list, and the change that put it there.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub key: String,
+    pub stamp: Dot,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinEntry {
+    /// Pins of the item no unpin has seen yet, it stays pinned while there are any.
+    pub tags: BTreeSet<Dot>,
+    pub position: Position,
+}
+
+/// The part of the pinned list that is merged between devices. Item contents are sent separately, by pin id.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinState {
+    pub entries: BTreeMap<Uuid, PinEntry>,
+    /// Tags of every pin that has been undone.
+    pub removed: BTreeSet<Dot>,
+}
+
+/// This device's replica of the pinned list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinSet {
+    replica: Uuid,
+    /// Lamport clock, kept ahead of every counter seen from other replicas.
+    clock: u64,
+    state: PinState,
+}
+
+impl PinSet {
+    /// A replica holding `ids`, in that order. Used the first time, when there are only locally pinned items.
+    pub fn from_ids(ids: impl IntoIterator<Item = Uuid>) -> Self {
+        let mut pins = Self {
+            replica: Uuid::new_v4(),
+            clock: 0,
+            state: PinState::default(),
+        };
+        for id in ids {
+            let last = pins.ordered().last().map(|(_, key)| key.to_string());
+            let key = pins.key_between(last.as_deref(), None);
+            pins.insert(id, key);
+        }
+        pins
+    }
+
+    pub fn state(&self) -> &PinState {
+        &self.state
+    }
+
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.state.entries.contains_key(id)
+    }
+
+    /// Pinned item ids in list order.
+    pub fn ids(&self) -> Vec<Uuid> {
+        self.ordered().into_iter().map(|(id, _)| id).collect()
+    }
+
+    /// Pin an item at the top of the list. Returns false if it was already pinned.
+    pub fn pin(&mut self, id: Uuid) -> bool {
+        if self.contains(&id) {
+            return false;
+        }
+        let first = self.ordered().first().map(|(_, key)| key.to_string());
+        let key = self.key_between(None, first.as_deref());
+        self.insert(id, key);
+        true
+    }
+
+    /// Returns false if the item wasn't pinned.
+    pub fn unpin(&mut self, id: &Uuid) -> bool {
+        let Some(entry) = self.state.entries.remove(id) else {
+            return false;
+        };
+        self.state.removed.extend(entry.tags);
+        true
+    }
+
+    /// Move a pinned item so it ends up at `index`. Returns false if it wasn't pinned.
+    pub fn move_to(&mut self, id: &Uuid, index: usize) -> bool {
+        if !self.contains(id) {
+            return false;
+        }
+        let others = self
+            .ordered()
+            .into_iter()
+            .filter(|(other, _)| other != id)
+            .map(|(_, key)| key.to_string())
+            .collect::<Vec<_>>();
+        let index = index.min(others.len());
+        let before = index.checked_sub(1).map(|i| others[i].as_str());
+        let after = others.get(index).map(String::as_str);
+        let key = self.key_between(before, after);
+
+        let stamp = self.tick();
+        if let Some(entry) = self.state.entries.get_mut(id) {
+            entry.position = Position { key, stamp };
+        }
+        true
+    }
+
+    /// Merge in another replica's state. Returns whether anything here changed.
+    pub fn merge(&mut self, other: &PinState) -> bool {
+        let before = self.state.clone();
+        self.state.removed.extend(other.removed.iter().copied());
+        for (id, theirs) in &other.entries {
+            match self.state.entries.get_mut(id) {
+                Some(ours) => {
+                    ours.tags.extend(theirs.tags.iter().copied());
+                    if theirs.position.stamp > ours.position.stamp {
+                        ours.position = theirs.position.clone();
+                    }
+                }
+                None => {
+                    self.state.entries.insert(*id, theirs.clone());
+                }
+            }
+        }
+        let removed = &self.state.removed;
+        self.state.entries.retain(|_, entry| {
+            entry.tags.retain(|tag| !removed.contains(tag));
+            !entry.tags.is_empty()
+        });
+
+        let seen = other
+            .removed
+            .iter()
+            .chain(other.entries.values().flat_map(|e| e.tags.iter()))
+            .chain(other.entries.values().map(|e| &e.position.stamp))
+            .map(|dot| dot.counter)
+            .max()
+            .unwrap_or(0);
+        self.clock = self.clock.max(seen);
+        self.state != before
+    }
+
+    fn insert(&mut self, id: Uuid, key: String) {
+        let stamp = self.tick();
+        self.state.entries.insert(
+            id,
+            PinEntry {
+                tags: BTreeSet::from([stamp]),
+                position: Position { key, stamp },
+            },
+        );
+    }
+
+    /// A key between two others, ending in digits of this replica's own so that replicas placing an item in the
+    /// same spot at the same time don't come up with the same key.
+    fn key_between(&self, before: Option<&str>, after: Option<&str>) -> String {
+        let mut key = key_between(before, after);
+        key.extend(
+            self.replica.as_bytes()[..2]
+                .iter()
+                .map(|b| KEY_DIGITS[1 + *b as usize % (KEY_DIGITS.len() - 1)] as char),
+        );
+        key
+    }
+
+    fn tick(&mut self) -> Dot {
+        self.clock += 1;
+        Dot {
+            counter: self.clock,
+            replica: self.replica,
+        }
+    }
+
+    /// Ids and sort keys in list order. Items that end up with the same key sort by id, so every replica agrees.
+    fn ordered(&self) -> Vec<(Uuid, &str)> {
+        let mut ordered = self
+            .state
+            .entries
+            .iter()
+            .map(|(id, entry)| (*id, entry.position.key.as_str()))
+            .collect::<Vec<_>>();
+        ordered.sort_by(|a, b| a.1.cmp(b.1).then_with(|| a.0.cmp(&b.0)));
+        ordered
+    }
+}
+
+/// The id an item is pinned under, taken from its contents so the same item pinned on two devices is one pin.
+pub fn pin_id(item: &Contents) -> Uuid {
+    let mut hasher = Sha256::new();
+    match item {
+        Contents::Text { text, .. } => {
+            hasher.update(b"text\0");
+            hasher.update(text.as_bytes());
+        }
+        Contents::FilePath { paths, .. } => {
+            hasher.update(b"paths\0");
+            for path in paths {
+                hasher.update(path.to_string_lossy().as_bytes());
+                hasher.update(b"\0");
+            }
+        }
+        Contents::Image { image, .. } => {
+            hasher.update(b"image\0");
+            hasher.update(image.width().to_be_bytes());
+            hasher.update(image.height().to_be_bytes());
+            hasher.update(image.rgba());
+        }
+    }
+    let hash = hasher.finalize();
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hash[..16]);
+    Uuid::from_bytes(bytes)
+}
+
+/// Whether a key is made of key digits and doesn't end in a zero, the only keys `key_between` can work with.
+pub fn valid_key(key: &str) -> bool {
+    key.bytes().all(|b| KEY_DIGITS.contains(&b)) && key.bytes().last().is_some_and(|b| b != b'0')
+}
+
+/// A sort key strictly between `before` and `after`, where `None` is the start or end of the list. Keys are
+/// fractions in base 62 that never end in a zero digit, so there is always room for another one in between.
+/// If there is no room, because the two are equal or out of order, the key goes after `before`. An invalid key
+/// is treated as missing.
+fn key_between(before: Option<&str>, after: Option<&str>) -> String {
+    let before = before.filter(|k| valid_key(k));
+    let after = after.filter(|k| valid_key(k));
+    if let (Some(before), Some(after)) = (before, after) {
+        if before >= after {
+            return key_between(Some(before), None);
+        }
+    }
+    let digit = |key: Option<&str>, i: usize, missing: usize| {
+        key.and_then(|k| k.as_bytes().get(i))
+            .and_then(|b| KEY_DIGITS.iter().position(|d| d == b))
+            .unwrap_or(missing)
+    };
+    let mut key = String::new();
+    let mut bounded = after.is_some();
+    for i in 0.. {
+        let low = digit(before, i, 0);
+        let high = match bounded {
+            true => digit(after, i, 0),
+            false => KEY_DIGITS.len(),
+        };
+        if high > low + 1 {
+            key.push(KEY_DIGITS[(low + high) / 2] as char);
+            break;
+        }
+        key.push(KEY_DIGITS[low] as char);
+        // once this digit is below the upper bound, anything after it is too
+        if high > low {
+            bounded = false;
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_between(before: Option<&str>, after: Option<&str>) -> String {
+        let key = key_between(before, after);
+        assert!(valid_key(&key), "{key:?} is not a valid key");
+        if let Some(before) = before {
+            assert!(key.as_str() > before, "{key:?} is not after {before:?}");
+        }
+        if let Some(after) = after.filter(|a| before.is_none_or(|b| b < *a)) {
+            assert!(key.as_str() < after, "{key:?} is not before {after:?}");
+        }
+        key
+    }
+
+    #[test]
+    fn keys_between() {
+        assert_between(None, None);
+        assert_between(None, Some("1"));
+        assert_between(Some("z"), None);
+        assert_between(Some("zzz"), None);
+        assert_between(Some("1"), Some("2"));
+        assert_between(Some("1"), Some("11"));
+        assert_between(Some("1"), Some("101"));
+        assert_between(Some("V"), Some("Vz"));
+        assert_between(Some("Vy"), Some("Vz"));
+    }
+
+    #[test]
+    fn keep_fitting_keys_in() {
+        let mut before = "1".to_string();
+        let after = "2".to_string();
+        for _ in 0..200 {
+            before = assert_between(Some(&before), Some(&after));
+        }
+        let mut after = "2".to_string();
+        for _ in 0..200 {
+            after = assert_between(None, Some(&after));
+        }
+    }
+
+    #[test]
+    fn equal_keys_end_up_after() {
+        let key = assert_between(Some("V"), Some("V"));
+        assert!(key.as_str() > "V");
+    }
+
+    #[test]
+    fn out_of_order_keys_end_up_after() {
+        let key = assert_between(Some("W"), Some("V"));
+        assert!(key.as_str() > "W");
+    }
+
+    #[test]
+    fn invalid_keys() {
+        for key in ["", "0", "10", "a-b", "é", "V\u{0}"] {
+            assert!(!valid_key(key), "{key:?} should be invalid");
+            key_between(Some(key), None);
+            key_between(None, Some(key));
+            key_between(Some(key), Some(key));
+            key_between(Some("V"), Some(key));
+        }
+        assert!(valid_key("V1"));
+    }
+
+    #[test]
+    fn replicas_pinning_at_the_top_together() {
+        let shared = Uuid::new_v4();
+        let mut a = PinSet::from_ids([shared]);
+        let mut b = PinSet::from_ids([]);
+        a.replica = Uuid::from_bytes([1; 16]);
+        b.replica = Uuid::from_bytes([2; 16]);
+        b.merge(a.state());
+
+        let (x, y) = (Uuid::new_v4(), Uuid::new_v4());
+        a.pin(x);
+        b.pin(y);
+        a.merge(b.state());
+        b.merge(a.state());
+        assert_eq!(a.ids(), b.ids());
+        let keys = a
+            .state()
+            .entries
+            .values()
+            .map(|e| &e.position.key)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            keys.len(),
+            3,
+            "pins at the top of two replicas got the same key"
+        );
+
+        // moving another item in between the two of them has room to do so
+        assert!(a.move_to(&shared, 1));
+        assert_eq!(a.ids()[1], shared);
+        b.merge(a.state());
+        assert_eq!(a.ids(), b.ids());
+    }
+
+    /// Two replicas with fixed ids, `b` sorting after `a`, that have both seen `ids` pinned.
+    fn replicas(ids: &[Uuid]) -> (PinSet, PinSet) {
+        let mut a = PinSet::from_ids(ids.iter().copied());
+        a.replica = Uuid::from_bytes([1; 16]);
+        let mut b = PinSet::from_ids([]);
+        b.replica = Uuid::from_bytes([2; 16]);
+        b.merge(a.state());
+        (a, b)
+    }
+
+    /// Merge the two replicas into each other, asserting they agree afterwards.
+    fn sync(a: &mut PinSet, b: &mut PinSet) {
+        let theirs = b.state().clone();
+        b.merge(a.state());
+        a.merge(&theirs);
+        assert_eq!(a.state(), b.state());
+        assert_eq!(a.ids(), b.ids());
+    }
+
+    #[test]
+    fn pin_wins_over_a_concurrent_unpin() {
+        let x = Uuid::new_v4();
+        let (mut a, mut b) = replicas(&[x]);
+        // a unpins x while b, not having seen that, unpins and pins it again
+        assert!(a.unpin(&x));
+        assert!(b.unpin(&x));
+        assert!(b.pin(x));
+        sync(&mut a, &mut b);
+        assert_eq!(a.ids(), [x]);
+
+        // one that isn't pinned again stays unpinned
+        assert!(a.unpin(&x));
+        sync(&mut a, &mut b);
+        assert!(b.ids().is_empty());
+    }
+
+    #[test]
+    fn pin_on_another_device_survives_an_unpin_it_never_saw() {
+        let x = Uuid::new_v4();
+        let (mut a, mut b) = replicas(&[]);
+        a.pin(x);
+        a.unpin(&x);
+        b.pin(x);
+        sync(&mut a, &mut b);
+        assert_eq!(a.ids(), [x]);
+    }
+
+    #[test]
+    fn merges_in_any_order_agree() {
+        let (x, y, z, w) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        let (mut a, mut b) = replicas(&[x, y, z]);
+        let mut c = PinSet::from_ids([]);
+        c.merge(a.state());
+
+        a.move_to(&z, 0);
+        a.unpin(&y);
+        b.pin(w);
+        b.move_to(&x, 3);
+        c.unpin(&x);
+        c.move_to(&y, 2);
+
+        let merged = |first: &PinSet, others: [&PinSet; 2]| {
+            let mut merged = first.clone();
+            for other in others {
+                merged.merge(other.state());
+            }
+            merged
+        };
+        let orders = [
+            merged(&a, [&b, &c]),
+            merged(&a, [&c, &b]),
+            merged(&b, [&a, &c]),
+            merged(&b, [&c, &a]),
+            merged(&c, [&a, &b]),
+            merged(&c, [&b, &a]),
+        ];
+        for merged in &orders[1..] {
+            assert_eq!(merged.state(), orders[0].state());
+            assert_eq!(merged.ids(), orders[0].ids());
+        }
+        // y was unpinned and x too, z moved to the top and w pinned there as well
+        let ids = orders[0].ids();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&z) && ids.contains(&w));
+
+        // merging again changes nothing
+        let mut again = orders[0].clone();
+        assert!(!again.merge(a.state()));
+        assert!(!again.merge(orders[3].state()));
+    }
+
+    #[test]
+    fn concurrent_moves_of_the_same_item() {
+        let (x, y, z) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let (mut a, mut b) = replicas(&[x, y, z]);
+        a.move_to(&x, 2);
+        assert_eq!(a.ids(), [y, z, x]);
+        b.move_to(&x, 1);
+        assert_eq!(b.ids(), [y, x, z]);
+        // the moves have the same counter, so the replica breaks the tie
+        sync(&mut a, &mut b);
+        assert_eq!(a.ids(), [y, x, z]);
+
+        // a later move wins whichever replica made it
+        a.move_to(&x, 0);
+        b.move_to(&z, 0);
+        a.move_to(&x, 2);
+        sync(&mut a, &mut b);
+        assert_eq!(b.ids(), [z, y, x]);
+    }
+
+    #[test]
+    fn moving_between_equal_keys() {
+        let (x, y, z) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut pins = PinSet::from_ids([x, y, z]);
+        // as if two replicas had picked the same key before suffixes
+        for id in [x, y] {
+            pins.state.entries.get_mut(&id).unwrap().position.key = "V".to_string();
+        }
+        assert!(pins.move_to(&z, 1));
+        assert_eq!(pins.ids().len(), 3);
+    }
+}
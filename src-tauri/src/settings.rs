@@ -0,0 +1,77 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tauri::AppHandle;
+use tauri_plugin_store::StoreExt;
+
+use crate::path_mapping::PathMapping;
+
+const SETTINGS_STORE: &str = "settings.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Send every new local copy to connected trusted devices.
+    pub auto_share: bool,
+    /// Write items received from other devices into the system clipboard, not just history.
+    pub write_received: bool,
+    /// Show a desktop notification for items received from devices that aren't muted.
+    pub notify_received: bool,
+    /// Where files received from other devices are saved, the system downloads folder if unset.
+    pub downloads_dir: Option<PathBuf>,
+    /// Ask before downloading files adding up to more than this many bytes.
+    pub confirm_transfer_size: u64,
+    /// `host:port` of a relay server, for reaching trusted devices that discovery can't find.
+    pub relay_address: Option<String>,
+    /// Port to accept connections from other devices on, so they can add this one by address. A random free
+    /// port if unset. Only read at startup.
+    pub sync_port: Option<u16>,
+    /// Serve the web client to browsers on the local network.
+    pub web_client: bool,
+    /// Address of the network interface the web client listens on, all of them if unset.
+    pub web_interface: Option<String>,
+    /// Port the web client listens on.
+    pub web_port: u16,
+    /// Prefixes that shared folders have here and on other devices, to map file paths received from them.
+    pub path_mappings: Vec<PathMapping>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            auto_share: true,
+            write_received: false,
+            notify_received: false,
+            downloads_dir: None,
+            confirm_transfer_size: 100 * 1024 * 1024,
+            relay_address: None,
+            sync_port: None,
+            web_client: false,
+            web_interface: None,
+            web_port: 8787,
+            path_mappings: vec![],
+        }
+    }
+}
+
+pub fn load_settings(app: &AppHandle) -> Result<Settings, anyhow::Error> {
+    let store = app
+        .store(SETTINGS_STORE)
+        .with_context(|| "failed to get or create settings store")?;
+    let settings = match store.get("settings") {
+        Some(value) => {
+            serde_json::from_value(value).with_context(|| "stored settings were malformed")?
+        }
+        None => Settings::default(),
+    };
+    store.close_resource();
+    Ok(settings)
+}
+
+pub fn store_settings(settings: &Settings, app: &AppHandle) -> Result<(), anyhow::Error> {
+    let store = app
+        .store(SETTINGS_STORE)
+        .with_context(|| "failed to get settings store")?;
+    store.set("settings", serde_json::to_value(settings)?);
+    Ok(())
+}
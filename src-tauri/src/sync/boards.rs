@@ -0,0 +1,726 @@
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tauri::{AppHandle, Emitter, Manager};
+use tauri_plugin_store::StoreExt;
+use uuid::Uuid;
+
+use super::protocol::{self, Message, MAX_FRAME_LEN};
+use super::{lock, with_manager, Shared};
+use crate::contents::Contents;
+use crate::{limits, path_mapping};
+
+const BOARDS_STORE: &str = "boards.json";
+
+/// A board as listed in the catalog a device sends to the devices it shares with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BoardInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub version: u64,
+    pub items: u32,
+}
+
+/// A named, read-only collection of items published to subscribers, in the order the publisher chose.
+#[derive(Debug, Clone)]
+pub struct Board {
+    pub id: Uuid,
+    pub name: String,
+    /// Bumped every time the publisher changes the board.
+    pub version: u64,
+    pub items: Vec<Arc<Contents>>,
+}
+
+/// One of our boards, for the devices page.
+#[derive(Debug, Clone, Serialize)]
+pub struct PublishedBoard {
+    pub id: Uuid,
+    pub name: String,
+    pub items: usize,
+    pub subscribers: Vec<String>,
+}
+
+/// A board another device offers us.
+#[derive(Debug, Clone, Serialize)]
+pub struct AvailableBoard {
+    pub device_id: String,
+    pub id: Uuid,
+    pub name: String,
+    pub items: u32,
+    pub subscribed: bool,
+}
+
+/// A board we subscribe to, shown next to our own pinned items.
+#[derive(Debug, Clone, Serialize)]
+pub struct BoardView {
+    pub id: Uuid,
+    pub name: String,
+    pub device_id: String,
+    pub device_name: String,
+    pub items: Vec<Arc<Contents>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Published {
+    id: Uuid,
+    name: String,
+    version: u64,
+    subscribers: BTreeSet<String>,
+    /// Devices that may no longer subscribe.
+    revoked: BTreeSet<String>,
+    #[serde(skip)]
+    items: Vec<Arc<Contents>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Subscription {
+    id: Uuid,
+    name: String,
+    device_id: String,
+    device_name: String,
+    version: u64,
+    #[serde(skip)]
+    items: Vec<Arc<Contents>>,
+}
+
+/// Boards we publish and boards we subscribe to, persisted in `boards.json` with each board's items encoded in
+/// its own file under `boards/` in the app data dir. Catalogs of what other devices offer are only kept in memory.
+pub struct Boards {
+    app: AppHandle,
+    dir: PathBuf,
+    published: Vec<Published>,
+    subscribed: Vec<Subscription>,
+    catalogs: HashMap<String, Vec<BoardInfo>>,
+}
+
+impl Boards {
+    pub fn load(app: &AppHandle) -> Result<Self, anyhow::Error> {
+        let store = app
+            .store(BOARDS_STORE)
+            .with_context(|| "failed to get or create boards store")?;
+        let mut published = match store.get("published") {
+            Some(value) => serde_json::from_value::<Vec<Published>>(value)
+                .with_context(|| "published boards were malformed")?,
+            None => vec![],
+        };
+        let mut subscribed = match store.get("subscribed") {
+            Some(value) => serde_json::from_value::<Vec<Subscription>>(value)
+                .with_context(|| "subscribed boards were malformed")?,
+            None => vec![],
+        };
+        store.close_resource();
+
+        let mut dir = app
+            .path()
+            .app_local_data_dir()
+            .with_context(|| "failed to get app local data dir")?;
+        dir.push("boards");
+
+        let boards = Self {
+            app: app.clone(),
+            dir,
+            published: vec![],
+            subscribed: vec![],
+            catalogs: HashMap::new(),
+        };
+        for board in &mut published {
+            match boards.read_items("published", &board.id) {
+                Ok(items) => board.items = items,
+                Err(e) => log::error!(
+                    "The items on board {} were lost, subscribers keep their copy: {:#}",
+                    board.name,
+                    e
+                ),
+            }
+        }
+        for board in &mut subscribed {
+            match boards.read_items("subscribed", &board.id) {
+                Ok(items) => board.items = items,
+                Err(e) => {
+                    log::error!(
+                        "The items on board {} were lost, asking for them again: {:#}",
+                        board.name,
+                        e
+                    );
+                    // the publisher's version is always newer, so the board is fetched again on the next connect
+                    board.version = 0;
+                }
+            }
+        }
+        Ok(Self {
+            published,
+            subscribed,
+            ..boards
+        })
+    }
+
+    pub fn publish(
+        &mut self,
+        name: &str,
+        items: Vec<Arc<Contents>>,
+    ) -> Result<Uuid, anyhow::Error> {
+        check_items(&items)?;
+        let board = Published {
+            id: Uuid::new_v4(),
+            name: board_name(name)?,
+            version: 1,
+            subscribers: BTreeSet::new(),
+            revoked: BTreeSet::new(),
+            items,
+        };
+        self.write_items("published", &board.to_board())?;
+        log::info!(
+            "published board {} with {} items",
+            board.name,
+            board.items.len()
+        );
+        let id = board.id;
+        self.published.push(board);
+        self.save()?;
+        Ok(id)
+    }
+
+    /// Replace what is on one of our boards. Returns the devices subscribed to it.
+    pub fn update(
+        &mut self,
+        id: &Uuid,
+        name: Option<&str>,
+        items: Vec<Arc<Contents>>,
+    ) -> Result<Vec<String>, anyhow::Error> {
+        let name = name.map(board_name).transpose()?;
+        check_items(&items)?;
+        let index = self
+            .published
+            .iter()
+            .position(|b| b.id == *id)
+            .ok_or_else(|| anyhow!("No published board with id {}", id))?;
+        let mut board = self.published[index].clone();
+        if let Some(name) = name {
+            board.name = name;
+        }
+        board.items = items;
+        board.version += 1;
+        // only taken once it is on disk, so a board too large to share is left as it was
+        self.write_items("published", &board.to_board())?;
+        self.published[index] = board.clone();
+        self.save()?;
+        Ok(board.subscribers.into_iter().collect())
+    }
+
+    /// Stop publishing a board. Returns the devices that were subscribed to it.
+    pub fn delete(&mut self, id: &Uuid) -> Result<Vec<String>, anyhow::Error> {
+        let index = self
+            .published
+            .iter()
+            .position(|b| b.id == *id)
+            .ok_or_else(|| anyhow!("No published board with id {}", id))?;
+        let board = self.published.remove(index);
+        self.remove_items("published", id);
+        self.save()?;
+        log::info!("unpublished board {}", board.name);
+        Ok(board.subscribers.into_iter().collect())
+    }
+
+    /// Drop a subscriber from one of our boards and keep it from subscribing again.
+    pub fn revoke(&mut self, id: &Uuid, device_id: &str) -> Result<(), anyhow::Error> {
+        let board = self
+            .published
+            .iter_mut()
+            .find(|b| b.id == *id)
+            .ok_or_else(|| anyhow!("No published board with id {}", id))?;
+        board.subscribers.remove(device_id);
+        board.revoked.insert(device_id.to_string());
+        log::info!("revoked {} from board {}", device_id, board.name);
+        self.save()
+    }
+
+    pub fn published(&self) -> Vec<PublishedBoard> {
+        self.published
+            .iter()
+            .map(|b| PublishedBoard {
+                id: b.id,
+                name: b.name.clone(),
+                items: b.items.len(),
+                subscribers: b.subscribers.iter().cloned().collect(),
+            })
+            .collect()
+    }
+
+    /// Our boards that a device may see.
+    pub fn catalog_for(&self, device_id: &str) -> Vec<BoardInfo> {
+        self.published
+            .iter()
+            .filter(|b| !b.revoked.contains(device_id))
+            .map(|b| BoardInfo {
+                id: b.id,
+                name: b.name.clone(),
+                version: b.version,
+                items: b.items.len() as u32,
+            })
+            .collect()
+    }
+
+    pub fn board(&self, id: &Uuid) -> Option<Board> {
+        self.published
+            .iter()
+            .find(|b| b.id == *id)
+            .map(Published::to_board)
+    }
+
+    /// A device asked to subscribe to one of our boards. Returns the board, or `None` if it doesn't exist or the
+    /// device was revoked.
+    pub fn add_subscriber(&mut self, id: &Uuid, device_id: &str) -> Option<Board> {
+        let board = self
+            .published
+            .iter_mut()
+            .find(|b| b.id == *id && !b.revoked.contains(device_id))?;
+        if board.subscribers.insert(device_id.to_string()) {
+            log::info!("{} subscribed to board {}", device_id, board.name);
+        }
+        let board = board.to_board();
+        if let Err(e) = self.save() {
+            log::error!("Could not save boards: {:#}", e);
+        }
+        Some(board)
+    }
+
+    pub fn remove_subscriber(&mut self, id: &Uuid, device_id: &str) {
+        let Some(board) = self.published.iter_mut().find(|b| b.id == *id) else {
+            return;
+        };
+        if board.subscribers.remove(device_id) {
+            log::info!("{} unsubscribed from board {}", device_id, board.name);
+            if let Err(e) = self.save() {
+                log::error!("Could not save boards: {:#}", e);
+            }
+        }
+    }
+
+    /// Take in the boards a device offers. Subscriptions to boards it no longer offers us are dropped, which is
+    /// returned as whether anything changed. Also returns the boards we should ask for again because the device
+    /// has a newer version than ours.
+    pub fn set_catalog(
+        &mut self,
+        device_id: &str,
+        catalog: Vec<BoardInfo>,
+    ) -> Result<(bool, Vec<Uuid>), anyhow::Error> {
+        let gone = self
+            .subscribed
+            .iter()
+            .filter(|s| s.device_id == device_id && !catalog.iter().any(|b| b.id == s.id))
+            .map(|s| s.id)
+            .collect::<Vec<_>>();
+        for id in &gone {
+            self.unsubscribe(id)?;
+        }
+        let outdated = self
+            .subscribed
+            .iter()
+            .filter(|s| s.device_id == device_id)
+            .filter(|s| {
+                catalog
+                    .iter()
+                    .any(|b| b.id == s.id && b.version > s.version)
+            })
+            .map(|s| s.id)
+            .collect();
+        self.catalogs.insert(device_id.to_string(), catalog);
+        Ok((!gone.is_empty(), outdated))
+    }
+
+    pub fn available(&self) -> Vec<AvailableBoard> {
+        let mut available = self
+            .catalogs
+            .iter()
+            .flat_map(|(device_id, catalog)| {
+                catalog.iter().map(|b| AvailableBoard {
+                    device_id: device_id.clone(),
+                    id: b.id,
+                    name: b.name.clone(),
+                    items: b.items,
+                    subscribed: self.is_subscribed(device_id, &b.id),
+                })
+            })
+            .collect::<Vec<_>>();
+        available.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        available
+    }
+
+    /// Note a subscription to a board a device offers. Its items arrive once the device answers.
+    pub fn subscribe(
+        &mut self,
+        device_id: &str,
+        device_name: &str,
+        id: &Uuid,
+    ) -> Result<(), anyhow::Error> {
+        let info = self
+            .catalogs
+            .get(device_id)
+            .and_then(|c| c.iter().find(|b| b.id == *id))
+            .ok_or_else(|| anyhow!("{} does not offer a board with id {}", device_id, id))?;
+        if self.is_subscribed(device_id, id) {
+            return Ok(());
+        }
+        self.subscribed.push(Subscription {
+            id: *id,
+            name: info.name.clone(),
+            device_id: device_id.to_string(),
+            device_name: device_name.to_string(),
+            version: 0,
+            items: vec![],
+        });
+        self.save()
+    }
+
+    /// Returns the device that publishes the board, if we were subscribed.
+    pub fn unsubscribe(&mut self, id: &Uuid) -> Result<Option<String>, anyhow::Error> {
+        let Some(index) = self.subscribed.iter().position(|b| b.id == *id) else {
+            return Ok(None);
+        };
+        let board = self.subscribed.remove(index);
+        self.remove_items("subscribed", id);
+        self.save()?;
+        log::info!("unsubscribed from board {}", board.name);
+        Ok(Some(board.device_id))
+    }
+
+    pub fn is_subscribed(&self, device_id: &str, id: &Uuid) -> bool {
+        self.subscribed
+            .iter()
+            .any(|b| b.id == *id && b.device_id == device_id)
+    }
+
+    /// Take in a newer version of a board we subscribe to. Returns whether anything changed.
+    pub fn receive(&mut self, device_id: &str, board: Board) -> Result<bool, anyhow::Error> {
+        let Some(subscription) = self
+            .subscribed
+            .iter_mut()
+            .find(|b| b.id == board.id && b.device_id == device_id)
+        else {
+            return Ok(false);
+        };
+        if board.version <= subscription.version {
+            return Ok(false);
+        }
+        subscription.name = board.name.clone();
+        subscription.version = board.version;
+        subscription.items = board.items.clone();
+        log::info!(
+            "board {} from {} now has {} items",
+            board.name,
+            device_id,
+            board.items.len()
+        );
+        self.write_items("subscribed", &board)?;
+        self.save()?;
+        Ok(true)
+    }
+
+    /// The publisher ended our subscription. Returns whether we had one.
+    pub fn revoked(&mut self, device_id: &str, id: &Uuid) -> Result<bool, anyhow::Error> {
+        if !self.is_subscribed(device_id, id) {
+            return Ok(false);
+        }
+        if let Some(catalog) = self.catalogs.get_mut(device_id) {
+            catalog.retain(|b| b.id != *id);
+        }
+        Ok(self.unsubscribe(id)?.is_some())
+    }
+
+    pub fn views(&self) -> Vec<BoardView> {
+        self.subscribed
+            .iter()
+            .map(|b| BoardView {
+                id: b.id,
+                name: b.name.clone(),
+                device_id: b.device_id.clone(),
+                device_name: b.device_name.clone(),
+                items: b.items.clone(),
+            })
+            .collect()
+    }
+
+    /// An item on a board we subscribe to, by its id in this process.
+    pub fn item(&self, id: u32) -> Option<Arc<Contents>> {
+        self.subscribed
+            .iter()
+            .flat_map(|b| &b.items)
+            .find(|item| item.id() == id)
+            .cloned()
+    }
+
+    /// Drop everything shared with a device we no longer trust.
+    pub fn forget(&mut self, device_id: &str) -> Result<(), anyhow::Error> {
+        self.catalogs.remove(device_id);
+        for board in &mut self.published {
+            board.subscribers.remove(device_id);
+            board.revoked.remove(device_id);
+        }
+        let ids = self
+            .subscribed
+            .iter()
+            .filter(|b| b.device_id == device_id)
+            .map(|b| b.id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            self.unsubscribe(&id)?;
+        }
+        self.save()
+    }
+
+    fn write_items(&self, kind: &str, board: &Board) -> Result<(), anyhow::Error> {
+        let data = protocol::encode(&Message::Board(board.clone()));
+        if data.len() > MAX_FRAME_LEN {
+            return Err(anyhow!("the board {} is too large to share", board.name));
+        }
+        let dir = self.dir.join(kind);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create boards dir at {}", dir.display()))?;
+        let path = dir.join(format!("{}.board", board.id));
+        fs::write(&path, data)
+            .with_context(|| format!("failed to write board to {}", path.display()))
+    }
+
+    /// The items of a board, or why they couldn't be read. A file that is there but can't be decoded is moved
+    /// aside to `<id>.board.unreadable` rather than being overwritten by the next change to the board.
+    fn read_items(&self, kind: &str, id: &Uuid) -> Result<Vec<Arc<Contents>>, anyhow::Error> {
+        let path = self.dir.join(kind).join(format!("{}.board", id));
+        let data = fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        let error = match protocol::decode(&data) {
+            Ok(Message::Board(board)) => return Ok(board.items),
+            Ok(other) => anyhow!("{} held a {} message", path.display(), other.name()),
+            Err(e) => anyhow!("could not decode {}: {}", path.display(), e),
+        };
+        let aside = path.with_extension("board.unreadable");
+        if let Err(e) = fs::rename(&path, &aside) {
+            log::warn!("Could not move {} aside: {}", path.display(), e);
+        }
+        Err(error)
+    }
+
+    fn remove_items(&self, kind: &str, id: &Uuid) {
+        let path = self.dir.join(kind).join(format!("{}.board", id));
+        if let Err(e) = fs::remove_file(&path) {
+            log::warn!("Could not remove board {}: {}", path.display(), e);
+        }
+    }
+
+    fn save(&self) -> Result<(), anyhow::Error> {
+        let store = self
+            .app
+            .store(BOARDS_STORE)
+            .with_context(|| "failed to get boards store")?;
+        store.set("published", serde_json::to_value(&self.published)?);
+        store.set("subscribed", serde_json::to_value(&self.subscribed)?);
+        Ok(())
+    }
+}
+
+impl Published {
+    fn to_board(&self) -> Board {
+        Board {
+            id: self.id,
+            name: self.name.clone(),
+            version: self.version,
+            items: self.items.clone(),
+        }
+    }
+}
+
+pub fn parse_id(id: &str) -> Result<Uuid, anyhow::Error> {
+    Uuid::parse_str(id).with_context(|| "invalid board id")
+}
+
+/// Hold the items on a board to the limits every device applies to incoming items, otherwise subscribers would
+/// turn the board away, and so would we when reading it back.
+fn check_items(items: &[Arc<Contents>]) -> Result<(), anyhow::Error> {
+    for item in items {
+        limits::check_item(item).with_context(|| "an item is too large to put on a board")?;
+    }
+    Ok(())
+}
+
+fn board_name(name: &str) -> Result<String, anyhow::Error> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(anyhow!("A board needs a name"));
+    }
+    Ok(name.to_string())
+}
+
+/// Our catalog for a device that just connected. Subscribers ask for the boards that changed while they were away.
+pub fn catalog(shared: &Shared, device_id: &str) -> Option<Vec<BoardInfo>> {
+    lock(&shared.boards)
+        .map(|b| b.catalog_for(device_id))
+        .map_err(|e| log::error!("Couldn't access boards: {:#}", e))
+        .ok()
+}
+
+/// Our catalog changed, tell every connected device we share with.
+pub fn share_catalog(shared: &Shared) {
+    let devices = lock(&shared.links)
+        .map(|l| l.keys().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    for device_id in devices {
+        let sends =
+            lock(&shared.trust).is_ok_and(|t| t.policy(&device_id).is_some_and(|p| p.sends()));
+        if let (true, Ok(catalog)) = (
+            sends,
+            lock(&shared.boards).map(|b| b.catalog_for(&device_id)),
+        ) {
+            send(shared, &device_id, Message::BoardCatalog(catalog));
+        }
+    }
+}
+
+/// Send a board to a subscriber, leaving out what our policy for it doesn't allow.
+pub fn send_board(shared: &Shared, device_id: &str, mut board: Board) {
+    let Some(policy) = lock(&shared.trust)
+        .ok()
+        .and_then(|t| t.policy(device_id).cloned())
+    else {
+        return;
+    };
+    let Ok(links) = lock(&shared.links) else {
+        return;
+    };
+    let Some(link) = links.get(device_id) else {
+        return;
+    };
+    board
+        .items
+        .retain(|item| policy.allows_send(item) && link.capabilities.supports(item));
+    link.send(Message::Board(board));
+}
+
+pub fn send(shared: &Shared, device_id: &str, msg: Message) {
+    if let Some(link) = lock(&shared.links)
+        .ok()
+        .as_ref()
+        .and_then(|l| l.get(device_id))
+    {
+        link.send(msg);
+    }
+}
+
+pub fn handle(shared: &Shared, from: &str, msg: Message) {
+    let Some(policy) = lock(&shared.trust)
+        .ok()
+        .and_then(|t| t.policy(from).cloned())
+    else {
+        return;
+    };
+    match msg {
+        Message::BoardCatalog(catalog) if policy.receives() => {
+            let (dropped, outdated, available) = match lock(&shared.boards).and_then(|mut b| {
+                let (dropped, outdated) = b.set_catalog(from, catalog)?;
+                Ok((dropped, outdated, b.available()))
+            }) {
+                Ok(res) => res,
+                Err(e) => {
+                    log::error!("Could not take in boards from {}: {:#}", from, e);
+                    return;
+                }
+            };
+            for id in outdated {
+                send(shared, from, Message::BoardSubscribe(id));
+            }
+            if dropped {
+                show(shared);
+            }
+            if let Err(e) = shared.app.emit("boards-available", available) {
+                log::error!("Could not emit boards-available event: {}", e);
+            }
+        }
+        Message::BoardSubscribe(id) if policy.sends() => {
+            match lock(&shared.boards)
+                .ok()
+                .and_then(|mut b| b.add_subscriber(&id, from))
+            {
+                Some(board) => send_board(shared, from, board),
+                None => send(shared, from, Message::BoardRevoked(id)),
+            }
+        }
+        Message::BoardUnsubscribe(id) => {
+            if let Ok(mut boards) = lock(&shared.boards) {
+                boards.remove_subscriber(&id, from);
+            }
+        }
+        Message::Board(mut board) if policy.receives() => {
+            board.items.retain(|item| policy.allows_receive(item));
+            for item in board.items.iter_mut() {
+                let attributed = super::attribute(shared, from, Arc::clone(item));
+                *item = path_mapping::map_item(&shared.app, attributed);
+            }
+            let id = board.id;
+            let changed =
+                lock(&shared.boards).and_then(|mut boards| match boards.is_subscribed(from, &id) {
+                    true => boards.receive(from, board).map(Some),
+                    false => Ok(None),
+                });
+            match changed {
+                Ok(Some(true)) => show(shared),
+                Ok(Some(false)) => {}
+                // we unsubscribed while the device was away
+                Ok(None) => send(shared, from, Message::BoardUnsubscribe(id)),
+                Err(e) => log::error!("Could not save board from {}: {:#}", from, e),
+            }
+        }
+        Message::BoardRevoked(id) => {
+            match lock(&shared.boards).and_then(|mut b| b.revoked(from, &id)) {
+                Ok(true) => {
+                    log::info!("{} ended our subscription to board {}", from, id);
+                    show(shared);
+                }
+                Ok(false) => {}
+                Err(e) => log::error!("Could not drop board {}: {:#}", id, e),
+            }
+        }
+        other => log::debug!(
+            "sharing policy for {} does not allow {}",
+            from,
+            other.name()
+        ),
+    }
+}
+
+/// Boards are shown with the pinned items, so send the clipboard manager's update again.
+fn show(shared: &Shared) {
+    with_manager(shared, |m| m.emit());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn items_over_the_limits_cant_go_on_a_board() {
+        let text = |len| Arc::new(Contents::from_text("a".repeat(len)));
+        let paths = |count| Arc::new(Contents::from_paths(vec![PathBuf::from("file"); count]));
+        check_items(&[text(limits::MAX_TEXT_LEN), paths(limits::MAX_PATHS)]).unwrap();
+        assert!(check_items(&[text(1), text(limits::MAX_TEXT_LEN + 1)]).is_err());
+        assert!(check_items(&[paths(limits::MAX_PATHS + 1)]).is_err());
+    }
+
+    #[test]
+    fn boards_within_the_limits_read_back() {
+        let items = vec![
+            Arc::new(Contents::from_text("a".repeat(limits::MAX_TEXT_LEN))),
+            Arc::new(Contents::from_paths(vec![PathBuf::from("file")])),
+        ];
+        check_items(&items).unwrap();
+        let board = Board {
+            id: Uuid::new_v4(),
+            name: "board".into(),
+            version: 1,
+            items,
+        };
+        match protocol::decode(&protocol::encode(&Message::Board(board))).unwrap() {
+            Message::Board(board) => assert_eq!(board.items.len(), 2),
+            other => panic!("read back a {}", other.name()),
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use anyhow::Context;
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::protocol::PROTOCOL_VERSION;
+use super::transport::{TransportKind, Transports};
+
+pub const SERVICE_TYPE: &str = "_purple-clip._tcp.local.";
+
+/// What this device advertises about itself.
+pub struct LocalDevice {
+    pub id: String,
+    pub name: String,
+    pub os: String,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Peer {
+    pub id: String,
+    pub name: String,
+    pub os: String,
+    pub version: u16,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    /// The `host:port` it was added by, for static peers rather than discovered ones.
+    pub static_address: Option<String>,
+    /// Transports it accepts connections on. QUIC uses the UDP port numbered `port`.
+    pub transports: Vec<TransportKind>,
+    /// Path of its local socket, which only works from the same machine.
+    pub socket: Option<PathBuf>,
+}
+
+type PeerTable = Arc<Mutex<HashMap<String, Peer>>>;
+
+/// Advertises this device over mDNS and keeps a live table of the other devices on the network.
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    peers: PeerTable,
+    fullname: String,
+}
+
+impl Discovery {
+    /// `on_change` is called from the browse thread with the full peer list every time it changes.
+    /// With `loopback` set, the loopback interface is used as well, so several instances on one machine can find each other.
+    pub fn start<F>(
+        local: &LocalDevice,
+        transports: &Transports,
+        loopback: bool,
+        on_change: F,
+    ) -> Result<Self, anyhow::Error>
+    where
+        F: Fn(Vec<Peer>) + Send + 'static,
+    {
+        let daemon = ServiceDaemon::new().with_context(|| "failed to start mDNS daemon")?;
+        if loopback {
+            daemon
+                .enable_interface(IfKind::LoopbackV4)
+                .with_context(|| "failed to enable mDNS on loopback")?;
+        }
+
+        let version = PROTOCOL_VERSION.to_string();
+        let kinds = transports
+            .kinds()
+            .iter()
+            .map(|k| k.name())
+            .collect::<Vec<_>>()
+            .join(",");
+        let socket = transports
+            .socket()
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut properties = vec![
+            ("id", local.id.as_str()),
+            ("name", local.name.as_str()),
+            ("os", local.os.as_str()),
+            ("v", version.as_str()),
+            ("t", kinds.as_str()),
+        ];
+        if !socket.is_empty() {
+            properties.push(("sock", socket.as_str()));
+        }
+        let port = transports.port();
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            &local.id,
+            &format!("{}.local.", local.id),
+            "",
+            port,
+            &properties[..],
+        )
+        .with_context(|| "failed to build mDNS service info")?
+        .enable_addr_auto();
+        let own_fullname = service.get_fullname().to_string();
+
+        let fullname = own_fullname.clone();
+        daemon
+            .register(service)
+            .with_context(|| "failed to register mDNS service")?;
+        let events = daemon
+            .browse(SERVICE_TYPE)
+            .with_context(|| "failed to browse for mDNS services")?;
+
+        log::info!("advertising {} on port {}", own_fullname, port);
+
+        let peers: PeerTable = Arc::new(Mutex::new(HashMap::new()));
+        let table = Arc::clone(&peers);
+
+        std::thread::spawn(move || {
+            while let Ok(event) = events.recv() {
+                let changed = match event {
+                    ServiceEvent::ServiceResolved(info) if info.get_fullname() != own_fullname => {
+                        match peer_from_info(&info) {
+                            Some(peer) => {
+                                log::info!("discovered device {} ({})", peer.name, peer.id);
+                                insert(&table, info.get_fullname().to_string(), peer)
+                            }
+                            None => {
+                                log::warn!("ignoring malformed service {}", info.get_fullname());
+                                false
+                            }
+                        }
+                    }
+                    ServiceEvent::ServiceRemoved(_, fullname) => remove(&table, &fullname),
+                    _ => false,
+                };
+
+                if changed {
+                    on_change(snapshot(&table));
+                }
+            }
+            log::info!("mDNS browse thread exited");
+        });
+
+        Ok(Self {
+            daemon,
+            peers,
+            fullname,
+        })
+    }
+
+    pub fn peers(&self) -> Vec<Peer> {
+        snapshot(&self.peers)
+    }
+
+    pub fn peer(&self, id: &str) -> Option<Peer> {
+        self.peers
+            .lock()
+            .ok()?
+            .values()
+            .find(|peer| peer.id == id)
+            .cloned()
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        // say goodbye, so other devices drop us now rather than when our records expire
+        match self.daemon.unregister(&self.fullname) {
+            Ok(done) => _ = done.recv_timeout(Duration::from_secs(1)),
+            Err(e) => log::warn!("Could not unregister mDNS service: {}", e),
+        }
+        if let Err(e) = self.daemon.shutdown() {
+            log::error!("Could not shut down mDNS daemon: {}", e);
+        }
+    }
+}
+
+fn peer_from_info(info: &ServiceInfo) -> Option<Peer> {
+    let mut addresses = info.get_addresses().iter().copied().collect::<Vec<_>>();
+    addresses.sort();
+
+    Some(Peer {
+        id: info.get_property_val_str("id")?.to_string(),
+        name: info.get_property_val_str("name")?.to_string(),
+        os: info.get_property_val_str("os")?.to_string(),
+        version: info.get_property_val_str("v")?.parse().ok()?,
+        addresses,
+        port: info.get_port(),
+        static_address: None,
+        transports: info
+            .get_property_val_str("t")
+            .map(|t| t.split(',').filter_map(TransportKind::parse).collect())
+            .unwrap_or_else(|| vec![TransportKind::Tcp]),
+        socket: info.get_property_val_str("sock").map(PathBuf::from),
+    })
+}
+
+fn insert(table: &PeerTable, fullname: String, peer: Peer) -> bool {
+    match table.lock() {
+        Ok(mut peers) => {
+            peers.insert(fullname, peer);
+            true
+        }
+        Err(e) => {
+            log::error!("Couldn't access peer table: {}", e);
+            false
+        }
+    }
+}
+
+fn remove(table: &PeerTable, fullname: &str) -> bool {
+    match table.lock() {
+        Ok(mut peers) => peers
+            .remove(fullname)
+            .inspect(|peer| log::info!("device {} ({}) went away", peer.name, peer.id))
+            .is_some(),
+        Err(e) => {
+            log::error!("Couldn't access peer table: {}", e);
+            false
+        }
+    }
+}
+
+fn snapshot(table: &PeerTable) -> Vec<Peer> {
+    let mut peers = table
+        .lock()
+        .map(|peers| peers.values().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    peers.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use uuid::Uuid;
+
+    /// What a named instance sets up for discovery: its own data dir, transports and id.
+    struct Instance {
+        dir: PathBuf,
+        local: LocalDevice,
+        transports: Transports,
+    }
+
+    impl Instance {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("purple-clip-test-{}", Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let transports = Transports::bind(&dir, 0).unwrap();
+            let local = LocalDevice {
+                id: Uuid::new_v4().simple().to_string(),
+                name: name.to_string(),
+                os: "test".to_string(),
+            };
+            Self {
+                dir,
+                local,
+                transports,
+            }
+        }
+    }
+
+    impl Drop for Instance {
+        fn drop(&mut self) {
+            _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn wait_for(discovery: &Discovery, id: &str) -> Peer {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while Instant::now() < deadline {
+            if let Some(peer) = discovery.peer(id) {
+                return peer;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        panic!("{} was not discovered", id);
+    }
+
+    #[test]
+    fn two_instances_find_each_other_on_loopback() {
+        let a = Instance::new("instance a");
+        let b = Instance::new("instance b");
+        let (changed, changes) = std::sync::mpsc::channel();
+        let discovery_a = Discovery::start(&a.local, &a.transports, true, move |peers| {
+            _ = changed.send(peers);
+        })
+        .unwrap();
+        let discovery_b = Discovery::start(&b.local, &b.transports, true, |_| {}).unwrap();
+
+        let peer = wait_for(&discovery_a, &b.local.id);
+        assert_eq!(peer.name, "instance b");
+        assert_eq!(peer.os, "test");
+        assert_eq!(peer.version, PROTOCOL_VERSION);
+        assert_eq!(peer.port, b.transports.port());
+        assert_eq!(peer.transports, b.transports.kinds());
+        assert_eq!(peer.socket.as_deref(), b.transports.socket());
+        assert!(!peer.addresses.is_empty());
+        assert_eq!(wait_for(&discovery_b, &a.local.id).name, "instance a");
+
+        // nobody finds themselves, and the change was reported
+        assert!(discovery_a.peer(&a.local.id).is_none());
+        assert!(changes
+            .try_iter()
+            .any(|peers| peers.iter().any(|p| p.id == b.local.id)));
+
+        // and a departing instance is noticed
+        drop(discovery_b);
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while discovery_a.peer(&b.local.id).is_some() {
+            assert!(Instant::now() < deadline, "instance b never went away");
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use anyhow::{anyhow, Context};
+use ed25519_dalek::SigningKey;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const IDENTITY_FILE: &str = "identity.key";
+const KEY_LEN: usize = 32;
+
+/// The long-term keypair of this device. The public half doubles as the device's identity on the network.
+pub struct Identity {
+    pub private: Vec<u8>,
+    pub public: Vec<u8>,
+}
+
+impl Identity {
+    /// Load the keypair stored in `dir`, generating and saving a new one on first run.
+    pub fn load_or_create(dir: &Path) -> Result<Self, anyhow::Error> {
+        let path = dir.join(IDENTITY_FILE);
+
+        if path.exists() {
+            let bytes = fs::read(&path)
+                .with_context(|| format!("failed to read identity key from {}", path.display()))?;
+            if bytes.len() != KEY_LEN * 2 {
+                return Err(anyhow!("identity key at {} is corrupt", path.display()));
+            }
+            let (private, public) = bytes.split_at(KEY_LEN);
+            return Ok(Self {
+                private: private.to_vec(),
+                public: public.to_vec(),
+            });
+        }
+
+        log::info!("generating new device identity at {}", path.display());
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse()?)
+            .generate_keypair()
+            .with_context(|| "failed to generate identity keypair")?;
+
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create data dir at {}", dir.display()))?;
+        write_private(
+            &path,
+            &[keypair.private.as_slice(), keypair.public.as_slice()].concat(),
+        )
+        .with_context(|| format!("failed to write identity key to {}", path.display()))?;
+
+        Ok(Self {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+
+    pub fn device_id(&self) -> String {
+        fingerprint(&self.public)
+    }
+
+    /// Key for signing what other devices may pass on, like revocations. Noise keys can't sign, so this one is
+    /// derived from the private key, and devices learn each other's half of it in the hello.
+    pub fn signing_key(&self) -> SigningKey {
+        let seed = Sha256::new()
+            .chain_update(b"purple-clip signing key")
+            .chain_update(&self.private)
+            .finalize();
+        SigningKey::from_bytes(&seed.into())
+    }
+}
+
+/// Write a file only the current user can read.
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+/// Short, stable identifier derived from a public key, used as the device id.
+pub fn fingerprint(public: &[u8]) -> String {
+    Sha256::digest(public)
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
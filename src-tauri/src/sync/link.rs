@@ -0,0 +1,524 @@
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::Emitter;
+
+use super::discovery::Peer;
+use super::presence;
+use super::protocol::{self, Capabilities, FrameHeader, Hello, Message, ProtocolError};
+use super::secure::SecureStream;
+use super::transport::Connection;
+use super::{lock, Shared};
+use crate::limits::Rejected;
+
+/// How often the connector retries trusted devices it isn't connected to.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// How often each side of a link sends a heartbeat.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// A link that has been silent this long, heartbeats included, is considered dead.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+static NEXT_LINK: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, serde::Serialize)]
+struct ItemRejected {
+    device_id: String,
+    reason: String,
+}
+
+/// An open sync connection to a trusted device. Messages queued on it are written by a dedicated thread.
+pub struct Link {
+    id: u64,
+    /// Opened by the device with the lower id, which wins when both devices dial each other at once.
+    preferred: bool,
+    pub name: String,
+    pub capabilities: Capabilities,
+    outgoing: mpsc::Sender<Message>,
+    stream: Box<dyn Connection>,
+}
+
+impl Link {
+    pub fn send(&self, msg: Message) -> bool {
+        self.outgoing.send(msg).is_ok()
+    }
+
+    pub fn close(&self) {
+        self.stream.shutdown();
+    }
+}
+
+/// Run a sync connection until it closes: register it, start its writer, and handle incoming messages.
+/// `dialed` is whether this side opened the connection.
+pub fn run(
+    stream: SecureStream,
+    remote: Hello,
+    capabilities: Capabilities,
+    shared: Arc<Shared>,
+    dialed: bool,
+) {
+    let (writer, [connection, link_connection]) = match split(&stream) {
+        Ok(res) => res,
+        Err(e) => {
+            log::error!("Could not split connection to {}: {}", remote.name, e);
+            return;
+        }
+    };
+    _ = stream.stream().set_read_timeout(Some(HEARTBEAT_TIMEOUT));
+    let transport = stream.stream().kind();
+
+    let (tx, rx) = mpsc::channel::<Message>();
+    let id = NEXT_LINK.fetch_add(1, Ordering::Relaxed);
+    let preferred = dialed == (shared.hello.device_id < remote.device_id);
+    let policy = lock(&shared.trust).ok().and_then(|mut t| {
+        if let Err(e) = t.set_signing_key(&remote.device_id, &remote.signing_key) {
+            log::error!("Could not save signing key of {}: {:#}", remote.name, e);
+        }
+        t.policy(&remote.device_id).cloned()
+    });
+
+    let mut queued = vec![];
+    match lock(&shared.links) {
+        Ok(mut links) => {
+            // static peers can be dialed from both sides, keep only one of the connections
+            if !preferred && links.get(&remote.device_id).is_some_and(|l| l.preferred) {
+                log::debug!(
+                    "already connected to {}, dropping second connection",
+                    remote.name
+                );
+                return;
+            }
+            // read what was kept while the device was away before the link is visible to anything sending new
+            // items, the writer sends it first
+            for msg in super::queued(&shared, &remote.device_id) {
+                let (Message::Item(item) | Message::Push(item)) = &msg else {
+                    continue;
+                };
+                if !capabilities.supports(item) {
+                    log::info!(
+                        "{} can't take {} items, dropping one queued for it",
+                        remote.name,
+                        item.kind()
+                    );
+                } else if !policy.as_ref().is_some_and(|p| p.allows_send(item)) {
+                    log::info!(
+                        "sharing policy for {} no longer allows sending {} items, dropping one queued for it",
+                        remote.name,
+                        item.kind()
+                    );
+                } else {
+                    queued.push(msg);
+                    continue;
+                }
+                super::unqueue(&shared, &remote.device_id, item);
+            }
+            let link = Link {
+                id,
+                preferred,
+                name: remote.name.clone(),
+                capabilities,
+                outgoing: tx.clone(),
+                stream: link_connection,
+            };
+            if let Some(old) = links.insert(remote.device_id.clone(), link) {
+                old.close();
+            }
+        }
+        Err(e) => {
+            log::error!("Could not register connection to {}: {:#}", remote.name, e);
+            return;
+        }
+    }
+    if !queued.is_empty() {
+        log::info!("sending {} queued items to {}", queued.len(), remote.name);
+    }
+    for msg in &queued {
+        if let Message::Item(item) | Message::Push(item) = msg {
+            super::transfer::offer(&shared, &remote.device_id, item);
+        }
+    }
+    super::transfer::resume(&shared, &remote.device_id);
+    presence::connected(&shared, &remote.device_id, transport);
+    // devices that were away when a device was revoked hear about it from whichever device they meet first
+    if let Ok(revocations) = lock(&shared.revocations) {
+        for revocation in revocations.list() {
+            if revocation.device_id != remote.device_id {
+                _ = tx.send(Message::Revocation(revocation));
+            }
+        }
+    }
+    if policy.as_ref().is_some_and(|p| p.sends()) {
+        if let Some(state) = super::pins::local_state(&shared) {
+            _ = tx.send(Message::Pins(state));
+        }
+        if let Some(catalog) = super::boards::catalog(&shared, &remote.device_id) {
+            _ = tx.send(Message::BoardCatalog(catalog));
+        }
+    }
+    log::info!(
+        "connected to {} ({}) over {}, shared capabilities {:?}",
+        remote.name,
+        remote.device_id,
+        transport.name(),
+        capabilities
+    );
+
+    // heartbeats carry the microseconds since this point, so the pong tells how long the round trip took
+    let started = Instant::now();
+    let name = remote.name.clone();
+    let writer_shared = Arc::clone(&shared);
+    let device_id = remote.device_id.clone();
+    std::thread::spawn(move || {
+        let mut writer = Counted::new(writer);
+        // queued items only leave the outbox once written, so a connection lost first sends them next time
+        for msg in queued {
+            match protocol::write_message(&mut writer, &msg) {
+                Ok(()) => {}
+                // nothing was written, so the connection is still fine
+                Err(e @ ProtocolError::FrameTooLarge(_)) => {
+                    log::warn!("Dropping queued item for {}: {}", name, e)
+                }
+                Err(e) => {
+                    log::warn!("Could not send queued item to {}: {}", name, e);
+                    connection.shutdown();
+                    return;
+                }
+            }
+            if let Message::Item(item) | Message::Push(item) = &msg {
+                super::unqueue(&writer_shared, &device_id, item);
+            }
+        }
+        let mut last_ping = Instant::now();
+        loop {
+            let msg = match rx.recv_timeout(HEARTBEAT_INTERVAL.saturating_sub(last_ping.elapsed()))
+            {
+                Ok(msg) => Some(msg),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let ping = (last_ping.elapsed() >= HEARTBEAT_INTERVAL).then(|| {
+                last_ping = Instant::now();
+                Message::Ping(started.elapsed().as_micros() as u64)
+            });
+            for msg in msg.iter().chain(ping.iter()) {
+                match protocol::write_message(&mut writer, msg) {
+                    Ok(()) => {}
+                    Err(e @ ProtocolError::FrameTooLarge(_)) => {
+                        log::error!("Not sending {} to {}: {}", msg.name(), name, e)
+                    }
+                    Err(e) => {
+                        log::warn!("Could not send {} to {}: {}", msg.name(), name, e);
+                        connection.shutdown();
+                        return;
+                    }
+                }
+            }
+            presence::traffic(&writer_shared, &device_id, writer.take_count(), 0);
+        }
+    });
+
+    let mut reader = Counted::new(stream);
+    let reason = loop {
+        let msg = protocol::read_admitted(&mut reader, |header| {
+            rate_limit(&shared, &remote.device_id, header)
+        });
+        presence::traffic(&shared, &remote.device_id, 0, reader.take_count());
+        match msg {
+            Ok(Message::Ping(value)) => {
+                _ = tx.send(Message::Pong(value));
+            }
+            Ok(Message::Pong(value)) => {
+                let rtt = started
+                    .elapsed()
+                    .saturating_sub(Duration::from_micros(value));
+                presence::round_trip(&shared, &remote.device_id, rtt);
+            }
+            Ok(Message::Item(item)) => {
+                log::info!("received {} item from {}", item.kind(), remote.name);
+                let item = super::attribute(&shared, &remote.device_id, item);
+                super::receive_item(&shared, &remote.device_id, item, false);
+            }
+            Ok(Message::Push(item)) => {
+                log::info!("{} sent us a {} item", remote.name, item.kind());
+                let item = super::attribute(&shared, &remote.device_id, item);
+                super::receive_item(&shared, &remote.device_id, item, true);
+            }
+            Ok(Message::Pins(state)) => super::pins::merge(&shared, &remote.device_id, state),
+            Ok(Message::PinRequest(ids)) => super::pins::answer(&shared, &remote.device_id, ids),
+            Ok(Message::PinnedItem(item)) => {
+                let item = super::attribute(&shared, &remote.device_id, item);
+                super::pins::receive(&shared, &remote.device_id, item);
+            }
+            Ok(
+                msg @ (Message::BoardCatalog(_)
+                | Message::BoardSubscribe(_)
+                | Message::BoardUnsubscribe(_)
+                | Message::Board(_)
+                | Message::BoardRevoked(_)),
+            ) => super::boards::handle(&shared, &remote.device_id, msg),
+            Ok(
+                msg @ (Message::ManifestRequest(_)
+                | Message::Manifest(..)
+                | Message::ChunkRequest { .. }
+                | Message::Chunk { .. }
+                | Message::TransferFailed(..)),
+            ) => super::transfer::handle(&shared, &remote.device_id, msg),
+            Ok(Message::Revocation(revocation)) => {
+                if super::revocation::receive(&shared, &remote.device_id, revocation) {
+                    break "This device was revoked".to_string();
+                }
+            }
+            Ok(Message::Rejected(reason)) => {
+                log::warn!("{} turned away an item we sent: {}", remote.name, reason);
+                let event = ItemRejected {
+                    device_id: remote.device_id.clone(),
+                    reason,
+                };
+                if let Err(e) = shared.app.emit("item-rejected", event) {
+                    log::error!("Could not emit item-rejected event: {}", e);
+                }
+            }
+            Ok(other) => {
+                log::warn!("{} sent unexpected {}, ignoring", remote.name, other.name());
+            }
+            Err(ProtocolError::Rejected(e)) => {
+                log::warn!("Rejected item from {}: {}", remote.name, e);
+                _ = tx.send(Message::Rejected(e.to_string()));
+            }
+            Err(ProtocolError::UnknownMessage(kind)) => {
+                log::warn!(
+                    "{} sent unknown message type {:#04x}, skipping",
+                    remote.name,
+                    kind
+                );
+            }
+            Err(e) => {
+                log::info!("connection to {} closed: {}", remote.name, e);
+                break close_reason(e);
+            }
+        }
+    };
+
+    let current = lock(&shared.links).is_ok_and(|mut links| {
+        if links.get(&remote.device_id).is_none_or(|l| l.id != id) {
+            return false;
+        }
+        links.remove(&remote.device_id).inspect(Link::close);
+        true
+    });
+    // a link that was replaced or forgotten says nothing about whether the device is still there
+    if current {
+        presence::disconnected(&shared, &remote.device_id, Some(reason));
+    }
+}
+
+/// Charge a frame carrying items to the device's allowance, going by its length so an item over the rate costs
+/// nothing to decode.
+fn rate_limit(shared: &Shared, device_id: &str, header: &FrameHeader) -> Result<(), Rejected> {
+    if !header.carries_items() {
+        return Ok(());
+    }
+    match lock(&shared.rates) {
+        Ok(mut rates) => rates.take(device_id, header.len),
+        Err(_) => Ok(()),
+    }
+}
+
+fn close_reason(e: ProtocolError) -> String {
+    match e {
+        ProtocolError::Io(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            format!("No heartbeat for {} seconds", HEARTBEAT_TIMEOUT.as_secs())
+        }
+        ProtocolError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            "The device closed the connection".to_string()
+        }
+        e => e.to_string(),
+    }
+}
+
+/// Counts the bytes that go through a stream, for the device status.
+struct Counted<S> {
+    inner: S,
+    count: u64,
+}
+
+impl<S> Counted<S> {
+    fn new(inner: S) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    /// Bytes counted since the last call.
+    fn take_count(&mut self) -> u64 {
+        std::mem::take(&mut self.count)
+    }
+}
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A handle to write with, and two to close the connection with from the writer and the link.
+fn split(stream: &SecureStream) -> io::Result<(SecureStream, [Box<dyn Connection>; 2])> {
+    Ok((
+        stream.try_clone()?,
+        [stream.stream().try_clone()?, stream.stream().try_clone()?],
+    ))
+}
+
+/// Keep sync connections open to every trusted device on the network. Only the side with the lower
+/// device id dials discovered devices, so two devices never race to open a pair of connections to each other.
+/// Static peers are dialed whatever their id, since they may not be able to reach us.
+pub fn connector(shared: Arc<Shared>, updates: mpsc::Receiver<Vec<Peer>>) {
+    let mut peers = vec![];
+    let mut listed = vec![];
+    loop {
+        match updates.recv_timeout(RECONNECT_INTERVAL) {
+            Ok(update) => peers = update,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let devices = match lock(&shared.static_peers) {
+            Ok(static_peers) => static_peers.merge(peers.clone()),
+            Err(_) => peers.clone(),
+        };
+        if devices != listed {
+            if let Err(e) = shared.app.emit("devices-update", &devices) {
+                log::error!("Could not emit devices-update event: {}", e);
+            }
+            listed = devices;
+        }
+
+        let own_id = shared.hello.device_id.as_str();
+        for peer in &peers {
+            if own_id >= peer.id.as_str() || !should_dial(&shared, &peer.id) {
+                continue;
+            }
+            let shared = Arc::clone(&shared);
+            let peer = peer.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = super::dial(&shared, &peer) {
+                    log::warn!("Could not connect to {}: {:#}", peer.name, e);
+                    presence::failed(&shared, &peer.id, format!("{:#}", e));
+                }
+                if let Ok(mut dialing) = lock(&shared.dialing) {
+                    dialing.remove(&peer.id);
+                }
+            });
+        }
+
+        dial_revoked(&shared, &peers);
+        dial_static_peers(&shared, &peers);
+
+        // trusted devices discovery can't see might still be reachable through the relay
+        let Some(relay) = super::relay::address(&shared) else {
+            continue;
+        };
+        let discovered = peers.iter().map(|p| p.id.as_str()).collect::<HashSet<_>>();
+        let unseen = lock(&shared.trust)
+            .map(|t| {
+                t.iter()
+                    .map(|d| d.id.clone())
+                    .filter(|id| !discovered.contains(id.as_str()) && own_id < id.as_str())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        for device_id in unseen {
+            if !should_dial(&shared, &device_id) {
+                continue;
+            }
+            let shared = Arc::clone(&shared);
+            let relay = relay.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = super::dial_relay(&shared, &relay, &device_id) {
+                    log::debug!("Could not reach {} through the relay: {:#}", device_id, e);
+                    presence::failed(&shared, &device_id, format!("{:#}", e));
+                }
+                if let Ok(mut dialing) = lock(&shared.dialing) {
+                    dialing.remove(&device_id);
+                }
+            });
+        }
+    }
+}
+
+/// Dial revoked devices we hold a wipe for, whatever their id, since they have no reason to keep dialing us.
+fn dial_revoked(shared: &Arc<Shared>, discovered: &[Peer]) {
+    for peer in discovered {
+        if !super::revocation::wipe_pending(shared, &peer.id)
+            || !lock(&shared.dialing).is_ok_and(|mut d| d.insert(peer.id.clone()))
+        {
+            continue;
+        }
+        let shared = Arc::clone(shared);
+        let peer = peer.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = super::dial(&shared, &peer) {
+                log::debug!("Could not reach revoked device {}: {:#}", peer.name, e);
+            }
+            if let Ok(mut dialing) = lock(&shared.dialing) {
+                dialing.remove(&peer.id);
+            }
+        });
+    }
+}
+
+/// Try every static peer that is due, except those discovery found, which are dialed like any other.
+fn dial_static_peers(shared: &Arc<Shared>, discovered: &[Peer]) {
+    let due = match lock(&shared.static_peers) {
+        Ok(static_peers) => static_peers.due(),
+        Err(_) => return,
+    };
+    for (address, device_id) in due {
+        if let Some(id) = &device_id {
+            if discovered.iter().any(|p| &p.id == id) {
+                continue;
+            }
+            let trusted = lock(&shared.trust).is_ok_and(|t| t.contains(id));
+            if trusted && !should_dial(shared, id) {
+                continue;
+            }
+        }
+        if !lock(&shared.static_peers).is_ok_and(|mut s| s.begin(&address)) {
+            continue;
+        }
+        let shared = Arc::clone(shared);
+        std::thread::spawn(move || {
+            super::static_peers::dial(&shared, &address);
+            if let (Some(id), Ok(mut dialing)) = (device_id, lock(&shared.dialing)) {
+                dialing.remove(&id);
+            }
+        });
+    }
+}
+
+fn should_dial(shared: &Shared, device_id: &str) -> bool {
+    let trusted = lock(&shared.trust).is_ok_and(|t| t.contains(device_id));
+    let connected = lock(&shared.links).is_ok_and(|l| l.contains_key(device_id));
+    trusted
+        && !connected
+        && lock(&shared.dialing).is_ok_and(|mut d| d.insert(device_id.to_string()))
+}
@@ -0,0 +1,1139 @@
+mod boards;
+mod discovery;
+mod identity;
+mod link;
+mod outbox;
+mod pairing;
+mod pins;
+mod policy;
+mod presence;
+mod protocol;
+mod relay;
+mod revocation;
+mod secure;
+mod static_peers;
+mod transfer;
+mod transport;
+mod trust;
+
+use anyhow::{anyhow, Context};
+use serde::ser::SerializeStruct;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+use thiserror::Error;
+
+use crate::clipboard_manager::ClipboardManager;
+use crate::contents::{Contents, ItemMeta, Source};
+use crate::limits::RateLimits;
+use crate::path_mapping;
+use crate::pinned::PinState;
+use crate::settings::Settings;
+
+pub use boards::{AvailableBoard, BoardView, PublishedBoard};
+pub use discovery::Peer;
+pub use outbox::QueuedItem;
+pub use pairing::PairingCode;
+pub use policy::SharingPolicy;
+pub use presence::DeviceStatus;
+pub use static_peers::StaticPeerStatus;
+pub use trust::TrustedDevice;
+
+use boards::Boards;
+use discovery::{Discovery, LocalDevice};
+use identity::Identity;
+use link::Link;
+use outbox::Outbox;
+use presence::Presence;
+use protocol::{Capabilities, Hello, Intent, Message, ProtocolError, PROTOCOL_VERSION};
+use revocation::Revocations;
+use secure::SecureStream;
+use static_peers::StaticPeers;
+use transfer::Transfers;
+use transport::{Connection, Transport, Transports};
+use trust::TrustStore;
+use uuid::Uuid;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a pairing request waits for the user to type in or confirm the code.
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// An incoming pairing request waiting on the user.
+struct PendingPairing {
+    code: PairingCode,
+    decision: mpsc::Sender<bool>,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct PairingRequest {
+    device_id: String,
+    name: String,
+    words: Vec<&'static str>,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct PairingResult {
+    device_id: String,
+    accepted: bool,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct ItemReceived {
+    device_id: String,
+    pushed: bool,
+    item: Arc<Contents>,
+}
+
+/// Why an item could not be sent to a device.
+#[derive(Debug, Error)]
+pub enum SendError {
+    #[error("No item with id {0}")]
+    ItemNotFound(u32),
+    #[error("Device sync is not running")]
+    NotRunning,
+    #[error("{0} is not a trusted device")]
+    NotTrusted(String),
+    #[error("{0} can't receive {1} items")]
+    Unsupported(String, &'static str),
+    #[error("The sharing policy for {0} doesn't allow sending this {1} item")]
+    NotAllowed(String, &'static str),
+    #[error("This {0} item is too large to send: {1}")]
+    TooLarge(&'static str, String),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl SendError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SendError::ItemNotFound(_) => "item_not_found",
+            SendError::NotRunning => "not_running",
+            SendError::NotTrusted(_) => "not_trusted",
+            SendError::Unsupported(..) => "unsupported",
+            SendError::NotAllowed(..) => "not_allowed",
+            SendError::TooLarge(..) => "too_large",
+            SendError::Other(_) => "other",
+        }
+    }
+}
+
+impl serde::Serialize for SendError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut s = serializer.serialize_struct("SendError", 2)?;
+        s.serialize_field("kind", self.kind())?;
+        s.serialize_field("message", &self.to_string())?;
+        s.end()
+    }
+}
+
+/// What happened to an item sent with `SyncManager::send_to`.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Delivery {
+    Sent,
+    /// The device is offline, the item goes out when it comes back.
+    Queued,
+}
+
+/// State shared between the sync manager and its connection threads.
+struct Shared {
+    app: AppHandle,
+    identity: Identity,
+    hello: Hello,
+    trust: Mutex<TrustStore>,
+    pending: Mutex<HashMap<String, PendingPairing>>,
+    links: Mutex<HashMap<String, Link>>,
+    dialing: Mutex<HashSet<String>>,
+    transfers: Mutex<Transfers>,
+    outbox: Mutex<Outbox>,
+    static_peers: Mutex<StaticPeers>,
+    presence: Mutex<Presence>,
+    boards: Mutex<Boards>,
+    revocations: Mutex<Revocations>,
+    /// How many more items each device may send us.
+    rates: Mutex<RateLimits>,
+    transports: Transports,
+}
+
+/// Owns everything needed to talk to other devices on the network.
+pub struct SyncManager {
+    shared: Arc<Shared>,
+    discovery: Discovery,
+}
+
+impl SyncManager {
+    pub fn new(app: &AppHandle) -> Result<Self, anyhow::Error> {
+        let data_dir = app
+            .path()
+            .app_local_data_dir()
+            .with_context(|| "failed to get app local data dir")?;
+        let identity = Identity::load_or_create(&data_dir)?;
+        let signing_key = identity.signing_key().verifying_key().to_bytes().to_vec();
+
+        let sync_port = app
+            .state::<Mutex<Settings>>()
+            .lock()
+            .map_err(|e| anyhow!("settings lock was poisoned: {}", e))?
+            .sync_port
+            .unwrap_or(0);
+        let transports = Transports::bind(&data_dir, sync_port)?;
+
+        let local = LocalDevice {
+            id: identity.device_id(),
+            name: tauri_plugin_os::hostname(),
+            os: tauri_plugin_os::platform().to_string(),
+        };
+
+        let shared = Arc::new(Shared {
+            app: app.clone(),
+            hello: Hello {
+                version: PROTOCOL_VERSION,
+                intent: Intent::Sync,
+                device_id: local.id.clone(),
+                public_key: identity.public.clone(),
+                name: local.name.clone(),
+                os: local.os.clone(),
+                capabilities: Capabilities::ALL,
+                signing_key,
+            },
+            identity,
+            trust: Mutex::new(TrustStore::load(app)?),
+            pending: Mutex::new(HashMap::new()),
+            links: Mutex::new(HashMap::new()),
+            dialing: Mutex::new(HashSet::new()),
+            transfers: Mutex::new(Transfers::default()),
+            outbox: Mutex::new(Outbox::load(app)?),
+            static_peers: Mutex::new(StaticPeers::load(app)?),
+            presence: Mutex::new(Presence::default()),
+            boards: Mutex::new(Boards::load(app)?),
+            revocations: Mutex::new(Revocations::load(app)?),
+            rates: Mutex::new(RateLimits::default()),
+            transports,
+        });
+
+        for transport in shared.transports.listening() {
+            let (transport, accept_shared) = (Arc::clone(transport), Arc::clone(&shared));
+            std::thread::spawn(move || accept_loop(transport, accept_shared));
+        }
+
+        let (updates, connector_updates) = mpsc::channel();
+        let connector_shared = Arc::clone(&shared);
+        std::thread::spawn(move || link::connector(connector_shared, connector_updates));
+
+        let relay_shared = Arc::clone(&shared);
+        std::thread::spawn(move || relay::listen(relay_shared));
+
+        // the connector emits devices-update, since the list includes static peers it finds reachable
+        let loopback = cfg!(dev) || crate::instance().is_some();
+        let discovery = Discovery::start(&local, &shared.transports, loopback, move |peers| {
+            _ = updates.send(peers);
+        })?;
+
+        log::info!("started device sync as {} ({})", local.name, local.id);
+
+        Ok(Self { shared, discovery })
+    }
+
+    /// Devices found by discovery, and static peers that can be reached.
+    pub fn devices(&self) -> Vec<Peer> {
+        let discovered = self.discovery.peers();
+        match lock(&self.shared.static_peers) {
+            Ok(static_peers) => static_peers.merge(discovered),
+            Err(_) => discovered,
+        }
+    }
+
+    pub fn static_peers(&self) -> Result<Vec<StaticPeerStatus>, anyhow::Error> {
+        Ok(lock(&self.shared.static_peers)?.list())
+    }
+
+    /// Add a device by `host:port`. It is connected to from the next connector round on.
+    pub fn add_static_peer(&self, address: &str) -> Result<(), anyhow::Error> {
+        lock(&self.shared.static_peers)?.add(address)
+    }
+
+    pub fn remove_static_peer(&self, address: &str) -> Result<(), anyhow::Error> {
+        if !lock(&self.shared.static_peers)?.remove(address)? {
+            return Err(anyhow!("{} is not a static peer", address));
+        }
+        Ok(())
+    }
+
+    pub fn trusted_devices(&self) -> Result<Vec<TrustedDevice>, anyhow::Error> {
+        Ok(lock(&self.shared.trust)?.list())
+    }
+
+    pub fn forget(&self, device_id: &str) -> Result<(), anyhow::Error> {
+        forget_device(&self.shared, device_id)
+    }
+
+    /// Forget a device here and on every device that trusts it, as they connect to us or to each other. With
+    /// `wipe`, the device clears its history and pinned items the next time it connects to any of them.
+    pub fn revoke(&self, device_id: &str, wipe: bool) -> Result<(), anyhow::Error> {
+        revocation::issue(&self.shared, device_id, wipe)
+    }
+
+    /// Whether a trusted device is connected, how fast the connection is and how much went over it.
+    pub fn device_status(&self, device_id: &str) -> Result<DeviceStatus, anyhow::Error> {
+        if !lock(&self.shared.trust)?.contains(device_id) {
+            return Err(anyhow!("{} is not a trusted device", device_id));
+        }
+        Ok(lock(&self.shared.presence)?.get(device_id))
+    }
+
+    /// Change what is shared with a trusted device. Connected devices are held to it from the next item on.
+    pub fn set_policy(&self, device_id: &str, policy: SharingPolicy) -> Result<(), anyhow::Error> {
+        if !lock(&self.shared.trust)?.set_policy(device_id, policy)? {
+            return Err(anyhow!("{} is not a trusted device", device_id));
+        }
+        Ok(())
+    }
+
+    /// Stop or start showing notifications for items from a trusted device.
+    pub fn set_muted(&self, device_id: &str, muted: bool) -> Result<(), anyhow::Error> {
+        if !lock(&self.shared.trust)?.set_muted(device_id, muted)? {
+            return Err(anyhow!("{} is not a trusted device", device_id));
+        }
+        Ok(())
+    }
+
+    /// Queue a newly copied item for every trusted device whose policy allows it. Devices that are offline get it
+    /// when they come back.
+    pub fn broadcast(&self, item: &Arc<Contents>) -> Result<(), anyhow::Error> {
+        if !self
+            .shared
+            .app
+            .state::<Mutex<Settings>>()
+            .lock()
+            .is_ok_and(|s| s.auto_share)
+        {
+            return Ok(());
+        }
+        protocol::check_item(item)
+            .with_context(|| format!("this {} item is too large to share", item.kind()))?;
+        let trust = lock(&self.shared.trust)?;
+        let links = lock(&self.shared.links)?;
+        for device in trust.iter().filter(|d| d.policy.allows_send(item)) {
+            match links.get(&device.id) {
+                Some(link) if !link.capabilities.supports(item) => {}
+                Some(link) if link.send(Message::Item(Arc::clone(item))) => {
+                    log::debug!("sharing {} item with {}", item.kind(), link.name);
+                    transfer::offer(&self.shared, &device.id, item);
+                }
+                _ => enqueue(&self.shared, &device.id, item, false),
+            }
+        }
+        Ok(())
+    }
+
+    /// Send our pinned list to every connected device we share with, after it was changed here.
+    pub fn share_pins(&self, state: &PinState) {
+        pins::share(&self.shared, state, None);
+    }
+
+    /// Publish a named collection of items that devices we share with can subscribe to.
+    pub fn publish_board(
+        &self,
+        name: &str,
+        items: Vec<Arc<Contents>>,
+    ) -> Result<Uuid, anyhow::Error> {
+        let id = lock(&self.shared.boards)?.publish(name, items)?;
+        boards::share_catalog(&self.shared);
+        Ok(id)
+    }
+
+    /// Change the items on one of our boards, and its name if given. Subscribers get the new version.
+    pub fn update_board(
+        &self,
+        id: &str,
+        name: Option<&str>,
+        items: Vec<Arc<Contents>>,
+    ) -> Result<(), anyhow::Error> {
+        let id = &boards::parse_id(id)?;
+        let (subscribers, board) = {
+            let mut boards = lock(&self.shared.boards)?;
+            let subscribers = boards.update(id, name, items)?;
+            (subscribers, boards.board(id))
+        };
+        if let Some(board) = board {
+            for device_id in subscribers {
+                boards::send_board(&self.shared, &device_id, board.clone());
+            }
+        }
+        boards::share_catalog(&self.shared);
+        Ok(())
+    }
+
+    /// Stop publishing one of our boards. It disappears from every subscriber.
+    pub fn delete_board(&self, id: &str) -> Result<(), anyhow::Error> {
+        let id = &boards::parse_id(id)?;
+        let subscribers = lock(&self.shared.boards)?.delete(id)?;
+        for device_id in subscribers {
+            boards::send(&self.shared, &device_id, Message::BoardRevoked(*id));
+        }
+        boards::share_catalog(&self.shared);
+        Ok(())
+    }
+
+    pub fn published_boards(&self) -> Result<Vec<PublishedBoard>, anyhow::Error> {
+        Ok(lock(&self.shared.boards)?.published())
+    }
+
+    /// Remove a device from one of our boards. It can't subscribe again until it is forgotten and paired anew.
+    pub fn revoke_board_subscriber(&self, id: &str, device_id: &str) -> Result<(), anyhow::Error> {
+        let id = &boards::parse_id(id)?;
+        lock(&self.shared.boards)?.revoke(id, device_id)?;
+        boards::send(&self.shared, device_id, Message::BoardRevoked(*id));
+        Ok(())
+    }
+
+    /// Boards published by connected devices, as of the last catalog each of them sent.
+    pub fn available_boards(&self) -> Result<Vec<AvailableBoard>, anyhow::Error> {
+        Ok(lock(&self.shared.boards)?.available())
+    }
+
+    /// Subscribe to a board a connected device publishes. Its items arrive shortly after.
+    pub fn subscribe_board(&self, device_id: &str, id: &str) -> Result<(), anyhow::Error> {
+        let id = &boards::parse_id(id)?;
+        let name = lock(&self.shared.trust)?
+            .iter()
+            .find(|d| d.id == device_id)
+            .map(|d| d.name.clone())
+            .ok_or_else(|| anyhow!("{} is not a trusted device", device_id))?;
+        if !lock(&self.shared.links)?.contains_key(device_id) {
+            return Err(anyhow!("{} is not connected", name));
+        }
+        lock(&self.shared.boards)?.subscribe(device_id, &name, id)?;
+        boards::send(&self.shared, device_id, Message::BoardSubscribe(*id));
+        Ok(())
+    }
+
+    /// Returns false if we weren't subscribed to the board.
+    pub fn unsubscribe_board(&self, id: &str) -> Result<bool, anyhow::Error> {
+        let id = &boards::parse_id(id)?;
+        let Some(device_id) = lock(&self.shared.boards)?.unsubscribe(id)? else {
+            return Ok(false);
+        };
+        boards::send(&self.shared, &device_id, Message::BoardUnsubscribe(*id));
+        Ok(true)
+    }
+
+    /// The boards we subscribe to, with their items.
+    pub fn board_views(&self) -> Vec<BoardView> {
+        lock(&self.shared.boards)
+            .map(|b| b.views())
+            .unwrap_or_default()
+    }
+
+    /// An item on a board we subscribe to.
+    pub fn board_item(&self, id: u32) -> Option<Arc<Contents>> {
+        lock(&self.shared.boards).ok()?.item(id)
+    }
+
+    /// Send one item to one trusted device, whether or not it was shared automatically. If the device is
+    /// offline the item is queued for it.
+    pub fn send_to(&self, device_id: &str, item: &Arc<Contents>) -> Result<Delivery, SendError> {
+        let other = |e: anyhow::Error| SendError::Other(format!("{:#}", e));
+        let trust = lock(&self.shared.trust).map_err(other)?;
+        let device = trust
+            .iter()
+            .find(|d| d.id == device_id)
+            .ok_or_else(|| SendError::NotTrusted(device_id.to_string()))?;
+        if !device.policy.allows_send(item) {
+            return Err(SendError::NotAllowed(device.name.clone(), item.kind()));
+        }
+        protocol::check_item(item).map_err(|e| SendError::TooLarge(item.kind(), e.to_string()))?;
+        let links = lock(&self.shared.links).map_err(other)?;
+        if let Some(link) = links.get(device_id) {
+            if !link.capabilities.supports(item) {
+                return Err(SendError::Unsupported(link.name.clone(), item.kind()));
+            }
+            if link.send(Message::Push(Arc::clone(item))) {
+                log::info!("sent {} item to {}", item.kind(), link.name);
+                transfer::offer(&self.shared, device_id, item);
+                return Ok(Delivery::Sent);
+            }
+        }
+        lock(&self.shared.outbox)
+            .and_then(|mut o| o.push(device_id, item, true))
+            .map_err(other)?;
+        log::info!(
+            "{} is offline, queued {} item for it",
+            device.name,
+            item.kind()
+        );
+        Ok(Delivery::Queued)
+    }
+
+    pub fn queued_items(&self) -> Result<Vec<QueuedItem>, anyhow::Error> {
+        Ok(lock(&self.shared.outbox)?.list())
+    }
+
+    /// Drop items waiting for one device, or for every device if `device_id` is `None`.
+    pub fn clear_outbox(&self, device_id: Option<&str>) -> Result<(), anyhow::Error> {
+        lock(&self.shared.outbox)?.clear(device_id)
+    }
+
+    /// Accept or decline a file transfer that was too large to start without asking.
+    pub fn confirm_transfer(&self, transfer_id: &str, accept: bool) -> Result<(), anyhow::Error> {
+        let uid = Uuid::parse_str(transfer_id).with_context(|| "invalid transfer id")?;
+        transfer::confirm(&self.shared, uid, accept)
+    }
+
+    /// Answer a pairing request from `device_id` with the code the user entered, or else get ready to ask it to pair.
+    /// Asking goes over the network, so it is left to `PairRequest::send`, which doesn't need the sync manager.
+    pub fn pair(
+        &self,
+        device_id: &str,
+        code: Option<String>,
+    ) -> Result<Option<PairRequest>, anyhow::Error> {
+        if let Some(pending) = lock(&self.shared.pending)?.remove(device_id) {
+            let accepted = code.is_none_or(|c| pending.code.matches(&c));
+            if !accepted {
+                log::warn!("wrong pairing code entered for {}", device_id);
+            }
+            _ = pending.decision.send(accepted);
+            return match accepted {
+                true => Ok(None),
+                false => Err(anyhow!("The pairing code did not match")),
+            };
+        }
+
+        let peer = match self.discovery.peer(device_id) {
+            Some(peer) => peer,
+            None => lock(&self.shared.static_peers)?
+                .peer(device_id)
+                .ok_or_else(|| anyhow!("{} is not on the network", device_id))?,
+        };
+        Ok(Some(PairRequest {
+            peer,
+            shared: Arc::clone(&self.shared),
+        }))
+    }
+}
+
+/// Asking a device to pair, with what that needs taken from the sync manager.
+pub struct PairRequest {
+    peer: Peer,
+    shared: Arc<Shared>,
+}
+
+impl PairRequest {
+    /// Connect to the device and start pairing, returning the code to compare with the one it shows.
+    pub fn send(self) -> Result<PairingCode, anyhow::Error> {
+        let hello = Hello {
+            intent: Intent::Pair,
+            ..self.shared.hello.clone()
+        };
+        // pairing runs in a command on the async runtime, where QUIC's blocking calls can't be made, and every
+        // device listens on TCP
+        let stream = transport::connect_tcp(&self.peer, HANDSHAKE_TIMEOUT)?;
+        let stream = SecureStream::initiate(Box::new(stream), &self.shared.identity)?;
+        let (mut stream, remote, _) = open(stream, &hello)?;
+        if remote.device_id != self.peer.id {
+            return Err(anyhow!("{} answered as {}", self.peer.id, remote.device_id));
+        }
+        let code = pairing::initiate(&mut stream, &hello.public_key, &remote.public_key)?;
+
+        let shared = self.shared;
+        std::thread::spawn(move || await_pairing(stream, remote, shared));
+
+        Ok(code)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<std::sync::MutexGuard<'_, T>, anyhow::Error> {
+    mutex
+        .lock()
+        .map_err(|e| anyhow!("sync state lock was poisoned: {}", e))
+}
+
+fn forget_device(shared: &Shared, device_id: &str) -> Result<(), anyhow::Error> {
+    if !lock(&shared.trust)?.remove(device_id)? {
+        return Err(anyhow!("{} is not a trusted device", device_id));
+    }
+    if let Some(link) = lock(&shared.links)?.remove(device_id) {
+        link.close();
+    }
+    lock(&shared.presence)?.remove(device_id);
+    lock(&shared.rates)?.forget(device_id);
+    lock(&shared.boards)?.forget(device_id)?;
+    lock(&shared.outbox)?.clear(Some(device_id))
+}
+
+/// Trust a device we just paired with, even if it was revoked before.
+fn trust_device(shared: &Shared, device: TrustedDevice) -> Result<(), anyhow::Error> {
+    let device_id = device.id.clone();
+    lock(&shared.trust)?.add(device)?;
+    lock(&shared.revocations)?.remove(&device_id)
+}
+
+/// Run `f` on the clipboard manager. Must not be called while holding any of the sync locks.
+fn with_manager<T, F>(shared: &Shared, f: F) -> Option<T>
+where
+    F: FnOnce(&mut ClipboardManager) -> T,
+{
+    let state = shared.app.state::<Mutex<Option<ClipboardManager>>>();
+    let mut manager = state
+        .lock()
+        .map_err(|e| log::error!("Couldn't access clipboard manager: {}", e))
+        .ok()?;
+    manager.as_mut().map(f)
+}
+
+/// Record where an item another device sent us came from. Items copied on the sending device go over the wire
+/// without an origin, so that is filled in too. The device is named as we know it, or by its id if it's one we
+/// never paired with and the item was passed on to us.
+fn attribute(shared: &Shared, from: &str, item: Arc<Contents>) -> Arc<Contents> {
+    let origin = item
+        .meta()
+        .origin
+        .clone()
+        .unwrap_or_else(|| from.to_string());
+    let source = match origin == shared.hello.device_id {
+        true => Source::Local,
+        false => Source::Device {
+            name: lock(&shared.trust)
+                .ok()
+                .and_then(|t| t.get(&origin).map(|d| d.name.clone()))
+                .unwrap_or_else(|| origin.clone()),
+            id: origin.clone(),
+        },
+    };
+    let meta = ItemMeta {
+        origin: Some(origin),
+        source,
+        ..item.meta().clone()
+    };
+    Arc::new(Arc::unwrap_or_clone(item).with_meta(meta))
+}
+
+/// Exchange hellos over an established secure channel, making sure the key in the remote hello is the one
+/// that was authenticated by the Noise handshake.
+fn open(
+    mut stream: SecureStream,
+    hello: &Hello,
+) -> Result<(SecureStream, Hello, Capabilities), ProtocolError> {
+    let (remote, capabilities) = protocol::handshake(&mut stream, hello)?;
+    if remote.public_key != stream.remote_key() {
+        return Err(ProtocolError::Malformed(
+            "hello key does not match the authenticated key".into(),
+        ));
+    }
+    Ok((stream, remote, capabilities))
+}
+
+/// Open a sync connection to a trusted device on the network, over the best transport we both have.
+fn dial(shared: &Arc<Shared>, peer: &Peer) -> Result<(), anyhow::Error> {
+    let stream = shared.transports.connect(peer, HANDSHAKE_TIMEOUT)?;
+    dial_stream(shared, stream, &peer.id)
+}
+
+/// Open a sync connection to a trusted device through the relay.
+fn dial_relay(shared: &Arc<Shared>, relay: &str, device_id: &str) -> Result<(), anyhow::Error> {
+    dial_stream(
+        shared,
+        Box::new(relay::connect(relay, device_id)?),
+        device_id,
+    )
+}
+
+fn dial_stream(
+    shared: &Arc<Shared>,
+    stream: Box<dyn Connection>,
+    device_id: &str,
+) -> Result<(), anyhow::Error> {
+    let stream = SecureStream::initiate(stream, &shared.identity)?;
+    let (mut stream, remote, capabilities) = open(stream, &shared.hello)?;
+    if remote.device_id != device_id {
+        return Err(anyhow!("{} answered as {}", device_id, remote.device_id));
+    }
+    if revocation::deliver_wipe(shared, &mut stream, &remote) {
+        return Ok(());
+    }
+    if !lock(&shared.trust)?.is_trusted(&remote.device_id, &remote.public_key) {
+        return Err(anyhow!("{} is not a trusted device", remote.device_id));
+    }
+    link::run(stream, remote, capabilities, Arc::clone(shared), true);
+    Ok(())
+}
+
+/// Keep an item for a trusted device that is offline.
+fn enqueue(shared: &Shared, device_id: &str, item: &Arc<Contents>, pushed: bool) {
+    match lock(&shared.outbox).and_then(|mut o| o.push(device_id, item, pushed)) {
+        Ok(()) => log::debug!("queued {} item for {}", item.kind(), device_id),
+        Err(e) => log::warn!("Could not queue item for {}: {:#}", device_id, e),
+    }
+}
+
+/// Everything queued for a device while it was offline, oldest first. It stays queued until `unqueue` is called.
+fn queued(shared: &Shared, device_id: &str) -> Vec<Message> {
+    lock(&shared.outbox)
+        .and_then(|mut o| o.queued(device_id))
+        .unwrap_or_else(|e| {
+            log::error!("Could not read outbox for {}: {:#}", device_id, e);
+            vec![]
+        })
+}
+
+/// Take an item out of a device's queue, once it was written to the device or can't be sent to it.
+fn unqueue(shared: &Shared, device_id: &str, item: &Contents) {
+    let uid = item.meta().uid.to_string();
+    if let Err(e) = lock(&shared.outbox).and_then(|mut o| o.remove(device_id, &uid)) {
+        log::error!(
+            "Could not remove item from outbox for {}: {:#}",
+            device_id,
+            e
+        );
+    }
+}
+
+/// Take in an item received from another device, if our policy for it allows that. The files behind a paths
+/// item are downloaded first. A pushed item is taken in even if it started out here.
+fn receive_item(shared: &Shared, from: &str, item: Arc<Contents>, pushed: bool) {
+    if came_back(&shared.hello.device_id, &item, pushed) {
+        log::debug!("dropping item {} that originated here", item.meta().uid);
+        return;
+    }
+    let allowed =
+        lock(&shared.trust).is_ok_and(|t| t.policy(from).is_some_and(|p| p.allows_receive(&item)));
+    if !allowed {
+        log::info!(
+            "sharing policy for {} does not allow receiving this {} item",
+            from,
+            item.kind()
+        );
+        return;
+    }
+    if let Contents::FilePath { .. } = item.as_ref() {
+        // files in a folder both devices have don't need copying over
+        match path_mapping::map_shared(&shared.app, &item) {
+            Some(mapped) => deliver_item(shared, from, Arc::new(mapped), pushed),
+            None => transfer::start(shared, from, item, pushed),
+        }
+        return;
+    }
+    deliver_item(shared, from, item, pushed);
+}
+
+/// Whether an item another device sent us was copied here to begin with. Only one pushed on purpose is taken in.
+fn came_back(device_id: &str, item: &Contents, pushed: bool) -> bool {
+    !pushed && item.meta().origin.as_deref() == Some(device_id)
+}
+
+/// Hand a received item to the clipboard manager, and show a notification for it unless the device is muted.
+fn deliver_item(shared: &Shared, from: &str, item: Arc<Contents>, pushed: bool) {
+    let (write_received, notify_received) = shared
+        .app
+        .state::<Mutex<Settings>>()
+        .lock()
+        .map(|s| (s.write_received, s.notify_received))
+        .unwrap_or_default();
+    let state = shared.app.state::<Mutex<Option<ClipboardManager>>>();
+    let received = match state.lock() {
+        Ok(mut manager) => manager
+            .as_mut()
+            .and_then(|m| m.receive(item, write_received, pushed)),
+        Err(e) => {
+            log::error!("Couldn't access clipboard manager: {}", e);
+            None
+        }
+    };
+
+    if let Some(item) = received {
+        let unmuted = lock(&shared.trust)
+            .ok()
+            .and_then(|t| t.get(from).filter(|d| !d.muted).map(|d| d.name.clone()));
+        if let Some(name) = unmuted.filter(|_| notify_received) {
+            crate::notifications::item_received(&shared.app, &name, &item);
+        }
+        let event = ItemReceived {
+            device_id: from.to_string(),
+            pushed,
+            item,
+        };
+        if let Err(e) = shared.app.emit("item-received", event) {
+            log::error!("Could not emit item-received event: {}", e);
+        }
+    }
+}
+
+fn emit_pairing_result(shared: &Shared, device_id: String, accepted: bool) {
+    if let Err(e) = shared.app.emit(
+        "pairing-complete",
+        PairingResult {
+            device_id,
+            accepted,
+        },
+    ) {
+        log::error!("Could not emit pairing-complete event: {}", e);
+    }
+}
+
+/// Initiator side, after the code has been shown: wait for the other device's user to accept or reject it.
+fn await_pairing(mut stream: SecureStream, remote: Hello, shared: Arc<Shared>) {
+    _ = stream.stream().set_read_timeout(Some(PAIRING_TIMEOUT));
+    let accepted = match protocol::read_message(&mut stream) {
+        Ok(Message::PairResult(accepted)) => accepted,
+        Ok(other) => {
+            log::warn!("{} sent {} while pairing", remote.name, other.name());
+            false
+        }
+        Err(e) => {
+            log::warn!("pairing with {} failed: {}", remote.name, e);
+            false
+        }
+    };
+
+    if accepted {
+        let device = TrustedDevice::new(
+            remote.device_id.clone(),
+            remote.name,
+            remote.os,
+            remote.public_key,
+            remote.signing_key,
+        );
+        if let Err(e) = trust_device(&shared, device) {
+            log::error!("Could not save trusted device: {:#}", e);
+        }
+    }
+    emit_pairing_result(&shared, remote.device_id, accepted);
+}
+
+fn accept_loop(transport: Arc<dyn Transport>, shared: Arc<Shared>) {
+    loop {
+        match transport.accept() {
+            Ok(stream) => {
+                let shared = Arc::clone(&shared);
+                std::thread::spawn(move || serve(stream, shared));
+            }
+            Err(e) => log::error!(
+                "Could not accept sync connection over {}: {}",
+                transport.kind().name(),
+                e
+            ),
+        }
+    }
+}
+
+fn serve(stream: Box<dyn Connection>, shared: Arc<Shared>) {
+    _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+    let address = stream.remote();
+    let (mut stream, remote, capabilities) = match SecureStream::respond(stream, &shared.identity)
+        .and_then(|stream| open(stream, &shared.hello))
+    {
+        Ok(res) => res,
+        Err(e) => {
+            log::warn!("Handshake with {} failed: {}", address, e);
+            return;
+        }
+    };
+
+    if remote.intent == Intent::Pair {
+        if let Err(e) = answer_pairing(stream, remote, &shared) {
+            log::warn!("Pairing request failed: {:#}", e);
+        }
+        return;
+    }
+
+    let trusted = lock(&shared.trust)
+        .map(|t| t.is_trusted(&remote.device_id, &remote.public_key))
+        .unwrap_or(false);
+    if !trusted && revocation::deliver_wipe(&shared, &mut stream, &remote) {
+        return;
+    }
+    if !trusted {
+        log::warn!(
+            "refusing connection from unpaired device {} ({})",
+            remote.name,
+            remote.device_id
+        );
+        return;
+    }
+    link::run(stream, remote, capabilities, shared, false);
+}
+
+/// Responder side of pairing: derive the code, ask the user, and report their decision back.
+fn answer_pairing(
+    mut stream: SecureStream,
+    remote: Hello,
+    shared: &Shared,
+) -> Result<(), anyhow::Error> {
+    let code = pairing::respond(&mut stream, &shared.hello.public_key, &remote.public_key)?;
+    let (tx, rx) = mpsc::channel();
+
+    lock(&shared.pending)?.insert(
+        remote.device_id.clone(),
+        PendingPairing {
+            code: code.clone(),
+            decision: tx,
+        },
+    );
+    shared.app.emit(
+        "pairing-request",
+        PairingRequest {
+            device_id: remote.device_id.clone(),
+            name: remote.name.clone(),
+            words: code.words,
+        },
+    )?;
+
+    let accepted = match rx.recv_timeout(PAIRING_TIMEOUT) {
+        Ok(accepted) => accepted,
+        Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => {
+            lock(&shared.pending)?.remove(&remote.device_id);
+            log::info!("pairing request from {} timed out", remote.name);
+            false
+        }
+    };
+
+    if accepted {
+        trust_device(
+            shared,
+            TrustedDevice::new(
+                remote.device_id.clone(),
+                remote.name.clone(),
+                remote.os.clone(),
+                remote.public_key.clone(),
+                remote.signing_key.clone(),
+            ),
+        )?;
+    }
+    protocol::write_message(&mut stream, &Message::PairResult(accepted))?;
+    emit_pairing_result(shared, remote.device_id, accepted);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard_manager::History;
+    use discovery::Peer;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Instant;
+    use transport::TransportKind;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// An instance of the app as far as sharing copies goes, with a data dir, a clipboard and a history of its own.
+    /// Copies and received items go through `History` and `came_back` the way `ClipboardManager::check`,
+    /// `ClipboardManager::receive` and `receive_item` take them, and are sent to the other instances over TCP.
+    struct Instance {
+        dir: PathBuf,
+        identity: Identity,
+        transports: Transports,
+        history: Mutex<History>,
+        clipboard: Mutex<Option<String>>,
+        links: Mutex<Vec<SecureStream>>,
+        sent: AtomicUsize,
+        received: AtomicUsize,
+    }
+
+    impl Instance {
+        fn new() -> Arc<Self> {
+            let dir = std::env::temp_dir().join(format!("purple-clip-test-{}", Uuid::new_v4()));
+            Arc::new(Self {
+                identity: Identity::load_or_create(&dir).unwrap(),
+                transports: Transports::bind(&dir, 0).unwrap(),
+                dir,
+                history: Mutex::new(History::new()),
+                clipboard: Mutex::new(None),
+                links: Mutex::new(vec![]),
+                sent: AtomicUsize::new(0),
+                received: AtomicUsize::new(0),
+            })
+        }
+
+        fn hello(&self) -> Hello {
+            Hello {
+                version: PROTOCOL_VERSION,
+                intent: Intent::Sync,
+                device_id: self.identity.device_id(),
+                public_key: self.identity.public.clone(),
+                name: tauri_plugin_os::hostname(),
+                os: "test".into(),
+                capabilities: Capabilities::ALL,
+                signing_key: self
+                    .identity
+                    .signing_key()
+                    .verifying_key()
+                    .to_bytes()
+                    .to_vec(),
+            }
+        }
+
+        fn peer(&self) -> Peer {
+            Peer {
+                id: self.identity.device_id(),
+                name: tauri_plugin_os::hostname(),
+                os: "test".into(),
+                version: PROTOCOL_VERSION,
+                addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+                port: self.transports.port(),
+                static_address: None,
+                transports: vec![TransportKind::Tcp],
+                socket: None,
+            }
+        }
+
+        /// Start reading what the other end of `stream` sends, and share copies with it from now on.
+        fn add_link(self: &Arc<Self>, mut stream: SecureStream) {
+            let (remote, _) = protocol::handshake(&mut stream, &self.hello()).unwrap();
+            let mut reader = stream.try_clone().unwrap();
+            self.links.lock().unwrap().push(stream);
+            let instance = Arc::clone(self);
+            thread::spawn(move || {
+                while let Ok(msg) = protocol::read_message(&mut reader) {
+                    if let Message::Item(item) = msg {
+                        instance.receive(&remote.device_id, item);
+                    }
+                }
+            });
+        }
+
+        /// What the clipboard watcher and `ClipboardManager::check` do when the clipboard changed to `text`.
+        fn clipboard_changed(&self, text: &str) {
+            let copied = self
+                .history
+                .lock()
+                .unwrap()
+                .copied(Contents::from_text(text.into()));
+            if let Some(item) = copied {
+                for link in self.links.lock().unwrap().iter_mut() {
+                    protocol::write_message(link, &Message::Item(Arc::clone(&item))).unwrap();
+                    self.sent.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+
+        /// Copy `text` here. The clipboard stays locked until the change is handled, the way the watcher reports
+        /// one change at a time.
+        fn copy(&self, text: &str) {
+            let mut clipboard = self.clipboard.lock().unwrap();
+            *clipboard = Some(text.to_string());
+            self.clipboard_changed(text);
+        }
+
+        /// What `receive_item` and `ClipboardManager::receive` do with an item, writing it to the clipboard.
+        fn receive(&self, from: &str, item: Arc<Contents>) {
+            self.received.fetch_add(1, Ordering::SeqCst);
+            if came_back(&self.identity.device_id(), &item, false) {
+                panic!("{} sent us back our own item", from);
+            }
+            let mut clipboard = self.clipboard.lock().unwrap();
+            let Some(item) = self.history.lock().unwrap().received(item, true, false) else {
+                return;
+            };
+            if let Contents::Text { text, .. } = item.as_ref() {
+                *clipboard = Some(text.clone());
+                self.clipboard_changed(text);
+            }
+        }
+
+        fn clipboard(&self) -> Option<String> {
+            self.clipboard.lock().unwrap().clone()
+        }
+
+        fn counts(&self) -> (usize, usize) {
+            (
+                self.sent.load(Ordering::SeqCst),
+                self.received.load(Ordering::SeqCst),
+            )
+        }
+    }
+
+    impl Drop for Instance {
+        fn drop(&mut self) {
+            _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Three instances, each linked to the other two over TCP with the Noise handshake and hellos.
+    fn network() -> Vec<Arc<Instance>> {
+        let instances = (0..3).map(|_| Instance::new()).collect::<Vec<_>>();
+        for (a, b) in [(0, 1), (0, 2), (1, 2)] {
+            let server = Arc::clone(&instances[b]);
+            let listener = Arc::clone(
+                server
+                    .transports
+                    .listening()
+                    .find(|t| t.kind() == TransportKind::Tcp)
+                    .unwrap(),
+            );
+            let accepted = thread::spawn(move || {
+                let connection = listener.accept().unwrap();
+                connection.set_read_timeout(Some(TIMEOUT)).unwrap();
+                let stream = SecureStream::respond(connection, &server.identity).unwrap();
+                server.add_link(stream);
+            });
+            let client = &instances[a];
+            let connection = client
+                .transports
+                .connect(&instances[b].peer(), TIMEOUT)
+                .unwrap();
+            let stream = SecureStream::initiate(connection, &client.identity).unwrap();
+            client.add_link(stream);
+            accepted.join().unwrap();
+        }
+        instances
+    }
+
+    /// Wait for every instance to have received `expected` items, then a while longer for any that shouldn't come.
+    fn settle(instances: &[Arc<Instance>], expected: [usize; 3]) {
+        let deadline = Instant::now() + TIMEOUT;
+        while instances
+            .iter()
+            .zip(expected)
+            .any(|(i, expected)| i.counts().1 < expected)
+        {
+            assert!(Instant::now() < deadline, "items were not delivered");
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(300));
+    }
+
+    #[test]
+    fn no_echo_loop_between_three_instances() {
+        let instances = network();
+        let [a, b, c] = [&instances[0], &instances[1], &instances[2]];
+
+        a.copy("hello");
+        settle(&instances, [0, 1, 1]);
+        assert_eq!(a.counts(), (2, 0));
+        assert_eq!(b.counts(), (0, 1));
+        assert_eq!(c.counts(), (0, 1));
+        for instance in &instances {
+            assert_eq!(instance.clipboard().as_deref(), Some("hello"));
+        }
+
+        // the same text copied again later is a real copy, and shared
+        c.copy("hello");
+        settle(&instances, [1, 2, 1]);
+        assert_eq!(a.counts(), (2, 1));
+        assert_eq!(b.counts(), (0, 2));
+        assert_eq!(c.counts(), (2, 1));
+    }
+
+    #[test]
+    fn copies_on_every_instance_at_once() {
+        let instances = network();
+        thread::scope(|s| {
+            for (i, instance) in instances.iter().enumerate() {
+                s.spawn(move || instance.copy(&format!("copy {i}")));
+            }
+        });
+        settle(&instances, [2, 2, 2]);
+        for instance in &instances {
+            assert_eq!(instance.counts(), (2, 2));
+        }
+    }
+}
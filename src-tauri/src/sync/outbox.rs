@@ -0,0 +1,392 @@
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Manager};
+use tauri_plugin_store::StoreExt;
+
+use super::protocol::{self, Message};
+use crate::contents::Contents;
+
+const OUTBOX_STORE: &str = "outbox.json";
+/// Most items kept for one device, the oldest are dropped first.
+const MAX_ITEMS: usize = 50;
+/// Most bytes of encoded items kept for one device.
+const MAX_BYTES: u64 = 64 * 1024 * 1024;
+/// Items older than this are not worth delivering any more.
+const EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// An item waiting for a trusted device to come back online.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedItem {
+    pub device_id: String,
+    pub uid: String,
+    pub kind: String,
+    pub size: u64,
+    pub queued_at: u64,
+    /// Sent with `send_item` rather than shared automatically.
+    pub pushed: bool,
+}
+
+impl QueuedItem {
+    fn file_name(&self) -> String {
+        format!("{}-{}.item", self.device_id, self.uid)
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.queued_at) > EXPIRY.as_secs()
+    }
+}
+
+/// Items that could not be delivered, per trusted device and in the order they were copied. The list lives in
+/// `outbox.json` and each item's encoded message in its own file under `outbox/` in the app data dir.
+pub struct Outbox {
+    app: AppHandle,
+    dir: PathBuf,
+    queues: Queues,
+}
+
+impl Outbox {
+    pub fn load(app: &AppHandle) -> Result<Self, anyhow::Error> {
+        let store = app
+            .store(OUTBOX_STORE)
+            .with_context(|| "failed to get or create outbox store")?;
+        let queued = match store.get("queued") {
+            Some(value) => serde_json::from_value::<Vec<QueuedItem>>(value)
+                .with_context(|| "outbox store contents were malformed")?,
+            None => vec![],
+        };
+        store.close_resource();
+
+        let mut dir = app
+            .path()
+            .app_local_data_dir()
+            .with_context(|| "failed to get app local data dir")?;
+        dir.push("outbox");
+
+        let mut outbox = Self {
+            app: app.clone(),
+            dir,
+            queues: Queues::default(),
+        };
+        for item in queued {
+            outbox
+                .queues
+                .0
+                .entry(item.device_id.clone())
+                .or_default()
+                .push_back(item);
+        }
+        outbox.prune_expired()?;
+        Ok(outbox)
+    }
+
+    /// Queue an item for a device, making room by dropping expired items and then its oldest queued ones. Items the
+    /// device would turn away are refused here rather than kept and dropped when they are read back.
+    pub fn push(
+        &mut self,
+        device_id: &str,
+        item: &Arc<Contents>,
+        pushed: bool,
+    ) -> Result<(), anyhow::Error> {
+        protocol::check_item(item)
+            .with_context(|| format!("the {} item can't be kept for later", item.kind()))?;
+        let msg = match pushed {
+            true => Message::Push(Arc::clone(item)),
+            false => Message::Item(Arc::clone(item)),
+        };
+        let data = protocol::encode(&msg);
+        if data.len() as u64 > MAX_BYTES {
+            return Err(anyhow!(
+                "the {} item is too large to keep for later",
+                item.kind()
+            ));
+        }
+
+        let queued = QueuedItem {
+            device_id: device_id.to_string(),
+            uid: item.meta().uid.to_string(),
+            kind: item.kind().to_string(),
+            size: data.len() as u64,
+            queued_at: now(),
+            pushed,
+        };
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create outbox dir at {}", self.dir.display()))?;
+        let path = self.dir.join(queued.file_name());
+        fs::write(&path, data)
+            .with_context(|| format!("failed to write queued item to {}", path.display()))?;
+
+        let expired = self.queues.drop_expired(now());
+        for item in &expired {
+            log::info!(
+                "dropping a {} item queued for {} that expired",
+                item.kind,
+                item.device_id
+            );
+        }
+        let evicted = self.queues.push(queued);
+        for item in &evicted {
+            log::info!(
+                "outbox for {} is full, dropping an old {} item",
+                device_id,
+                item.kind
+            );
+        }
+        for item in expired.iter().chain(&evicted) {
+            self.remove_file(item);
+        }
+        self.save()
+    }
+
+    /// Everything queued for a device, oldest first, leaving out anything that has expired. The items stay queued
+    /// until `remove` is called for each once it has been sent.
+    pub fn queued(&mut self, device_id: &str) -> Result<Vec<Message>, anyhow::Error> {
+        self.prune_expired()?;
+        let Some(queue) = self.queues.0.get(device_id) else {
+            return Ok(vec![]);
+        };
+        let mut messages = vec![];
+        let mut unreadable = vec![];
+        for queued in queue {
+            let path = self.dir.join(queued.file_name());
+            match fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(protocol::decode(&data)?))
+            {
+                Ok(msg) => messages.push(msg),
+                Err(e) => {
+                    log::error!("Could not read queued item {}: {:#}", path.display(), e);
+                    unreadable.push(queued.uid.clone());
+                }
+            }
+        }
+        for uid in unreadable {
+            self.remove(device_id, &uid)?;
+        }
+        Ok(messages)
+    }
+
+    /// Forget an item queued for a device, once it was sent or can't be.
+    pub fn remove(&mut self, device_id: &str, uid: &str) -> Result<(), anyhow::Error> {
+        let Some(item) = self.queues.remove(device_id, uid) else {
+            return Ok(());
+        };
+        self.remove_file(&item);
+        self.save()
+    }
+
+    /// Items waiting to be sent, leaving out those that expired and are only waiting to be cleaned up.
+    pub fn list(&self) -> Vec<QueuedItem> {
+        let now = now();
+        let mut items = self
+            .queues
+            .entries()
+            .into_iter()
+            .filter(|q| !q.expired(now))
+            .collect::<Vec<_>>();
+        items.sort_by_key(|q| q.queued_at);
+        items
+    }
+
+    /// Drop everything queued for one device, or for every device if `device_id` is `None`.
+    pub fn clear(&mut self, device_id: Option<&str>) -> Result<(), anyhow::Error> {
+        let cleared = match device_id {
+            Some(id) => self
+                .queues
+                .0
+                .remove(id)
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>(),
+            None => self.queues.0.drain().flat_map(|(_, q)| q).collect(),
+        };
+        for item in &cleared {
+            self.remove_file(item);
+        }
+        self.save()
+    }
+
+    fn prune_expired(&mut self) -> Result<(), anyhow::Error> {
+        let expired = self.queues.drop_expired(now());
+        if expired.is_empty() {
+            return Ok(());
+        }
+        for item in &expired {
+            log::info!(
+                "dropping a {} item queued for {} that expired",
+                item.kind,
+                item.device_id
+            );
+            self.remove_file(item);
+        }
+        self.save()
+    }
+
+    fn remove_file(&self, item: &QueuedItem) {
+        let path = self.dir.join(item.file_name());
+        if let Err(e) = fs::remove_file(&path) {
+            log::warn!("Could not remove queued item {}: {}", path.display(), e);
+        }
+    }
+
+    fn save(&self) -> Result<(), anyhow::Error> {
+        let store = self
+            .app
+            .store(OUTBOX_STORE)
+            .with_context(|| "failed to get outbox store")?;
+        store.set("queued", serde_json::to_value(self.queues.entries())?);
+        Ok(())
+    }
+}
+
+/// The items queued for each device, oldest first, without their files.
+#[derive(Default)]
+struct Queues(HashMap<String, VecDeque<QueuedItem>>);
+
+impl Queues {
+    /// Queue an item, replacing one with the same uid, then take the device's oldest items out until its queue is
+    /// within `MAX_ITEMS` and `MAX_BYTES` again. Returns the items taken out, leaving their files to the caller.
+    fn push(&mut self, queued: QueuedItem) -> Vec<QueuedItem> {
+        let queue = self.0.entry(queued.device_id.clone()).or_default();
+        queue.retain(|q| q.uid != queued.uid);
+        queue.push_back(queued);
+        let mut evicted = vec![];
+        while queue.len() > MAX_ITEMS || queue.iter().map(|q| q.size).sum::<u64>() > MAX_BYTES {
+            evicted.extend(queue.pop_front());
+        }
+        evicted
+    }
+
+    fn remove(&mut self, device_id: &str, uid: &str) -> Option<QueuedItem> {
+        let queue = self.0.get_mut(device_id)?;
+        let index = queue.iter().position(|q| q.uid == uid)?;
+        let removed = queue.remove(index);
+        if queue.is_empty() {
+            self.0.remove(device_id);
+        }
+        removed
+    }
+
+    /// Take expired items out of the queues, leaving their files to the caller.
+    fn drop_expired(&mut self, now: u64) -> Vec<QueuedItem> {
+        let mut expired = vec![];
+        for queue in self.0.values_mut() {
+            while queue.front().is_some_and(|q| q.expired(now)) {
+                expired.extend(queue.pop_front());
+            }
+        }
+        self.0.retain(|_, q| !q.is_empty());
+        expired
+    }
+
+    fn entries(&self) -> Vec<QueuedItem> {
+        self.0.values().flatten().cloned().collect()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn queued(device_id: &str, uid: &str, size: u64, queued_at: u64) -> QueuedItem {
+        QueuedItem {
+            device_id: device_id.to_string(),
+            uid: uid.to_string(),
+            kind: "text".to_string(),
+            size,
+            queued_at,
+            pushed: false,
+        }
+    }
+
+    fn uids(queues: &Queues, device_id: &str) -> Vec<String> {
+        queues.0[device_id].iter().map(|q| q.uid.clone()).collect()
+    }
+
+    #[test]
+    fn oldest_items_go_first_past_max_items() {
+        let mut queues = Queues::default();
+        for i in 0..MAX_ITEMS {
+            assert!(queues.push(queued("a", &i.to_string(), 1, NOW)).is_empty());
+        }
+        queues.push(queued("b", "other", 1, NOW));
+
+        let evicted = queues.push(queued("a", "new", 1, NOW));
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].uid, "0");
+        let kept = uids(&queues, "a");
+        assert_eq!(kept.len(), MAX_ITEMS);
+        assert_eq!(kept.first().unwrap(), "1");
+        assert_eq!(kept.last().unwrap(), "new");
+        assert_eq!(uids(&queues, "b"), ["other"]);
+    }
+
+    #[test]
+    fn oldest_items_go_first_past_max_bytes() {
+        let mut queues = Queues::default();
+        queues.push(queued("a", "1", MAX_BYTES / 2, NOW));
+        queues.push(queued("a", "2", MAX_BYTES / 4, NOW));
+        queues.push(queued("b", "other", MAX_BYTES, NOW));
+
+        let evicted = queues.push(queued("a", "3", MAX_BYTES / 4, NOW));
+        assert!(evicted.is_empty());
+        let evicted = queues.push(queued("a", "4", MAX_BYTES / 2, NOW));
+        let evicted = evicted.iter().map(|q| q.uid.as_str()).collect::<Vec<_>>();
+        assert_eq!(evicted, ["1"]);
+        assert_eq!(uids(&queues, "a"), ["2", "3", "4"]);
+        assert_eq!(uids(&queues, "b"), ["other"]);
+    }
+
+    #[test]
+    fn items_expire_after_a_day() {
+        let mut queues = Queues::default();
+        let day = EXPIRY.as_secs();
+        queues.push(queued("a", "old", 1, NOW - day - 1));
+        queues.push(queued("a", "day", 1, NOW - day));
+        queues.push(queued("a", "new", 1, NOW));
+        queues.push(queued("b", "old", 1, NOW - day - 1));
+
+        let expired = queues.drop_expired(NOW);
+        let mut expired = expired
+            .iter()
+            .map(|q| format!("{}/{}", q.device_id, q.uid))
+            .collect::<Vec<_>>();
+        expired.sort();
+        assert_eq!(expired, ["a/old", "b/old"]);
+        assert_eq!(uids(&queues, "a"), ["day", "new"]);
+        assert!(!queues.0.contains_key("b"));
+        assert!(queues.drop_expired(NOW).is_empty());
+    }
+
+    #[test]
+    fn an_item_queued_again_replaces_the_old_one() {
+        let mut queues = Queues::default();
+        queues.push(queued("a", "1", 10, NOW - 10));
+        queues.push(queued("a", "2", 10, NOW - 5));
+        queues.push(queued("b", "1", 10, NOW - 10));
+
+        let evicted = queues.push(queued("a", "1", 20, NOW));
+        assert!(evicted.is_empty());
+        assert_eq!(uids(&queues, "a"), ["2", "1"]);
+        let again = &queues.0["a"][1];
+        assert_eq!((again.size, again.queued_at), (20, NOW));
+        assert_eq!(uids(&queues, "b"), ["1"]);
+
+        assert_eq!(queues.remove("a", "1").unwrap().size, 20);
+        assert_eq!(uids(&queues, "a"), ["2"]);
+        assert!(queues.remove("a", "1").is_none());
+    }
+}
@@ -0,0 +1,218 @@
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+
+use super::protocol::{self, Message, ProtocolError};
+
+const NONCE_LEN: usize = 32;
+
+const WORDS: [&str; 64] = [
+    "acorn", "amber", "anchor", "apple", "arrow", "badger", "banjo", "basil", "beacon", "birch",
+    "bison", "blossom", "canyon", "cedar", "cherry", "cobalt", "comet", "coral", "cricket",
+    "daisy", "delta", "dune", "ember", "falcon", "fern", "fjord", "garnet", "glacier", "granite",
+    "harbor", "hazel", "heron", "iris", "ivory", "jasper", "juniper", "kestrel", "lagoon",
+    "lantern", "lemon", "lotus", "maple", "meadow", "nectar", "nutmeg", "oasis", "olive", "orchid",
+    "otter", "pebble", "pepper", "plum", "quartz", "raven", "river", "saffron", "sparrow",
+    "spruce", "thistle", "tulip", "velvet", "walnut", "willow", "zephyr",
+];
+
+/// Short authentication string both devices derive from the pairing exchange.
+/// If a third party swapped keys in the middle, the two devices end up showing different values.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct PairingCode {
+    pub code: String,
+    pub words: Vec<&'static str>,
+}
+
+impl PairingCode {
+    fn derive(initiator_key: &[u8], responder_key: &[u8], nonce_i: &[u8], nonce_r: &[u8]) -> Self {
+        let hash = Sha256::new()
+            .chain_update(b"purple-clip pairing v1")
+            .chain_update(initiator_key)
+            .chain_update(responder_key)
+            .chain_update(nonce_i)
+            .chain_update(nonce_r)
+            .finalize();
+
+        let number = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) % 1_000_000;
+        let words = hash[4..8]
+            .iter()
+            .map(|b| WORDS[*b as usize % WORDS.len()])
+            .collect();
+
+        Self {
+            code: format!("{:06}", number),
+            words,
+        }
+    }
+
+    /// Compare against a code typed in by the user, ignoring spacing.
+    pub fn matches(&self, typed: &str) -> bool {
+        typed
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .eq(self.code.chars())
+    }
+}
+
+/// Initiator side: commit to a nonce, learn the responder's, then reveal ours.
+/// Committing first stops either side from picking its nonce after seeing the other's.
+pub fn initiate<S: Read + Write>(
+    stream: &mut S,
+    local_key: &[u8],
+    remote_key: &[u8],
+) -> Result<PairingCode, ProtocolError> {
+    let nonce = random_nonce();
+    protocol::write_message(
+        stream,
+        &Message::PairCommit(Sha256::digest(&nonce).to_vec()),
+    )?;
+    let remote_nonce = match protocol::read_message(stream)? {
+        Message::PairNonce(n) => n,
+        other => return Err(ProtocolError::Unexpected(other.name())),
+    };
+    protocol::write_message(stream, &Message::PairNonce(nonce.clone()))?;
+    Ok(PairingCode::derive(
+        local_key,
+        remote_key,
+        &nonce,
+        &remote_nonce,
+    ))
+}
+
+/// Responder side of [`initiate`].
+pub fn respond<S: Read + Write>(
+    stream: &mut S,
+    local_key: &[u8],
+    remote_key: &[u8],
+) -> Result<PairingCode, ProtocolError> {
+    let commitment = match protocol::read_message(stream)? {
+        Message::PairCommit(c) => c,
+        other => return Err(ProtocolError::Unexpected(other.name())),
+    };
+    let nonce = random_nonce();
+    protocol::write_message(stream, &Message::PairNonce(nonce.clone()))?;
+    let remote_nonce = match protocol::read_message(stream)? {
+        Message::PairNonce(n) => n,
+        other => return Err(ProtocolError::Unexpected(other.name())),
+    };
+    if Sha256::digest(&remote_nonce)[..] != commitment[..] {
+        return Err(ProtocolError::Malformed(
+            "pairing nonce does not match its commitment".into(),
+        ));
+    }
+    Ok(PairingCode::derive(
+        remote_key,
+        local_key,
+        &remote_nonce,
+        &nonce,
+    ))
+}
+
+fn random_nonce() -> Vec<u8> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    const INITIATOR_KEY: &[u8] = &[1; 32];
+    const RESPONDER_KEY: &[u8] = &[2; 32];
+
+    fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    /// Run both sides of the exchange, returning the code each of them derived.
+    fn pair() -> (PairingCode, PairingCode) {
+        let (mut initiator, mut responder) = connected();
+        let responding =
+            thread::spawn(move || respond(&mut responder, RESPONDER_KEY, INITIATOR_KEY).unwrap());
+        let shown = initiate(&mut initiator, INITIATOR_KEY, RESPONDER_KEY).unwrap();
+        (shown, responding.join().unwrap())
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_code() {
+        let (initiator, responder) = pair();
+        assert_eq!(initiator, responder);
+        assert_eq!(initiator.code.len(), 6);
+        assert!(initiator.code.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(initiator.words.len(), 4);
+        assert!(initiator.words.iter().all(|w| WORDS.contains(w)));
+        // fresh nonces every time
+        assert_ne!(pair().0, initiator);
+    }
+
+    #[test]
+    fn swapped_keys_give_a_different_code() {
+        let nonces = ([3; NONCE_LEN], [4; NONCE_LEN]);
+        let honest = PairingCode::derive(INITIATOR_KEY, RESPONDER_KEY, &nonces.0, &nonces.1);
+        let swapped = PairingCode::derive(&[9; 32], RESPONDER_KEY, &nonces.0, &nonces.1);
+        assert_ne!(honest.code, swapped.code);
+    }
+
+    /// An initiator that reveals `reveal` after committing to `commit`.
+    fn initiate_with(commit: Vec<u8>, reveal: Vec<u8>) -> Result<PairingCode, ProtocolError> {
+        let (mut initiator, mut responder) = connected();
+        let initiating = thread::spawn(move || {
+            protocol::write_message(&mut initiator, &Message::PairCommit(commit)).unwrap();
+            protocol::read_message(&mut initiator).unwrap();
+            protocol::write_message(&mut initiator, &Message::PairNonce(reveal)).unwrap();
+        });
+        let result = respond(&mut responder, RESPONDER_KEY, INITIATOR_KEY);
+        initiating.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn reveal_must_match_the_commitment() {
+        let nonce = random_nonce();
+        let commit = Sha256::digest(&nonce).to_vec();
+        initiate_with(commit.clone(), nonce).unwrap();
+
+        let e = initiate_with(commit, random_nonce()).unwrap_err();
+        assert!(matches!(e, ProtocolError::Malformed(_)), "{e}");
+        let e = initiate_with(vec![0; 32], random_nonce()).unwrap_err();
+        assert!(matches!(e, ProtocolError::Malformed(_)), "{e}");
+    }
+
+    #[test]
+    fn nonce_before_a_commitment_is_rejected() {
+        let (mut initiator, mut responder) = connected();
+        protocol::write_message(&mut initiator, &Message::PairNonce(random_nonce())).unwrap();
+        let e = respond(&mut responder, RESPONDER_KEY, INITIATOR_KEY).unwrap_err();
+        assert!(matches!(e, ProtocolError::Unexpected(_)), "{e}");
+    }
+
+    #[test]
+    fn wrong_code_fails_the_pairing() {
+        let (mut initiator, mut responder) = connected();
+        let responding = thread::spawn(move || {
+            let code = respond(&mut responder, RESPONDER_KEY, INITIATOR_KEY).unwrap();
+            // the user types in a code that isn't the one on the other screen
+            let wrong = format!("{:06}", (code.code.parse::<u32>().unwrap() + 1) % 1_000_000);
+            let accepted = code.matches(&wrong);
+            protocol::write_message(&mut responder, &Message::PairResult(accepted)).unwrap();
+        });
+        let shown = initiate(&mut initiator, INITIATOR_KEY, RESPONDER_KEY).unwrap();
+        assert!(matches!(
+            protocol::read_message(&mut initiator).unwrap(),
+            Message::PairResult(false)
+        ));
+        responding.join().unwrap();
+
+        // while the right one is taken with any spacing
+        let spaced = format!("{} {}", &shown.code[..3], &shown.code[3..]);
+        assert!(shown.matches(&spaced));
+        assert!(!shown.matches(&shown.code[..5]));
+    }
+}
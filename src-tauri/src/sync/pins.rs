@@ -0,0 +1,112 @@
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::protocol::Message;
+use super::{lock, with_manager, Shared};
+use crate::contents::Contents;
+use crate::path_mapping;
+use crate::pinned::PinState;
+
+/// Send our pinned list to every connected device we share with, except the one it was just merged from.
+pub fn share(shared: &Shared, state: &PinState, except: Option<&str>) {
+    let Ok(trust) = lock(&shared.trust) else {
+        return;
+    };
+    let Ok(links) = lock(&shared.links) else {
+        return;
+    };
+    for device in trust
+        .iter()
+        .filter(|d| d.policy.sends() && Some(d.id.as_str()) != except)
+    {
+        if let Some(link) = links.get(&device.id) {
+            link.send(Message::Pins(state.clone()));
+        }
+    }
+}
+
+/// Our pinned list, to send to a device that just connected.
+pub fn local_state(shared: &Shared) -> Option<PinState> {
+    with_manager(shared, |m| m.pin_state())
+}
+
+/// Merge a device's pinned list into ours. The device gets the merged list back if it was missing anything,
+/// and the other devices get it if ours changed, so every device converges on the same list. Contents we don't
+/// have yet are asked for.
+pub fn merge(shared: &Shared, from: &str, remote: PinState) {
+    let Some(policy) = lock(&shared.trust)
+        .ok()
+        .and_then(|t| t.policy(from).cloned())
+    else {
+        return;
+    };
+    if !policy.receives() {
+        log::debug!("sharing policy for {} does not allow receiving pins", from);
+        return;
+    }
+    let Some((changed, missing, merged)) = with_manager(shared, |m| {
+        let (changed, missing) = m.merge_pins(&remote);
+        (changed, missing, m.pin_state())
+    }) else {
+        return;
+    };
+
+    if changed {
+        log::info!("merged pinned items from {}", from);
+        share(shared, &merged, Some(from));
+    }
+    let Ok(links) = lock(&shared.links) else {
+        return;
+    };
+    let Some(link) = links.get(from) else {
+        return;
+    };
+    if merged != remote && policy.sends() {
+        link.send(Message::Pins(merged));
+    }
+    if !missing.is_empty() {
+        link.send(Message::PinRequest(missing));
+    }
+}
+
+/// Send a device the contents of the pinned items it asked for, as far as our policy for it allows.
+pub fn answer(shared: &Shared, from: &str, ids: Vec<Uuid>) {
+    let Some(policy) = lock(&shared.trust)
+        .ok()
+        .and_then(|t| t.policy(from).cloned())
+    else {
+        return;
+    };
+    let items = with_manager(shared, |m| {
+        ids.iter()
+            .filter_map(|id| m.pinned_item(id))
+            .collect::<Vec<_>>()
+    })
+    .unwrap_or_default();
+    let Ok(links) = lock(&shared.links) else {
+        return;
+    };
+    let Some(link) = links.get(from) else {
+        return;
+    };
+    for item in items {
+        if policy.allows_send(&item) && link.capabilities.supports(&item) {
+            link.send(Message::PinnedItem(item));
+        }
+    }
+}
+
+pub fn receive(shared: &Shared, from: &str, item: Arc<Contents>) {
+    let allowed =
+        lock(&shared.trust).is_ok_and(|t| t.policy(from).is_some_and(|p| p.allows_receive(&item)));
+    if !allowed {
+        log::info!(
+            "sharing policy for {} does not allow receiving this pinned {} item",
+            from,
+            item.kind()
+        );
+        return;
+    }
+    let item = path_mapping::map_item(&shared.app, item);
+    with_manager(shared, |m| m.receive_pinned(Arc::unwrap_or_clone(item)));
+}
@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+
+use crate::contents::Contents;
+
+/// Which way items may flow between us and a trusted device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// We send items to the device but ignore anything it sends us.
+    Send,
+    /// We take items from the device but never send it any.
+    Receive,
+    #[default]
+    Both,
+    None,
+}
+
+/// What we are willing to share with one trusted device. Checked both before sending an item to it and
+/// when an item arrives from it, so a policy holds even if the other device doesn't have a matching one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SharingPolicy {
+    pub direction: Direction,
+    pub text: bool,
+    pub image: bool,
+    pub paths: bool,
+    /// Largest item in bytes that may be sent or received, `None` for no limit.
+    pub max_size: Option<usize>,
+}
+
+impl Default for SharingPolicy {
+    fn default() -> Self {
+        Self {
+            direction: Direction::Both,
+            text: true,
+            image: true,
+            paths: true,
+            max_size: None,
+        }
+    }
+}
+
+impl SharingPolicy {
+    pub fn allows_send(&self, item: &Contents) -> bool {
+        self.sends() && self.allows(item)
+    }
+
+    pub fn allows_receive(&self, item: &Contents) -> bool {
+        self.receives() && self.allows(item)
+    }
+
+    pub fn sends(&self) -> bool {
+        matches!(self.direction, Direction::Send | Direction::Both)
+    }
+
+    pub fn receives(&self) -> bool {
+        matches!(self.direction, Direction::Receive | Direction::Both)
+    }
+
+    fn allows(&self, item: &Contents) -> bool {
+        let kind = match item {
+            Contents::FilePath { .. } => self.paths,
+            Contents::Image { .. } => self.image,
+            Contents::Text { .. } => self.text,
+        };
+        kind && self.max_size.is_none_or(|max| item.size() <= max)
+    }
+}
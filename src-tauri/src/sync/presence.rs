@@ -0,0 +1,214 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tauri::Emitter;
+
+use super::transport::TransportKind;
+use super::{lock, Shared};
+
+/// Traffic and last seen times change with every message, so they are only sent this often.
+const TRAFFIC_EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Connected,
+    #[default]
+    Disconnected,
+}
+
+/// How the connection to a trusted device is doing, sent with every `device-status` event.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceStatus {
+    pub device_id: String,
+    pub state: ConnectionState,
+    /// What the current connection runs over.
+    pub transport: Option<TransportKind>,
+    /// Unix time in seconds of the last message from the device.
+    pub last_seen: Option<u64>,
+    /// Round trip time of the last heartbeat, in milliseconds.
+    pub rtt_ms: Option<u64>,
+    /// Bytes sent to and received from the device since the app started, counted before encryption.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Why the last connection closed or the last attempt to connect failed.
+    pub last_error: Option<String>,
+}
+
+/// Connection status of every trusted device we have tried to reach since starting. Kept in memory only.
+#[derive(Default)]
+pub struct Presence {
+    devices: HashMap<String, DeviceStatus>,
+    /// When `device-status` last went out for each device.
+    emitted: HashMap<String, Instant>,
+}
+
+impl Presence {
+    /// Status of a device, disconnected with nothing known if it hasn't been reached yet.
+    pub fn get(&self, device_id: &str) -> DeviceStatus {
+        self.devices
+            .get(device_id)
+            .cloned()
+            .unwrap_or_else(|| DeviceStatus {
+                device_id: device_id.to_string(),
+                ..Default::default()
+            })
+    }
+
+    pub fn remove(&mut self, device_id: &str) {
+        self.devices.remove(device_id);
+        self.emitted.remove(device_id);
+    }
+
+    /// Count traffic on a link, returning the status if it is time to send it.
+    fn count(&mut self, device_id: &str, sent: u64, received: u64) -> Option<DeviceStatus> {
+        let status = self.entry(device_id);
+        status.bytes_sent += sent;
+        status.bytes_received += received;
+        if received > 0 {
+            status.last_seen = Some(now());
+        }
+        let status = status.clone();
+        let due = self
+            .emitted
+            .get(device_id)
+            .is_none_or(|at| at.elapsed() >= TRAFFIC_EVENT_INTERVAL);
+        if !due || (sent == 0 && received == 0) {
+            return None;
+        }
+        self.emitted.insert(device_id.to_string(), Instant::now());
+        Some(status)
+    }
+
+    fn entry(&mut self, device_id: &str) -> &mut DeviceStatus {
+        self.devices
+            .entry(device_id.to_string())
+            .or_insert_with(|| DeviceStatus {
+                device_id: device_id.to_string(),
+                ..Default::default()
+            })
+    }
+}
+
+pub fn connected(shared: &Shared, device_id: &str, transport: TransportKind) {
+    update(shared, device_id, |status| {
+        status.state = ConnectionState::Connected;
+        status.transport = Some(transport);
+        status.last_seen = Some(now());
+        status.last_error = None;
+    });
+}
+
+pub fn disconnected(shared: &Shared, device_id: &str, error: Option<String>) {
+    update(shared, device_id, |status| {
+        status.state = ConnectionState::Disconnected;
+        status.transport = None;
+        status.rtt_ms = None;
+        if error.is_some() {
+            status.last_error = error;
+        }
+    });
+}
+
+/// An attempt to connect to a device failed.
+pub fn failed(shared: &Shared, device_id: &str, error: String) {
+    update(shared, device_id, |status| status.last_error = Some(error));
+}
+
+/// Count traffic on a link, which also tells when the device was last seen, emitting `device-status` for it at most
+/// once every `TRAFFIC_EVENT_INTERVAL`. Heartbeats keep traffic going, so the last count goes out soon after too.
+pub fn traffic(shared: &Shared, device_id: &str, sent: u64, received: u64) {
+    let status = match lock(&shared.presence) {
+        Ok(mut presence) => presence.count(device_id, sent, received),
+        Err(e) => {
+            log::error!("Couldn't access device status: {:#}", e);
+            return;
+        }
+    };
+    if let Some(status) = status {
+        emit(shared, status);
+    }
+}
+
+pub fn round_trip(shared: &Shared, device_id: &str, rtt: Duration) {
+    update(shared, device_id, |status| {
+        status.rtt_ms = Some(rtt.as_millis() as u64)
+    });
+}
+
+/// Change a device's status, emitting `device-status` if that made a difference.
+fn update<F>(shared: &Shared, device_id: &str, change: F)
+where
+    F: FnOnce(&mut DeviceStatus),
+{
+    let status = match lock(&shared.presence) {
+        Ok(mut presence) => {
+            let status = presence.entry(device_id);
+            let before = status.clone();
+            change(status);
+            if *status == before {
+                return;
+            }
+            let status = status.clone();
+            presence
+                .emitted
+                .insert(device_id.to_string(), Instant::now());
+            status
+        }
+        Err(e) => {
+            log::error!("Couldn't access device status: {:#}", e);
+            return;
+        }
+    };
+    emit(shared, status);
+}
+
+fn emit(shared: &Shared, status: DeviceStatus) {
+    if let Err(e) = shared.app.emit("device-status", status) {
+        log::error!("Could not emit device-status event: {}", e);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traffic_events_are_throttled() {
+        let mut presence = Presence::default();
+        let status = presence.count("a", 10, 0).expect("first traffic is sent");
+        assert_eq!(status.bytes_sent, 10);
+        assert_eq!(status.last_seen, None);
+
+        assert!(presence.count("a", 0, 20).is_none());
+        assert!(presence.count("a", 0, 0).is_none());
+        // other devices are throttled separately
+        assert!(presence.count("b", 0, 5).is_some());
+
+        let status = presence.get("a");
+        assert_eq!((status.bytes_sent, status.bytes_received), (10, 20));
+        assert!(status.last_seen.is_some());
+
+        presence
+            .emitted
+            .insert("a".into(), Instant::now() - TRAFFIC_EVENT_INTERVAL);
+        let status = presence
+            .count("a", 1, 0)
+            .expect("sent again after the interval");
+        assert_eq!((status.bytes_sent, status.bytes_received), (11, 20));
+    }
+
+    #[test]
+    fn idle_links_send_nothing() {
+        let mut presence = Presence::default();
+        assert!(presence.count("a", 0, 0).is_none());
+        assert_eq!(presence.get("a").last_seen, None);
+    }
+}
@@ -0,0 +1,1296 @@
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tauri::image::Image;
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::boards::{Board, BoardInfo};
+use super::identity::fingerprint;
+use super::revocation::Revocation;
+use crate::contents::{Contents, ItemMeta, Source};
+use crate::hlc::Timestamp;
+use crate::limits::{self, Rejected};
+use crate::pinned::{self, Dot, PinEntry, PinState, Position};
+
+pub const PROTOCOL_VERSION: u16 = 3;
+
+/// Upper bound on a single frame, so a bad length prefix can't make us allocate gigabytes. Leaves room for an image
+/// at the largest size the limits allow, with its meta.
+pub const MAX_FRAME_LEN: usize = limits::MAX_DECODED_BYTES as usize + 1024 * 1024;
+
+const MSG_HELLO: u8 = 0x01;
+const MSG_ITEM: u8 = 0x02;
+const MSG_PAIR_COMMIT: u8 = 0x03;
+const MSG_PAIR_NONCE: u8 = 0x04;
+const MSG_PAIR_RESULT: u8 = 0x05;
+const MSG_MANIFEST_REQUEST: u8 = 0x06;
+const MSG_MANIFEST: u8 = 0x07;
+const MSG_CHUNK_REQUEST: u8 = 0x08;
+const MSG_CHUNK: u8 = 0x09;
+const MSG_TRANSFER_FAILED: u8 = 0x0a;
+const MSG_PUSH: u8 = 0x0b;
+const MSG_PING: u8 = 0x0c;
+const MSG_PONG: u8 = 0x0d;
+const MSG_PINS: u8 = 0x0e;
+const MSG_PIN_REQUEST: u8 = 0x0f;
+const MSG_PINNED_ITEM: u8 = 0x10;
+const MSG_BOARD_CATALOG: u8 = 0x11;
+const MSG_BOARD_SUBSCRIBE: u8 = 0x12;
+const MSG_BOARD_UNSUBSCRIBE: u8 = 0x13;
+const MSG_BOARD: u8 = 0x14;
+const MSG_BOARD_REVOKED: u8 = 0x15;
+const MSG_REVOCATION: u8 = 0x16;
+const MSG_REJECTED: u8 = 0x17;
+
+const ITEM_TEXT: u8 = 0x00;
+const ITEM_IMAGE: u8 = 0x01;
+const ITEM_PATHS: u8 = 0x02;
+
+/// Bit set of the item kinds a device is able to send and receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const TEXT: Self = Self(1 << 0);
+    pub const IMAGE: Self = Self(1 << 1);
+    pub const PATHS: Self = Self(1 << 2);
+    pub const ALL: Self = Self(Self::TEXT.0 | Self::IMAGE.0 | Self::PATHS.0);
+
+    pub fn intersect(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn supports(self, item: &Contents) -> bool {
+        let needed = match item {
+            Contents::Text { .. } => Self::TEXT,
+            Contents::Image { .. } => Self::IMAGE,
+            Contents::FilePath { .. } => Self::PATHS,
+        };
+        self.0 & needed.0 != 0
+    }
+}
+
+/// Why a connection was opened. Only paired devices may open a `Sync` connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intent {
+    Sync,
+    Pair,
+}
+
+/// First message sent by both sides of a connection.
+#[derive(Debug, Clone)]
+pub struct Hello {
+    pub version: u16,
+    pub intent: Intent,
+    pub device_id: String,
+    pub public_key: Vec<u8>,
+    pub name: String,
+    pub os: String,
+    pub capabilities: Capabilities,
+    /// Public half of the key the device signs revocations with.
+    pub signing_key: Vec<u8>,
+}
+
+/// One file or directory of a paths item, relative to the directory holding the item's paths and always `/` separated.
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub path: String,
+    pub dir: bool,
+    pub size: u64,
+    /// sha256 of the file contents, zeroed for directories.
+    pub hash: [u8; 32],
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Hello(Hello),
+    Item(Arc<Contents>),
+    PairCommit(Vec<u8>),
+    PairNonce(Vec<u8>),
+    PairResult(bool),
+    /// Ask for the list of files behind a paths item we were sent.
+    ManifestRequest(Uuid),
+    Manifest(Uuid, Vec<FileEntry>),
+    /// Ask for the next chunk of the file at `index` in the manifest, starting at `offset`.
+    ChunkRequest {
+        uid: Uuid,
+        index: u32,
+        offset: u64,
+    },
+    Chunk {
+        uid: Uuid,
+        index: u32,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    TransferFailed(Uuid, String),
+    /// An item the user chose to send to this device in particular, taken in even if it was seen before.
+    Push(Arc<Contents>),
+    /// Heartbeat, answered with a `Pong` carrying the same value.
+    Ping(u64),
+    Pong(u64),
+    /// The sender's replica of the pinned list.
+    Pins(PinState),
+    /// Ask for the contents of pinned items by pin id.
+    PinRequest(Vec<Uuid>),
+    /// The contents of a pinned item, which doesn't go into history like a shared item does.
+    PinnedItem(Arc<Contents>),
+    /// The boards the sender publishes that we may subscribe to.
+    BoardCatalog(Vec<BoardInfo>),
+    BoardSubscribe(Uuid),
+    BoardUnsubscribe(Uuid),
+    /// The current version of a board we subscribe to.
+    Board(Board),
+    /// The publisher deleted the board or revoked our subscription.
+    BoardRevoked(Uuid),
+    /// A device was revoked and must be forgotten, passed on from device to device.
+    Revocation(Revocation),
+    /// Something we sent was turned away, and why.
+    Rejected(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("Unknown message type {0:#04x}")]
+    UnknownMessage(u8),
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(u16),
+    #[error("Frame of {0} bytes exceeds the maximum frame size")]
+    FrameTooLarge(usize),
+    #[error("Message ended unexpectedly")]
+    Truncated,
+    #[error("Malformed message: {0}")]
+    Malformed(String),
+    #[error("Secure handshake failed: {0}")]
+    Handshake(String),
+    #[error("Unexpected message: {0}")]
+    Unexpected(&'static str),
+    /// A well-formed message with an item over the limits, which is skipped like an unknown one.
+    #[error("Item rejected: {0}")]
+    Rejected(#[from] Rejected),
+    #[error("Connection error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl Message {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Hello(_) => "hello",
+            Message::Item(_) => "item",
+            Message::PairCommit(_) => "pair commit",
+            Message::PairNonce(_) => "pair nonce",
+            Message::PairResult(_) => "pair result",
+            Message::ManifestRequest(_) => "manifest request",
+            Message::Manifest(..) => "manifest",
+            Message::ChunkRequest { .. } => "chunk request",
+            Message::Chunk { .. } => "chunk",
+            Message::TransferFailed(..) => "transfer failure",
+            Message::Push(_) => "push",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Pins(_) => "pinned list",
+            Message::PinRequest(_) => "pinned item request",
+            Message::PinnedItem(_) => "pinned item",
+            Message::BoardCatalog(_) => "board catalog",
+            Message::BoardSubscribe(_) => "board subscription",
+            Message::BoardUnsubscribe(_) => "board unsubscription",
+            Message::Board(_) => "board",
+            Message::BoardRevoked(_) => "board revocation",
+            Message::Revocation(_) => "device revocation",
+            Message::Rejected(_) => "rejection",
+        }
+    }
+}
+
+/// Write a length-prefixed frame: `len: u32 BE | type: u8 | payload`, where `len` covers the type byte and payload.
+pub fn write_message<W: Write>(w: &mut W, msg: &Message) -> Result<(), ProtocolError> {
+    let body = encode(msg);
+    if body.len() > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge(body.len()));
+    }
+    w.write_all(&(body.len() as u32).to_be_bytes())?;
+    w.write_all(&body)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Check that an item of ours can be sent as a message: the other device would turn it away if it were over the
+/// limits, and it can't be written at all if it doesn't fit in a frame.
+pub fn check_item(item: &Contents) -> Result<(), ProtocolError> {
+    limits::check_item(item)?;
+    let len = item_frame_len(item);
+    match len > MAX_FRAME_LEN {
+        true => Err(ProtocolError::FrameTooLarge(len)),
+        false => Ok(()),
+    }
+}
+
+/// Length of the frame body of an item message, without encoding it: the message type, then what `put_item`
+/// writes.
+fn item_frame_len(item: &Contents) -> usize {
+    let meta = item.meta();
+    1 + 16
+        + 4
+        + meta.origin.as_deref().unwrap_or_default().len()
+        + 8
+        + 4
+        + 1
+        + match item {
+            Contents::Text { text, .. } => 4 + text.len(),
+            Contents::Image { image, .. } => 4 + 4 + 4 + image.rgba().len(),
+            Contents::FilePath { paths, .. } => {
+                4 + paths
+                    .iter()
+                    .map(|p| 4 + p.to_string_lossy().len())
+                    .sum::<usize>()
+            }
+        }
+}
+
+/// The start of a frame, read before the rest of it.
+pub struct FrameHeader {
+    kind: u8,
+    /// Length of the whole frame body, type included.
+    pub len: usize,
+}
+
+impl FrameHeader {
+    /// Whether the frame carries items from the other device, which are held to the rate limits.
+    pub fn carries_items(&self) -> bool {
+        matches!(self.kind, MSG_ITEM | MSG_PUSH | MSG_PINNED_ITEM | MSG_BOARD)
+    }
+}
+
+/// Read one frame. A frame with an unknown type or an item over the limits is consumed in full before the error is
+/// returned, so the caller can skip it and keep reading.
+pub fn read_message<R: Read>(r: &mut R) -> Result<Message, ProtocolError> {
+    read_admitted(r, |_| Ok(()))
+}
+
+/// Read one frame if `admit` lets it in by its header. A frame turned away is skipped without being held in memory
+/// or decoded.
+pub fn read_admitted<R: Read>(
+    r: &mut R,
+    admit: impl FnOnce(&FrameHeader) -> Result<(), Rejected>,
+) -> Result<Message, ProtocolError> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge(len));
+    }
+    if len == 0 {
+        return Err(ProtocolError::Malformed("empty frame".into()));
+    }
+    let mut kind = [0u8; 1];
+    r.read_exact(&mut kind)?;
+
+    if let Err(e) = admit(&FrameHeader { kind: kind[0], len }) {
+        let skipped = io::copy(&mut r.take(len as u64 - 1), &mut io::sink())?;
+        if skipped < len as u64 - 1 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        return Err(e.into());
+    }
+    let mut body = vec![0u8; len];
+    body[0] = kind[0];
+    r.read_exact(&mut body[1..])?;
+    decode(&body)
+}
+
+pub fn encode(msg: &Message) -> Vec<u8> {
+    let mut buf = Vec::new();
+    match msg {
+        Message::Hello(hello) => {
+            buf.push(MSG_HELLO);
+            put_u16(&mut buf, hello.version);
+            buf.push(match hello.intent {
+                Intent::Sync => 0,
+                Intent::Pair => 1,
+            });
+            put_str(&mut buf, &hello.device_id);
+            put_bytes(&mut buf, &hello.public_key);
+            put_str(&mut buf, &hello.name);
+            put_str(&mut buf, &hello.os);
+            put_u32(&mut buf, hello.capabilities.0);
+            put_bytes(&mut buf, &hello.signing_key);
+        }
+        Message::Item(item) => {
+            buf.push(MSG_ITEM);
+            put_item(&mut buf, item);
+        }
+        Message::PairCommit(commitment) => {
+            buf.push(MSG_PAIR_COMMIT);
+            put_bytes(&mut buf, commitment);
+        }
+        Message::PairNonce(nonce) => {
+            buf.push(MSG_PAIR_NONCE);
+            put_bytes(&mut buf, nonce);
+        }
+        Message::PairResult(accepted) => {
+            buf.push(MSG_PAIR_RESULT);
+            buf.push(*accepted as u8);
+        }
+        Message::ManifestRequest(uid) => {
+            buf.push(MSG_MANIFEST_REQUEST);
+            buf.extend_from_slice(uid.as_bytes());
+        }
+        Message::Manifest(uid, entries) => {
+            buf.push(MSG_MANIFEST);
+            buf.extend_from_slice(uid.as_bytes());
+            put_u32(&mut buf, entries.len() as u32);
+            for entry in entries {
+                put_str(&mut buf, &entry.path);
+                buf.push(entry.dir as u8);
+                put_u64(&mut buf, entry.size);
+                buf.extend_from_slice(&entry.hash);
+            }
+        }
+        Message::ChunkRequest { uid, index, offset } => {
+            buf.push(MSG_CHUNK_REQUEST);
+            buf.extend_from_slice(uid.as_bytes());
+            put_u32(&mut buf, *index);
+            put_u64(&mut buf, *offset);
+        }
+        Message::Chunk {
+            uid,
+            index,
+            offset,
+            data,
+        } => {
+            buf.push(MSG_CHUNK);
+            buf.extend_from_slice(uid.as_bytes());
+            put_u32(&mut buf, *index);
+            put_u64(&mut buf, *offset);
+            put_bytes(&mut buf, data);
+        }
+        Message::TransferFailed(uid, reason) => {
+            buf.push(MSG_TRANSFER_FAILED);
+            buf.extend_from_slice(uid.as_bytes());
+            put_str(&mut buf, reason);
+        }
+        Message::Push(item) => {
+            buf.push(MSG_PUSH);
+            put_item(&mut buf, item);
+        }
+        Message::Ping(value) => {
+            buf.push(MSG_PING);
+            put_u64(&mut buf, *value);
+        }
+        Message::Pong(value) => {
+            buf.push(MSG_PONG);
+            put_u64(&mut buf, *value);
+        }
+        Message::Pins(state) => {
+            buf.push(MSG_PINS);
+            put_u32(&mut buf, state.entries.len() as u32);
+            for (id, entry) in &state.entries {
+                buf.extend_from_slice(id.as_bytes());
+                put_u32(&mut buf, entry.tags.len() as u32);
+                for tag in &entry.tags {
+                    put_dot(&mut buf, tag);
+                }
+                put_str(&mut buf, &entry.position.key);
+                put_dot(&mut buf, &entry.position.stamp);
+            }
+            put_u32(&mut buf, state.removed.len() as u32);
+            for tag in &state.removed {
+                put_dot(&mut buf, tag);
+            }
+        }
+        Message::PinRequest(ids) => {
+            buf.push(MSG_PIN_REQUEST);
+            put_u32(&mut buf, ids.len() as u32);
+            for id in ids {
+                buf.extend_from_slice(id.as_bytes());
+            }
+        }
+        Message::PinnedItem(item) => {
+            buf.push(MSG_PINNED_ITEM);
+            put_item(&mut buf, item);
+        }
+        Message::BoardCatalog(boards) => {
+            buf.push(MSG_BOARD_CATALOG);
+            put_u32(&mut buf, boards.len() as u32);
+            for board in boards {
+                buf.extend_from_slice(board.id.as_bytes());
+                put_str(&mut buf, &board.name);
+                put_u64(&mut buf, board.version);
+                put_u32(&mut buf, board.items);
+            }
+        }
+        Message::BoardSubscribe(id) => {
+            buf.push(MSG_BOARD_SUBSCRIBE);
+            buf.extend_from_slice(id.as_bytes());
+        }
+        Message::BoardUnsubscribe(id) => {
+            buf.push(MSG_BOARD_UNSUBSCRIBE);
+            buf.extend_from_slice(id.as_bytes());
+        }
+        Message::Board(board) => {
+            buf.push(MSG_BOARD);
+            buf.extend_from_slice(board.id.as_bytes());
+            put_str(&mut buf, &board.name);
+            put_u64(&mut buf, board.version);
+            put_u32(&mut buf, board.items.len() as u32);
+            for item in &board.items {
+                put_item(&mut buf, item);
+            }
+        }
+        Message::BoardRevoked(id) => {
+            buf.push(MSG_BOARD_REVOKED);
+            buf.extend_from_slice(id.as_bytes());
+        }
+        Message::Revocation(revocation) => {
+            buf.push(MSG_REVOCATION);
+            put_str(&mut buf, &revocation.device_id);
+            put_bytes(&mut buf, &revocation.public_key);
+            put_str(&mut buf, &revocation.issuer);
+            put_u64(&mut buf, revocation.issued_at);
+            buf.push(revocation.wipe as u8);
+            put_bytes(&mut buf, &revocation.signature);
+        }
+        Message::Rejected(reason) => {
+            buf.push(MSG_REJECTED);
+            put_str(&mut buf, reason);
+        }
+    }
+    buf
+}
+
+pub fn decode(body: &[u8]) -> Result<Message, ProtocolError> {
+    let mut r = Reader { buf: body };
+    let msg = match r.u8()? {
+        MSG_HELLO => Message::Hello(Hello {
+            version: r.u16()?,
+            intent: match r.u8()? {
+                0 => Intent::Sync,
+                1 => Intent::Pair,
+                other => {
+                    return Err(ProtocolError::Malformed(format!(
+                        "unknown intent {}",
+                        other
+                    )))
+                }
+            },
+            device_id: r.string()?,
+            public_key: r.bytes()?.to_vec(),
+            name: r.string()?,
+            os: r.string()?,
+            capabilities: Capabilities(r.u32()?),
+            signing_key: r.bytes()?.to_vec(),
+        }),
+        MSG_ITEM => Message::Item(Arc::new(r.item()?)),
+        MSG_PAIR_COMMIT => Message::PairCommit(r.bytes()?.to_vec()),
+        MSG_PAIR_NONCE => Message::PairNonce(r.bytes()?.to_vec()),
+        MSG_PAIR_RESULT => Message::PairResult(r.u8()? != 0),
+        MSG_MANIFEST_REQUEST => Message::ManifestRequest(r.uid()?),
+        MSG_MANIFEST => {
+            let uid = r.uid()?;
+            let count = r.u32()?;
+            let entries = (0..count)
+                .map(|_| {
+                    Ok(FileEntry {
+                        path: r.string()?,
+                        dir: r.u8()? != 0,
+                        size: r.u64()?,
+                        hash: r.take(32)?.try_into().unwrap(),
+                    })
+                })
+                .collect::<Result<Vec<_>, ProtocolError>>()?;
+            Message::Manifest(uid, entries)
+        }
+        MSG_CHUNK_REQUEST => Message::ChunkRequest {
+            uid: r.uid()?,
+            index: r.u32()?,
+            offset: r.u64()?,
+        },
+        MSG_CHUNK => Message::Chunk {
+            uid: r.uid()?,
+            index: r.u32()?,
+            offset: r.u64()?,
+            data: r.bytes()?.to_vec(),
+        },
+        MSG_TRANSFER_FAILED => Message::TransferFailed(r.uid()?, r.string()?),
+        MSG_PUSH => Message::Push(Arc::new(r.item()?)),
+        MSG_PING => Message::Ping(r.u64()?),
+        MSG_PONG => Message::Pong(r.u64()?),
+        MSG_PINS => {
+            let mut state = PinState::default();
+            for _ in 0..r.u32()? {
+                let id = r.uid()?;
+                let tags = (0..r.u32()?).map(|_| r.dot()).collect::<Result<_, _>>()?;
+                let key = r.string()?;
+                if !pinned::valid_key(&key) {
+                    return Err(ProtocolError::Malformed(format!(
+                        "invalid pinned list sort key {:?}",
+                        key
+                    )));
+                }
+                let position = Position {
+                    key,
+                    stamp: r.dot()?,
+                };
+                state.entries.insert(id, PinEntry { tags, position });
+            }
+            for _ in 0..r.u32()? {
+                state.removed.insert(r.dot()?);
+            }
+            Message::Pins(state)
+        }
+        MSG_PIN_REQUEST => {
+            let count = r.u32()?;
+            Message::PinRequest((0..count).map(|_| r.uid()).collect::<Result<_, _>>()?)
+        }
+        MSG_PINNED_ITEM => Message::PinnedItem(Arc::new(r.item()?)),
+        MSG_BOARD_CATALOG => {
+            let count = r.u32()?;
+            let boards = (0..count)
+                .map(|_| {
+                    Ok(BoardInfo {
+                        id: r.uid()?,
+                        name: r.string()?,
+                        version: r.u64()?,
+                        items: r.u32()?,
+                    })
+                })
+                .collect::<Result<Vec<_>, ProtocolError>>()?;
+            Message::BoardCatalog(boards)
+        }
+        MSG_BOARD_SUBSCRIBE => Message::BoardSubscribe(r.uid()?),
+        MSG_BOARD_UNSUBSCRIBE => Message::BoardUnsubscribe(r.uid()?),
+        MSG_BOARD => {
+            let id = r.uid()?;
+            let name = r.string()?;
+            let version = r.u64()?;
+            let count = r.u32()?;
+            let items = (0..count)
+                .map(|_| r.item().map(Arc::new))
+                .collect::<Result<Vec<_>, _>>()?;
+            Message::Board(Board {
+                id,
+                name,
+                version,
+                items,
+            })
+        }
+        MSG_BOARD_REVOKED => Message::BoardRevoked(r.uid()?),
+        MSG_REVOCATION => Message::Revocation(Revocation {
+            device_id: r.string()?,
+            public_key: r.bytes()?.to_vec(),
+            issuer: r.string()?,
+            issued_at: r.u64()?,
+            wipe: r.u8()? != 0,
+            signature: r.bytes()?.to_vec(),
+        }),
+        MSG_REJECTED => Message::Rejected(r.string()?),
+        other => return Err(ProtocolError::UnknownMessage(other)),
+    };
+    r.finish()?;
+    Ok(msg)
+}
+
+/// Exchange hellos over a fresh stream, returning the remote hello and the capabilities both sides share.
+/// The remote device id must be the fingerprint of the key it presents.
+pub fn handshake<S: Read + Write>(
+    stream: &mut S,
+    local: &Hello,
+) -> Result<(Hello, Capabilities), ProtocolError> {
+    write_message(stream, &Message::Hello(local.clone()))?;
+    let remote = match read_message(stream)? {
+        Message::Hello(hello) => hello,
+        other => return Err(ProtocolError::Unexpected(other.name())),
+    };
+    if remote.version != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(remote.version));
+    }
+    if fingerprint(&remote.public_key) != remote.device_id {
+        return Err(ProtocolError::Malformed(
+            "device id does not match public key".into(),
+        ));
+    }
+    let capabilities = local.capabilities.intersect(remote.capabilities);
+    Ok((remote, capabilities))
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_bytes(buf, s.as_bytes());
+}
+
+fn put_dot(buf: &mut Vec<u8>, dot: &Dot) {
+    put_u64(buf, dot.counter);
+    buf.extend_from_slice(dot.replica.as_bytes());
+}
+
+fn put_item(buf: &mut Vec<u8>, item: &Contents) {
+    let meta = item.meta();
+    buf.extend_from_slice(meta.uid.as_bytes());
+    put_str(buf, meta.origin.as_deref().unwrap_or_default());
+    put_u64(buf, meta.time.wall);
+    put_u32(buf, meta.time.counter);
+    match item {
+        Contents::Text { text, .. } => {
+            buf.push(ITEM_TEXT);
+            put_str(buf, text);
+        }
+        Contents::Image { image, .. } => {
+            buf.push(ITEM_IMAGE);
+            put_u32(buf, image.width());
+            put_u32(buf, image.height());
+            put_bytes(buf, image.rgba());
+        }
+        Contents::FilePath { paths, .. } => {
+            buf.push(ITEM_PATHS);
+            put_u32(buf, paths.len() as u32);
+            for path in paths {
+                put_str(buf, &path.to_string_lossy());
+            }
+        }
+    }
+}
+
+fn utf8(bytes: &[u8]) -> Result<String, ProtocolError> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| ProtocolError::Malformed("string is not valid utf-8".into()))
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.buf.len() < n {
+            return Err(ProtocolError::Truncated);
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, ProtocolError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn uid(&mut self) -> Result<Uuid, ProtocolError> {
+        Ok(Uuid::from_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    fn dot(&mut self) -> Result<Dot, ProtocolError> {
+        Ok(Dot {
+            counter: self.u64()?,
+            replica: self.uid()?,
+        })
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], ProtocolError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, ProtocolError> {
+        let bytes = self.bytes()?;
+        utf8(bytes)
+    }
+
+    fn item(&mut self) -> Result<Contents, ProtocolError> {
+        let uid = self.uid()?;
+        let origin = self.string()?;
+        let time = Timestamp {
+            wall: self.u64()?,
+            counter: self.u32()?,
+        };
+        let meta = ItemMeta {
+            uid,
+            origin: (!origin.is_empty()).then_some(origin),
+            time,
+            // filled in once we know which device it came from
+            source: Source::Local,
+        };
+        let item = match self.u8()? {
+            ITEM_TEXT => {
+                let text = self.bytes()?;
+                limits::check_text(text.len())?;
+                Ok(Contents::from_text(utf8(text)?))
+            }
+            ITEM_IMAGE => {
+                let width = self.u32()?;
+                let height = self.u32()?;
+                limits::check_image(width, height)?;
+                let rgba = self.bytes()?;
+                if rgba.len() as u64 != width as u64 * height as u64 * 4 {
+                    return Err(ProtocolError::Malformed(format!(
+                        "image data is {} bytes, expected {}x{} rgba",
+                        rgba.len(),
+                        width,
+                        height
+                    )));
+                }
+                Contents::from_image(Image::new_owned(rgba.to_vec(), width, height))
+                    .map_err(|e| ProtocolError::Malformed(format!("{:#}", e)))
+            }
+            ITEM_PATHS => {
+                let count = self.u32()?;
+                limits::check_paths(count as usize)?;
+                let paths = (0..count)
+                    .map(|_| self.string().map(PathBuf::from))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Contents::from_paths(paths))
+            }
+            other => Err(ProtocolError::Malformed(format!(
+                "unknown item kind {:#04x}",
+                other
+            ))),
+        }?;
+        Ok(item.with_meta(meta))
+    }
+
+    fn finish(self) -> Result<(), ProtocolError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(ProtocolError::Malformed(format!(
+                "{} trailing bytes",
+                self.buf.len()
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::RateLimits;
+    use rand::Rng;
+    use std::io::Cursor;
+
+    fn frame(msg: &Message) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_message(&mut buf, msg).unwrap();
+        buf
+    }
+
+    fn text(text: &str) -> Message {
+        Message::Item(Arc::new(Contents::from_text(text.into())))
+    }
+
+    fn image(width: u32, height: u32) -> Message {
+        let rgba = vec![0x80; width as usize * height as usize * 4];
+        let image = Contents::from_image(Image::new_owned(rgba, width, height)).unwrap();
+        Message::Item(Arc::new(image))
+    }
+
+    /// An image item declaring its size, followed by `data` and nothing else.
+    fn declared_image(width: u32, height: u32, data: &[u8]) -> Vec<u8> {
+        let mut body = vec![MSG_ITEM];
+        body.extend_from_slice(Uuid::new_v4().as_bytes());
+        put_str(&mut body, "");
+        put_u64(&mut body, 0);
+        put_u32(&mut body, 0);
+        body.push(ITEM_IMAGE);
+        put_u32(&mut body, width);
+        put_u32(&mut body, height);
+        put_u32(
+            &mut body,
+            (width as u64 * height as u64).wrapping_mul(4) as u32,
+        );
+        body.extend_from_slice(data);
+        body
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut buf = (MAX_FRAME_LEN as u32 + 1).to_be_bytes().to_vec();
+        buf.push(MSG_ITEM);
+        let e = read_message(&mut Cursor::new(buf)).unwrap_err();
+        assert!(matches!(e, ProtocolError::FrameTooLarge(_)), "{e}");
+
+        let e = read_message(&mut Cursor::new(u32::MAX.to_be_bytes())).unwrap_err();
+        assert!(matches!(e, ProtocolError::FrameTooLarge(_)), "{e}");
+
+        let e = read_message(&mut Cursor::new(0u32.to_be_bytes())).unwrap_err();
+        assert!(matches!(e, ProtocolError::Malformed(_)), "{e}");
+    }
+
+    #[test]
+    fn truncated_frames() {
+        for msg in [text("hello"), image(3, 2)] {
+            let buf = frame(&msg);
+            for len in 0..buf.len() {
+                let e = read_message(&mut Cursor::new(&buf[..len])).unwrap_err();
+                assert!(
+                    matches!(&e, ProtocolError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof),
+                    "{len} bytes: {e}"
+                );
+            }
+            // a length prefix that claims less than the message needs
+            let body = &buf[4..];
+            for len in 1..body.len() {
+                let e = decode(&body[..len]).unwrap_err();
+                assert!(matches!(e, ProtocolError::Truncated), "{len} bytes: {e}");
+            }
+        }
+    }
+
+    #[test]
+    fn huge_declared_dimensions() {
+        // rejected before the pixels, which aren't there, are looked for
+        for (width, height) in [
+            (u32::MAX, u32::MAX),
+            (65536, 65536),
+            (1, limits::MAX_PIXELS as u32 + 1),
+        ] {
+            let e = decode(&declared_image(width, height, &[])).unwrap_err();
+            assert!(
+                matches!(e, ProtocolError::Rejected(Rejected::Pixels(..))),
+                "{width}x{height}: {e}"
+            );
+        }
+        // within the limits, but not what it says it is
+        let e = decode(&declared_image(2, 2, &[0; 15])).unwrap_err();
+        assert!(matches!(e, ProtocolError::Truncated), "{e}");
+    }
+
+    #[test]
+    fn huge_declared_text() {
+        let mut body = vec![MSG_ITEM];
+        body.extend_from_slice(Uuid::new_v4().as_bytes());
+        put_str(&mut body, "");
+        put_u64(&mut body, 0);
+        put_u32(&mut body, 0);
+        body.push(ITEM_TEXT);
+        put_bytes(&mut body, &vec![b'a'; limits::MAX_TEXT_LEN + 1]);
+        let e = decode(&body).unwrap_err();
+        assert!(
+            matches!(e, ProtocolError::Rejected(Rejected::Text(_))),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn rejected_frames_are_skipped() {
+        let mut buf = frame(&text("too much"));
+        buf.extend(frame(&Message::Ping(7)));
+        let mut r = Cursor::new(buf);
+
+        let e = read_admitted(&mut r, |_| Err(Rejected::Items)).unwrap_err();
+        assert!(matches!(e, ProtocolError::Rejected(Rejected::Items)), "{e}");
+        assert!(matches!(read_message(&mut r), Ok(Message::Ping(7))));
+
+        // a rejected frame that ends early is a broken connection, not a skipped frame
+        let buf = frame(&text("cut short"));
+        let mut r = Cursor::new(&buf[..buf.len() - 1]);
+        let e = read_admitted(&mut r, |_| Err(Rejected::Items)).unwrap_err();
+        assert!(matches!(e, ProtocolError::Io(_)), "{e}");
+    }
+
+    #[test]
+    fn burst_over_the_rate_limit() {
+        let mut rates = RateLimits::default();
+        let mut buf = Vec::new();
+        for i in 0..40 {
+            buf.extend(frame(&text(&i.to_string())));
+            buf.extend(frame(&Message::Ping(i)));
+        }
+        let mut r = Cursor::new(buf);
+        let mut admitted = 0;
+        let mut rejected = 0;
+        for _ in 0..80 {
+            let result = read_admitted(&mut r, |header| match header.carries_items() {
+                true => rates.take("device", header.len),
+                false => Ok(()),
+            });
+            match result {
+                Ok(Message::Item(_)) => admitted += 1,
+                Ok(Message::Ping(_)) => {}
+                Err(ProtocolError::Rejected(Rejected::Items)) => rejected += 1,
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+        assert_eq!(admitted, limits::MAX_ITEMS_BURST as usize);
+        assert_eq!(rejected, 40 - admitted);
+        assert_eq!(r.position() as usize, r.get_ref().len());
+    }
+
+    /// Random bytes and damaged frames must come back as errors, never a panic or a huge allocation.
+    #[test]
+    fn fuzz() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..10_000 {
+            let len = rng.gen_range(0..64);
+            let mut body: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            if let Some(kind) = body.first_mut() {
+                *kind %= MSG_REJECTED + 2;
+            }
+            let _ = decode(&body);
+            let mut buf = (len as u32).to_be_bytes().to_vec();
+            buf.extend(&body);
+            let _ = read_message(&mut Cursor::new(buf));
+        }
+
+        let frames = [
+            frame(&text("hello")),
+            frame(&image(3, 2)),
+            frame(&Message::Item(Arc::new(Contents::from_paths(vec![
+                "/tmp/a".into(),
+                "/tmp/b".into(),
+            ])))),
+        ];
+        for buf in &frames {
+            for _ in 0..2_000 {
+                let mut buf = buf.clone();
+                for _ in 0..rng.gen_range(1..4) {
+                    let i = rng.gen_range(0..buf.len());
+                    buf[i] = rng.gen();
+                }
+                let _ = read_message(&mut Cursor::new(buf));
+            }
+        }
+    }
+
+    fn round_trip(msg: Message) -> Message {
+        let buf = frame(&msg);
+        let decoded = read_message(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(decoded.name(), msg.name());
+        assert_eq!(
+            encode(&decoded),
+            encode(&msg),
+            "{} changed on the way",
+            msg.name()
+        );
+        decoded
+    }
+
+    fn hello(version: u16) -> Hello {
+        let public_key = vec![7; 32];
+        Hello {
+            version,
+            intent: Intent::Sync,
+            device_id: fingerprint(&public_key),
+            public_key,
+            name: "laptop".into(),
+            os: "linux".into(),
+            capabilities: Capabilities::ALL,
+            signing_key: vec![9; 32],
+        }
+    }
+
+    fn dot(counter: u64) -> Dot {
+        Dot {
+            counter,
+            replica: Uuid::from_bytes([3; 16]),
+        }
+    }
+
+    fn items() -> Vec<Arc<Contents>> {
+        let text = Contents::from_text("héllo".into());
+        let mut meta = text.meta().clone();
+        meta.origin = Some("origin-device".into());
+        meta.time = Timestamp {
+            wall: 1_700_000_000_000,
+            counter: 3,
+        };
+        vec![
+            Arc::new(text.with_meta(meta)),
+            Arc::new(Contents::from_text(String::new())),
+            Arc::new(Contents::from_image(Image::new_owned(vec![0x40; 5 * 3 * 4], 5, 3)).unwrap()),
+            Arc::new(Contents::from_paths(vec![
+                "/home/me/a.txt".into(),
+                "/home/me/dir".into(),
+            ])),
+            Arc::new(Contents::from_paths(vec![])),
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        let uid = Uuid::new_v4();
+        let pins = PinState {
+            entries: [(
+                uid,
+                PinEntry {
+                    tags: [dot(1), dot(4)].into(),
+                    position: Position {
+                        key: "V".into(),
+                        stamp: dot(4),
+                    },
+                },
+            )]
+            .into(),
+            removed: [dot(2)].into(),
+        };
+        let mut messages = vec![
+            Message::Hello(hello(PROTOCOL_VERSION)),
+            Message::Hello(Hello {
+                intent: Intent::Pair,
+                ..hello(PROTOCOL_VERSION)
+            }),
+            Message::PairCommit(vec![1; 32]),
+            Message::PairNonce(vec![2; 16]),
+            Message::PairResult(true),
+            Message::PairResult(false),
+            Message::ManifestRequest(uid),
+            Message::Manifest(
+                uid,
+                vec![
+                    FileEntry {
+                        path: "dir".into(),
+                        dir: true,
+                        size: 0,
+                        hash: [0; 32],
+                    },
+                    FileEntry {
+                        path: "dir/a.txt".into(),
+                        dir: false,
+                        size: 1 << 40,
+                        hash: [5; 32],
+                    },
+                ],
+            ),
+            Message::ChunkRequest {
+                uid,
+                index: 1,
+                offset: 4096,
+            },
+            Message::Chunk {
+                uid,
+                index: 1,
+                offset: 4096,
+                data: vec![0xaa; 100],
+            },
+            Message::TransferFailed(uid, "no such file".into()),
+            Message::Ping(u64::MAX),
+            Message::Pong(0),
+            Message::Pins(pins),
+            Message::Pins(PinState::default()),
+            Message::PinRequest(vec![uid, Uuid::new_v4()]),
+            Message::BoardCatalog(vec![BoardInfo {
+                id: uid,
+                name: "Snippets".into(),
+                version: 3,
+                items: 2,
+            }]),
+            Message::BoardSubscribe(uid),
+            Message::BoardUnsubscribe(uid),
+            Message::Board(Board {
+                id: uid,
+                name: "Snippets".into(),
+                version: 3,
+                items: items(),
+            }),
+            Message::BoardRevoked(uid),
+            Message::Revocation(Revocation {
+                device_id: "device".into(),
+                public_key: vec![4; 32],
+                issuer: "issuer".into(),
+                issued_at: 1_700_000_000,
+                wipe: true,
+                signature: vec![6; 64],
+            }),
+            Message::Rejected("too big".into()),
+        ];
+        for item in items() {
+            messages.push(Message::Item(Arc::clone(&item)));
+            messages.push(Message::Push(Arc::clone(&item)));
+            messages.push(Message::PinnedItem(item));
+        }
+        for msg in messages {
+            round_trip(msg);
+        }
+    }
+
+    #[test]
+    fn items_keep_their_contents_and_meta() {
+        for item in items() {
+            let Message::Item(decoded) = round_trip(Message::Item(Arc::clone(&item))) else {
+                panic!("not an item");
+            };
+            assert_eq!(decoded, item);
+            assert_eq!(decoded.meta().uid, item.meta().uid);
+            assert_eq!(decoded.meta().origin, item.meta().origin);
+            assert_eq!(decoded.meta().time, item.meta().time);
+        }
+    }
+
+    #[test]
+    fn item_frame_len_is_the_encoded_len() {
+        for item in items() {
+            check_item(&item).unwrap();
+            assert_eq!(item_frame_len(&item), encode(&Message::Item(item)).len());
+        }
+    }
+
+    #[test]
+    fn largest_image_fits_in_a_frame() {
+        let side = (limits::MAX_PIXELS as f64).sqrt() as u32;
+        let Message::Item(item) = image(side, side) else {
+            unreachable!()
+        };
+        check_item(&item).unwrap();
+        let msg = Message::Push(Arc::clone(&item));
+        let Message::Push(decoded) = read_message(&mut Cursor::new(frame(&msg))).unwrap() else {
+            panic!("not a push");
+        };
+        assert_eq!(decoded, item);
+
+        let Message::Item(item) = image(side + 1, side) else {
+            unreachable!()
+        };
+        let e = check_item(&item).unwrap_err();
+        assert!(matches!(e, ProtocolError::Rejected(_)), "{e}");
+    }
+
+    #[test]
+    fn unknown_message_type() {
+        let mut buf = Vec::new();
+        for kind in [0x00, MSG_REJECTED + 1, 0xff] {
+            buf.extend(3u32.to_be_bytes());
+            buf.extend([kind, 1, 2]);
+        }
+        buf.extend(frame(&Message::Ping(1)));
+        let mut r = Cursor::new(buf);
+        for kind in [0x00, MSG_REJECTED + 1, 0xff] {
+            let e = read_message(&mut r).unwrap_err();
+            assert!(
+                matches!(e, ProtocolError::UnknownMessage(k) if k == kind),
+                "{e}"
+            );
+        }
+        // the unknown frames were read in full, so the next one is found
+        assert!(matches!(read_message(&mut r), Ok(Message::Ping(1))));
+    }
+
+    #[test]
+    fn frame_over_the_maximum_is_not_written() {
+        let msg = Message::Chunk {
+            uid: Uuid::new_v4(),
+            index: 0,
+            offset: 0,
+            data: vec![0; MAX_FRAME_LEN],
+        };
+        let mut buf = Vec::new();
+        let e = write_message(&mut buf, &msg).unwrap_err();
+        assert!(matches!(e, ProtocolError::FrameTooLarge(_)), "{e}");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn every_truncated_payload_is_an_error() {
+        let msg = Message::Manifest(
+            Uuid::new_v4(),
+            vec![FileEntry {
+                path: "a".into(),
+                dir: false,
+                size: 1,
+                hash: [1; 32],
+            }],
+        );
+        for msg in [
+            msg,
+            Message::Hello(hello(PROTOCOL_VERSION)),
+            Message::Ping(1),
+        ] {
+            let body = encode(&msg);
+            for len in 0..body.len() {
+                let e = decode(&body[..len]).unwrap_err();
+                assert!(matches!(e, ProtocolError::Truncated), "{len} bytes: {e}");
+            }
+            let mut long = body.clone();
+            long.push(0);
+            let e = decode(&long).unwrap_err();
+            assert!(matches!(e, ProtocolError::Malformed(_)), "{e}");
+        }
+    }
+
+    /// The local side of a handshake: what the other device sends, and what we wrote to it.
+    struct Peer {
+        incoming: Cursor<Vec<u8>>,
+        outgoing: Vec<u8>,
+    }
+
+    impl Read for Peer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for Peer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.outgoing.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn handshake_with(remote: Hello) -> Result<(Hello, Capabilities), ProtocolError> {
+        let mut peer = Peer {
+            incoming: Cursor::new(frame(&Message::Hello(remote))),
+            outgoing: Vec::new(),
+        };
+        let local = Hello {
+            capabilities: Capabilities::TEXT,
+            ..hello(PROTOCOL_VERSION)
+        };
+        let result = handshake(&mut peer, &local);
+        assert!(matches!(
+            read_message(&mut Cursor::new(peer.outgoing)),
+            Ok(Message::Hello(_))
+        ));
+        result
+    }
+
+    #[test]
+    fn handshake_checks_version_and_identity() {
+        let (remote, capabilities) = handshake_with(hello(PROTOCOL_VERSION)).unwrap();
+        assert_eq!(remote.name, "laptop");
+        assert_eq!(capabilities, Capabilities::TEXT);
+
+        for version in [0, PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let e = handshake_with(hello(version)).unwrap_err();
+            assert!(
+                matches!(e, ProtocolError::UnsupportedVersion(v) if v == version),
+                "{e}"
+            );
+        }
+
+        let e = handshake_with(Hello {
+            device_id: "someone else".into(),
+            ..hello(PROTOCOL_VERSION)
+        })
+        .unwrap_err();
+        assert!(matches!(e, ProtocolError::Malformed(_)), "{e}");
+    }
+}
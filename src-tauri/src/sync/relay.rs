@@ -0,0 +1,119 @@
+use anyhow::{anyhow, Context};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::Manager;
+
+use super::{Shared, CONNECT_TIMEOUT, HANDSHAKE_TIMEOUT};
+use crate::settings::Settings;
+
+// must match the relay server in relay/src/main.rs
+const MAGIC: &[u8; 4] = b"PCR1";
+const OP_LISTEN: u8 = 1;
+const OP_CONNECT: u8 = 2;
+const OP_ACCEPT: u8 = 3;
+const STATUS_OK: u8 = 0;
+const STATUS_NOT_FOUND: u8 = 1;
+
+/// The relay sends a keepalive every 30 seconds, so a control connection this quiet is dead.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(90);
+/// How long to wait before reconnecting to the relay, or checking again whether one has been set.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The relay address from the settings, if one is set.
+pub fn address(shared: &Shared) -> Option<String> {
+    shared
+        .app
+        .state::<Mutex<Settings>>()
+        .lock()
+        .ok()
+        .and_then(|s| s.relay_address.clone())
+        .filter(|a| !a.trim().is_empty())
+}
+
+/// Open a session to a device through the relay. Once open it is used exactly like a direct connection,
+/// and the relay only ever sees the encrypted Noise traffic.
+pub fn connect(address: &str, device_id: &str) -> Result<TcpStream, anyhow::Error> {
+    let mut stream = request(address, OP_CONNECT, device_id)?;
+    match status(&mut stream)? {
+        STATUS_OK => Ok(stream),
+        STATUS_NOT_FOUND => Err(anyhow!("{} is not connected to the relay", device_id)),
+        _ => Err(anyhow!("{} did not answer through the relay", device_id)),
+    }
+}
+
+/// Stay connected to the relay while one is set, serving every session another device opens to us through it.
+pub fn listen(shared: Arc<Shared>) {
+    loop {
+        if let Some(address) = address(&shared) {
+            if let Err(e) = listen_once(&shared, &address) {
+                log::warn!("Connection to relay {} lost: {:#}", address, e);
+            }
+        }
+        std::thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+fn listen_once(shared: &Arc<Shared>, address: &str) -> Result<(), anyhow::Error> {
+    let mut control = request(address, OP_LISTEN, &shared.hello.device_id)?;
+    control.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+    log::info!("listening for devices on relay {}", address);
+
+    loop {
+        let mut len = [0u8; 1];
+        control.read_exact(&mut len)?;
+        if len[0] == 0 {
+            // keepalive, a good time to notice the relay setting changed
+            if self::address(shared).as_deref() != Some(address) {
+                log::info!("relay setting changed, leaving {}", address);
+                return Ok(());
+            }
+            continue;
+        }
+        let mut token = vec![0u8; len[0] as usize];
+        control.read_exact(&mut token)?;
+        let token = String::from_utf8(token).with_context(|| "relay sent a malformed token")?;
+
+        let shared = Arc::clone(shared);
+        let address = address.to_string();
+        std::thread::spawn(move || match accept(&address, &token) {
+            Ok(stream) => super::serve(Box::new(stream), shared),
+            Err(e) => log::warn!("Could not accept session through relay: {:#}", e),
+        });
+    }
+}
+
+fn accept(address: &str, token: &str) -> Result<TcpStream, anyhow::Error> {
+    let mut stream = request(address, OP_ACCEPT, token)?;
+    match status(&mut stream)? {
+        STATUS_OK => Ok(stream),
+        _ => Err(anyhow!("the session was gone before it could be accepted")),
+    }
+}
+
+fn request(address: &str, op: u8, value: &str) -> Result<TcpStream, anyhow::Error> {
+    let socket_address = address
+        .to_socket_addrs()
+        .with_context(|| format!("invalid relay address {}", address))?
+        .next()
+        .ok_or_else(|| anyhow!("relay address {} did not resolve", address))?;
+    let mut stream = TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT)
+        .with_context(|| format!("could not connect to relay {}", address))?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    let mut request = MAGIC.to_vec();
+    request.push(op);
+    request.push(value.len() as u8);
+    request.extend_from_slice(value.as_bytes());
+    stream.write_all(&request)?;
+    Ok(stream)
+}
+
+fn status(stream: &mut TcpStream) -> Result<u8, anyhow::Error> {
+    let mut status = [0u8; 1];
+    stream
+        .read_exact(&mut status)
+        .with_context(|| "relay closed the connection")?;
+    Ok(status[0])
+}
@@ -0,0 +1,428 @@
+use anyhow::{anyhow, Context};
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter};
+use tauri_plugin_store::StoreExt;
+
+use super::protocol::{self, Hello, Message};
+use super::secure::SecureStream;
+use super::{lock, with_manager, Shared, HANDSHAKE_TIMEOUT};
+
+const REVOCATIONS_STORE: &str = "revoked.json";
+
+/// A device cut off from every device that trusts it, usually because it was lost or stolen. Devices pass
+/// revocations on to each other, so one reaches devices the one that issued it never connects to again. With
+/// `wipe` set, the revoked device is also sent it the next time it connects, and clears what it holds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revocation {
+    pub device_id: String,
+    /// The key the device was paired with. A device paired anew after the revocation isn't affected by it.
+    pub public_key: Vec<u8>,
+    pub issuer: String,
+    /// Seconds since the epoch.
+    pub issued_at: u64,
+    pub wipe: bool,
+    /// Made with the issuer's signing key over everything above.
+    pub signature: Vec<u8>,
+}
+
+impl Revocation {
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buf = b"purple-clip revocation".to_vec();
+        for field in [
+            self.device_id.as_bytes(),
+            &self.public_key,
+            self.issuer.as_bytes(),
+        ] {
+            buf.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            buf.extend_from_slice(field);
+        }
+        buf.extend_from_slice(&self.issued_at.to_be_bytes());
+        buf.push(self.wipe as u8);
+        buf
+    }
+
+    fn verify(&self, signing_key: &[u8]) -> bool {
+        let Ok(key) = <[u8; 32]>::try_from(signing_key) else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(&self.signature) else {
+            return false;
+        };
+        VerifyingKey::from_bytes(&key)
+            .is_ok_and(|key| key.verify_strict(&self.signed_bytes(), &signature).is_ok())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Stored {
+    #[serde(flatten)]
+    revocation: Revocation,
+    /// We sent the wipe to the revoked device, so we don't again.
+    #[serde(default)]
+    wiped: bool,
+}
+
+#[derive(Clone, Serialize)]
+struct DeviceRevoked {
+    device_id: String,
+    issuer: String,
+}
+
+/// Revocations issued here or passed on to us, persisted in `revoked.json`, to hand on to devices that were
+/// offline and to the revoked devices themselves.
+pub struct Revocations {
+    app: AppHandle,
+    revocations: Vec<Stored>,
+}
+
+impl Revocations {
+    pub fn load(app: &AppHandle) -> Result<Self, anyhow::Error> {
+        let store = app
+            .store(REVOCATIONS_STORE)
+            .with_context(|| "failed to get or create revocations store")?;
+        let revocations = match store.get("revocations") {
+            Some(value) => serde_json::from_value::<Vec<Stored>>(value)
+                .with_context(|| "revocations store contents were malformed")?,
+            None => vec![],
+        };
+        store.close_resource();
+
+        Ok(Self {
+            app: app.clone(),
+            revocations,
+        })
+    }
+
+    pub fn list(&self) -> Vec<Revocation> {
+        self.revocations
+            .iter()
+            .map(|s| s.revocation.clone())
+            .collect()
+    }
+
+    fn get(&self, device_id: &str) -> Option<&Stored> {
+        self.revocations
+            .iter()
+            .find(|s| s.revocation.device_id == device_id)
+    }
+
+    /// Returns false if we already had this revocation, or a later one of the same device.
+    fn add(&mut self, revocation: Revocation) -> Result<bool, anyhow::Error> {
+        if self
+            .get(&revocation.device_id)
+            .is_some_and(|s| s.revocation.issued_at >= revocation.issued_at)
+        {
+            return Ok(false);
+        }
+        self.revocations
+            .retain(|s| s.revocation.device_id != revocation.device_id);
+        self.revocations.push(Stored {
+            revocation,
+            wiped: false,
+        });
+        self.save()?;
+        Ok(true)
+    }
+
+    fn wiped(&mut self, device_id: &str) -> Result<(), anyhow::Error> {
+        if let Some(stored) = self
+            .revocations
+            .iter_mut()
+            .find(|s| s.revocation.device_id == device_id)
+        {
+            stored.wiped = true;
+        }
+        self.save()
+    }
+
+    /// Drop the revocation of a device that was paired with again.
+    pub fn remove(&mut self, device_id: &str) -> Result<(), anyhow::Error> {
+        let before = self.revocations.len();
+        self.revocations
+            .retain(|s| s.revocation.device_id != device_id);
+        if self.revocations.len() == before {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), anyhow::Error> {
+        let store = self
+            .app
+            .store(REVOCATIONS_STORE)
+            .with_context(|| "failed to get revocations store")?;
+        store.set("revocations", serde_json::to_value(&self.revocations)?);
+        Ok(())
+    }
+}
+
+/// Revoke a trusted device here, and pass the revocation on to every connected device.
+pub fn issue(shared: &Shared, device_id: &str, wipe: bool) -> Result<(), anyhow::Error> {
+    let public_key = lock(&shared.trust)?
+        .get(device_id)
+        .map(|d| d.public_key.clone())
+        .ok_or_else(|| anyhow!("{} is not a trusted device", device_id))?;
+    let issued_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut revocation = Revocation {
+        device_id: device_id.to_string(),
+        public_key,
+        issuer: shared.hello.device_id.clone(),
+        issued_at,
+        wipe,
+        signature: vec![],
+    };
+    revocation.signature = shared
+        .identity
+        .signing_key()
+        .sign(&revocation.signed_bytes())
+        .to_vec();
+
+    log::info!(
+        "revoking device {}{}",
+        device_id,
+        if wipe { " and asking it to wipe" } else { "" }
+    );
+    lock(&shared.revocations)?.add(revocation.clone())?;
+    super::forget_device(shared, device_id)?;
+    pass_on(shared, None, &revocation);
+    Ok(())
+}
+
+/// A revocation a trusted device sent us. It only counts if it is signed by a device we trust, whoever passed it
+/// on, and is ignored if we revoked the issuer, so a stolen device can't cut the others off from each other.
+/// Returns whether this device is the one revoked, which ends the link.
+pub fn receive(shared: &Shared, from: &str, revocation: Revocation) -> bool {
+    if revocation.device_id == shared.hello.device_id {
+        receive_own(shared, from, revocation);
+        return true;
+    }
+    if revocation.issuer == shared.hello.device_id {
+        return false;
+    }
+    match accept(shared, &revocation) {
+        Ok(true) => {
+            log::info!(
+                "{} revoked device {}, passed on by {}",
+                revocation.issuer,
+                revocation.device_id,
+                from
+            );
+            pass_on(shared, Some(from), &revocation);
+        }
+        Ok(false) => {}
+        Err(e) => log::warn!(
+            "Ignoring revocation of {} from {}: {:#}",
+            revocation.device_id,
+            from,
+            e
+        ),
+    }
+    false
+}
+
+/// Store a revocation and forget the device it revokes. Returns false if it's nothing new.
+fn accept(shared: &Shared, revocation: &Revocation) -> Result<bool, anyhow::Error> {
+    if lock(&shared.revocations)?.get(&revocation.issuer).is_some() {
+        return Err(anyhow!("the issuer was revoked itself"));
+    }
+    let (issuer_key, revoked) = {
+        let trust = lock(&shared.trust)?;
+        let issuer_key = trust.get(&revocation.issuer).map(|d| d.signing_key.clone());
+        let revoked = trust.get(&revocation.device_id).map(|d| {
+            (
+                d.public_key == revocation.public_key,
+                d.paired_at > revocation.issued_at,
+            )
+        });
+        (issuer_key, revoked)
+    };
+    check_signature(revocation, issuer_key.as_deref())?;
+    if let Some((_, true)) = revoked {
+        log::debug!(
+            "{} was paired again after it was revoked",
+            revocation.device_id
+        );
+        return Ok(false);
+    }
+    if !lock(&shared.revocations)?.add(revocation.clone())? {
+        return Ok(false);
+    }
+    if let Some((true, false)) = revoked {
+        super::forget_device(shared, &revocation.device_id)?;
+        // boards from the device are gone too
+        with_manager(shared, |m| m.emit());
+        let event = DeviceRevoked {
+            device_id: revocation.device_id.clone(),
+            issuer: revocation.issuer.clone(),
+        };
+        if let Err(e) = shared.app.emit("device-revoked", event) {
+            log::error!("Could not emit device-revoked event: {}", e);
+        }
+    }
+    Ok(true)
+}
+
+/// A revocation counts only with a valid signature from its issuer, which has to be a device we trust and whose
+/// signing key we know. `issuer_key` is that key, `None` if we don't trust the issuer.
+fn check_signature(
+    revocation: &Revocation,
+    issuer_key: Option<&[u8]>,
+) -> Result<(), anyhow::Error> {
+    match issuer_key {
+        None => Err(anyhow!("the issuer is not a trusted device")),
+        Some([]) => Err(anyhow!("the issuer's signing key isn't known yet")),
+        Some(key) if !revocation.verify(key) => Err(anyhow!("bad signature")),
+        Some(_) => Ok(()),
+    }
+}
+
+/// This device was revoked. Only a wipe signed by a device we trust is acted on, whoever passed it on.
+fn receive_own(shared: &Shared, from: &str, revocation: Revocation) {
+    if !revocation.wipe {
+        log::warn!("{} revoked this device", revocation.issuer);
+        return;
+    }
+    let issuer_key = lock(&shared.trust)
+        .ok()
+        .and_then(|t| t.get(&revocation.issuer).map(|d| d.signing_key.clone()));
+    let valid = revocation.public_key == shared.hello.public_key
+        && check_signature(&revocation, issuer_key.as_deref()).is_ok();
+    if !valid {
+        log::warn!(
+            "Ignoring wipe of this device from {}, it isn't signed by a trusted device",
+            from
+        );
+        return;
+    }
+    log::warn!(
+        "{} revoked this device and asked for a wipe, clearing history and pinned items",
+        revocation.issuer
+    );
+    with_manager(shared, |m| m.wipe());
+}
+
+/// Send a revocation to every connected device except the one it came from and the one it revokes.
+fn pass_on(shared: &Shared, from: Option<&str>, revocation: &Revocation) {
+    let Ok(links) = lock(&shared.links) else {
+        return;
+    };
+    for (device_id, link) in links.iter() {
+        if Some(device_id.as_str()) != from && *device_id != revocation.device_id {
+            link.send(Message::Revocation(revocation.clone()));
+        }
+    }
+}
+
+/// Whether we hold a wipe for a device that it hasn't been sent yet.
+pub fn wipe_pending(shared: &Shared, device_id: &str) -> bool {
+    lock(&shared.revocations).is_ok_and(|r| {
+        r.get(device_id)
+            .is_some_and(|s| s.revocation.wipe && !s.wiped)
+    })
+}
+
+/// Hand a revoked device that connected its wipe, if we hold one for it. Returns whether we did, in which case the
+/// connection is done with. The device hangs up once it has the wipe, until then what it sends is read and
+/// dropped, since closing with unread data could lose the wipe on the way.
+pub fn deliver_wipe(shared: &Shared, stream: &mut SecureStream, remote: &Hello) -> bool {
+    let revocation = lock(&shared.revocations).ok().and_then(|r| {
+        r.get(&remote.device_id)
+            .filter(|s| s.revocation.wipe && !s.wiped)
+            .filter(|s| s.revocation.public_key == remote.public_key)
+            .map(|s| s.revocation.clone())
+    });
+    let Some(revocation) = revocation else {
+        return false;
+    };
+    if lock(&shared.trust).is_ok_and(|t| t.is_trusted(&remote.device_id, &remote.public_key)) {
+        return false;
+    }
+    match protocol::write_message(stream, &Message::Revocation(revocation)) {
+        Ok(()) => {
+            log::info!(
+                "sent wipe to revoked device {} ({})",
+                remote.name,
+                remote.device_id
+            );
+            if let Err(e) = lock(&shared.revocations).and_then(|mut r| r.wiped(&remote.device_id)) {
+                log::error!("Could not save revocation: {:#}", e);
+            }
+            let started = Instant::now();
+            while started.elapsed() < HANDSHAKE_TIMEOUT && protocol::read_message(stream).is_ok() {}
+        }
+        Err(e) => log::warn!("Could not send wipe to {}: {}", remote.name, e),
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    fn signed(key: &SigningKey) -> Revocation {
+        let mut revocation = Revocation {
+            device_id: "lost".to_string(),
+            public_key: vec![7; 32],
+            issuer: "issuer".to_string(),
+            issued_at: 1_700_000_000,
+            wipe: true,
+            signature: vec![],
+        };
+        revocation.signature = key.sign(&revocation.signed_bytes()).to_vec();
+        revocation
+    }
+
+    #[test]
+    fn accepts_signature_from_trusted_issuer() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let revocation = signed(&key);
+        let issuer_key = key.verifying_key().to_bytes();
+        assert!(check_signature(&revocation, Some(&issuer_key)).is_ok());
+    }
+
+    #[test]
+    fn rejects_forged_revocation() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let issuer_key = key.verifying_key().to_bytes();
+
+        // signed by some other key under the trusted issuer's id
+        let forger = SigningKey::from_bytes(&[2; 32]);
+        assert!(check_signature(&signed(&forger), Some(&issuer_key)).is_err());
+
+        // a real signature with the revoked device changed afterwards
+        let mut altered = signed(&key);
+        altered.device_id = "someone else".to_string();
+        assert!(check_signature(&altered, Some(&issuer_key)).is_err());
+
+        // or the wipe turned on
+        let mut altered = signed(&key);
+        altered.wipe = false;
+        assert!(check_signature(&altered, Some(&issuer_key)).is_err());
+    }
+
+    #[test]
+    fn rejects_unsigned_revocation() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let issuer_key = key.verifying_key().to_bytes();
+        let mut revocation = signed(&key);
+        revocation.signature = vec![];
+        assert!(check_signature(&revocation, Some(&issuer_key)).is_err());
+        revocation.signature = vec![0; 64];
+        assert!(check_signature(&revocation, Some(&issuer_key)).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_issuer() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let revocation = signed(&key);
+        assert!(check_signature(&revocation, None).is_err());
+        // trusted, but it hasn't told us its signing key yet
+        assert!(check_signature(&revocation, Some(&[])).is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use snow::{HandshakeState, TransportState};
+
+use super::identity::{Identity, NOISE_PARAMS};
+use super::protocol::ProtocolError;
+use super::transport::Connection;
+
+/// Largest Noise message, including its authentication tag.
+const MAX_NOISE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_CHUNK_LEN: usize = MAX_NOISE_LEN - TAG_LEN;
+
+/// A connection encrypted and mutually authenticated with a Noise XX handshake over both devices' long-term keys.
+///
+/// On the wire every Noise message is prefixed with its length as a u16. Any frame that fails to decrypt,
+/// whether tampered with, replayed or reordered, is reported as an `InvalidData` error and the connection is closed,
+/// so nothing after it is read either.
+pub struct SecureStream {
+    stream: Box<dyn Connection>,
+    transport: Arc<Mutex<TransportState>>,
+    /// Set once a frame failed to decrypt. Noise doesn't move on to the next nonce then, so a frame that was held
+    /// back would still decrypt after it.
+    broken: Arc<AtomicBool>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    write_buf: Vec<u8>,
+}
+
+impl SecureStream {
+    pub fn initiate(
+        mut stream: Box<dyn Connection>,
+        identity: &Identity,
+    ) -> Result<Self, ProtocolError> {
+        let mut noise = builder(identity)?.build_initiator().map_err(noise_error)?;
+        send_handshake(&mut stream, &mut noise)?;
+        recv_handshake(&mut stream, &mut noise)?;
+        send_handshake(&mut stream, &mut noise)?;
+        Self::finish(stream, noise)
+    }
+
+    pub fn respond(
+        mut stream: Box<dyn Connection>,
+        identity: &Identity,
+    ) -> Result<Self, ProtocolError> {
+        let mut noise = builder(identity)?.build_responder().map_err(noise_error)?;
+        recv_handshake(&mut stream, &mut noise)?;
+        send_handshake(&mut stream, &mut noise)?;
+        recv_handshake(&mut stream, &mut noise)?;
+        Self::finish(stream, noise)
+    }
+
+    fn finish(stream: Box<dyn Connection>, noise: HandshakeState) -> Result<Self, ProtocolError> {
+        let transport = noise.into_transport_mode().map_err(noise_error)?;
+        Ok(Self {
+            stream,
+            transport: Arc::new(Mutex::new(transport)),
+            broken: Arc::new(AtomicBool::new(false)),
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_buf: Vec::new(),
+        })
+    }
+
+    /// The long-term public key the other side proved it holds during the handshake.
+    pub fn remote_key(&self) -> Vec<u8> {
+        self.transport
+            .lock()
+            .ok()
+            .and_then(|t| t.get_remote_static().map(<[u8]>::to_vec))
+            .unwrap_or_default()
+    }
+
+    pub fn stream(&self) -> &dyn Connection {
+        &*self.stream
+    }
+
+    /// Another handle to the same connection, so one thread can read while another writes.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            stream: self.stream.try_clone()?,
+            transport: Arc::clone(&self.transport),
+            broken: Arc::clone(&self.broken),
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_buf: Vec::new(),
+        })
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        if self.broken.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the connection was closed after a frame failed to decrypt",
+            ));
+        }
+        let ciphertext = read_frame(&mut self.stream)?;
+        let mut plaintext = vec![0u8; ciphertext.len()];
+        let decrypted = self
+            .transport
+            .lock()
+            .map_err(|_| io::Error::other("noise transport lock was poisoned"))?
+            .read_message(&ciphertext, &mut plaintext);
+        let len = match decrypted {
+            Ok(len) => len,
+            Err(e) => {
+                self.broken.store(true, Ordering::SeqCst);
+                self.stream.shutdown();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("could not decrypt frame: {}", e),
+                ));
+            }
+        };
+        plaintext.truncate(len);
+        self.read_buf = plaintext;
+        self.read_pos = 0;
+        Ok(())
+    }
+}
+
+impl Read for SecureStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read_pos == self.read_buf.len() {
+            self.fill()?;
+        }
+        let n = buf.len().min(self.read_buf.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for SecureStream {
+    /// Buffered until `flush`, so one protocol message goes out as as few Noise messages as possible.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+        let mut out = Vec::new();
+        {
+            let mut transport = self
+                .transport
+                .lock()
+                .map_err(|_| io::Error::other("noise transport lock was poisoned"))?;
+            let mut message = vec![0u8; MAX_NOISE_LEN];
+            for chunk in self.write_buf.chunks(MAX_CHUNK_LEN) {
+                let len = transport
+                    .write_message(chunk, &mut message)
+                    .map_err(io::Error::other)?;
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+                out.extend_from_slice(&message[..len]);
+            }
+        }
+        self.write_buf.clear();
+        self.stream.write_all(&out)?;
+        self.stream.flush()
+    }
+}
+
+fn builder(identity: &Identity) -> Result<snow::Builder<'_>, ProtocolError> {
+    let params = NOISE_PARAMS.parse().map_err(noise_error)?;
+    Ok(snow::Builder::new(params).local_private_key(&identity.private))
+}
+
+fn noise_error(e: snow::Error) -> ProtocolError {
+    ProtocolError::Handshake(e.to_string())
+}
+
+fn send_handshake<S: Write + ?Sized>(
+    stream: &mut S,
+    noise: &mut HandshakeState,
+) -> Result<(), ProtocolError> {
+    let mut message = vec![0u8; MAX_NOISE_LEN];
+    let len = noise
+        .write_message(&[], &mut message)
+        .map_err(noise_error)?;
+    stream.write_all(&(len as u16).to_be_bytes())?;
+    stream.write_all(&message[..len])?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn recv_handshake<S: Read + ?Sized>(
+    stream: &mut S,
+    noise: &mut HandshakeState,
+) -> Result<(), ProtocolError> {
+    let message = read_frame(stream)?;
+    let mut payload = vec![0u8; MAX_NOISE_LEN];
+    noise
+        .read_message(&message, &mut payload)
+        .map_err(noise_error)?;
+    Ok(())
+}
+
+fn read_frame<S: Read + ?Sized>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut frame = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::transport::TransportKind;
+    use std::collections::VecDeque;
+    use std::sync::Condvar;
+    use std::time::Duration;
+
+    /// Bytes going one way through a pipe, which the tests can get at in between.
+    #[derive(Default)]
+    struct Buffer {
+        bytes: Mutex<(VecDeque<u8>, bool)>,
+        ready: Condvar,
+    }
+
+    impl Buffer {
+        fn take(&self) -> Vec<u8> {
+            self.bytes.lock().unwrap().0.drain(..).collect()
+        }
+
+        fn put(&self, bytes: &[u8]) {
+            self.bytes.lock().unwrap().0.extend(bytes);
+            self.ready.notify_all();
+        }
+
+        fn closed(&self) -> bool {
+            self.bytes.lock().unwrap().1
+        }
+    }
+
+    /// One end of an in-memory connection.
+    struct Pipe {
+        read: Arc<Buffer>,
+        write: Arc<Buffer>,
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let (a, b) = (Arc::new(Buffer::default()), Arc::new(Buffer::default()));
+        (
+            Pipe {
+                read: Arc::clone(&a),
+                write: Arc::clone(&b),
+            },
+            Pipe { read: b, write: a },
+        )
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut bytes = self.read.bytes.lock().unwrap();
+            while bytes.0.is_empty() && !bytes.1 {
+                bytes = self.read.ready.wait(bytes).unwrap();
+            }
+            let n = buf.len().min(bytes.0.len());
+            for (i, byte) in bytes.0.drain(..n).enumerate() {
+                buf[i] = byte;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.write.closed() {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.write.put(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Pipe {
+        fn try_clone(&self) -> io::Result<Box<dyn Connection>> {
+            Ok(Box::new(Pipe {
+                read: Arc::clone(&self.read),
+                write: Arc::clone(&self.write),
+            }))
+        }
+
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn shutdown(&self) {
+            for buffer in [&self.read, &self.write] {
+                buffer.bytes.lock().unwrap().1 = true;
+                buffer.ready.notify_all();
+            }
+        }
+
+        fn kind(&self) -> TransportKind {
+            TransportKind::Tcp
+        }
+
+        fn remote(&self) -> String {
+            "pipe".into()
+        }
+    }
+
+    fn identity() -> Identity {
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+            .generate_keypair()
+            .unwrap();
+        Identity {
+            private: keypair.private,
+            public: keypair.public,
+        }
+    }
+
+    /// A session over a pipe, and the buffer of what the sender writes to the receiver.
+    fn session() -> (SecureStream, SecureStream, Arc<Buffer>) {
+        let (a, b) = pipe();
+        let wire = Arc::clone(&a.write);
+        let responding =
+            std::thread::spawn(move || SecureStream::respond(Box::new(b), &identity()));
+        let sender = SecureStream::initiate(Box::new(a), &identity()).unwrap();
+        let receiver = responding.join().unwrap().unwrap();
+        (sender, receiver, wire)
+    }
+
+    /// Send `text` as one Noise message, which is taken off the wire and returned.
+    fn frame(sender: &mut SecureStream, wire: &Buffer, text: &str) -> Vec<u8> {
+        sender.write_all(text.as_bytes()).unwrap();
+        sender.flush().unwrap();
+        wire.take()
+    }
+
+    fn read(receiver: &mut SecureStream, len: usize) -> io::Result<String> {
+        let mut buf = vec![0u8; len];
+        receiver.read_exact(&mut buf)?;
+        Ok(String::from_utf8(buf).unwrap())
+    }
+
+    /// The read of the frame that doesn't belong failed, and the session is over.
+    fn assert_closed(receiver: &mut SecureStream, wire: &Buffer, e: io::Error) {
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{e}");
+        assert!(wire.closed());
+        assert!(read(receiver, 1).is_err());
+    }
+
+    #[test]
+    fn frames_go_through() {
+        let (mut sender, mut receiver, wire) = session();
+        for text in ["first", "second"] {
+            let frame = frame(&mut sender, &wire, text);
+            wire.put(&frame);
+            assert_eq!(read(&mut receiver, text.len()).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn tampered_frame() {
+        let (mut sender, mut receiver, wire) = session();
+        let mut frame = frame(&mut sender, &wire, "hello");
+        frame[4] ^= 1;
+        wire.put(&frame);
+        let e = read(&mut receiver, 5).unwrap_err();
+        assert_closed(&mut receiver, &wire, e);
+    }
+
+    #[test]
+    fn replayed_frame() {
+        let (mut sender, mut receiver, wire) = session();
+        let first = frame(&mut sender, &wire, "hello");
+        wire.put(&first);
+        assert_eq!(read(&mut receiver, 5).unwrap(), "hello");
+        wire.put(&first);
+        let e = read(&mut receiver, 5).unwrap_err();
+        assert_closed(&mut receiver, &wire, e);
+    }
+
+    #[test]
+    fn reordered_frames() {
+        let (mut sender, mut receiver, wire) = session();
+        let first = frame(&mut sender, &wire, "first");
+        let second = frame(&mut sender, &wire, "second");
+        wire.put(&second);
+        wire.put(&first);
+        let e = read(&mut receiver, 6).unwrap_err();
+        assert_closed(&mut receiver, &wire, e);
+    }
+}
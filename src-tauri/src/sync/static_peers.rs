@@ -0,0 +1,309 @@
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::AppHandle;
+use tauri_plugin_store::StoreExt;
+
+use super::discovery::Peer;
+use super::presence;
+use super::protocol::{Capabilities, Hello};
+use super::secure::SecureStream;
+use super::transport::TransportKind;
+use super::{lock, Shared, CONNECT_TIMEOUT, HANDSHAKE_TIMEOUT};
+
+const STATIC_PEERS_STORE: &str = "static_peers.json";
+/// Wait before retrying a static peer that couldn't be reached, doubled after every failure in a row.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// How often to check that a static peer we aren't paired with is still there.
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A device added by address, for networks where multicast discovery doesn't get through. What it said about
+/// itself the last time it answered is kept, so it can be listed and dialed like a discovered device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StaticPeer {
+    /// `host:port` as it was entered.
+    pub address: String,
+    pub device_id: Option<String>,
+    pub name: Option<String>,
+    pub os: Option<String>,
+}
+
+/// A static peer and how reaching it went the last time.
+#[derive(Debug, Clone, Serialize)]
+pub struct StaticPeerStatus {
+    #[serde(flatten)]
+    pub peer: StaticPeer,
+    pub online: bool,
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct Reachability {
+    /// The device as of the last time it answered, `None` while it can't be reached.
+    online: Option<Peer>,
+    last_error: Option<String>,
+    failures: u32,
+    next_attempt: Option<Instant>,
+    /// A connection attempt or an open link is using this address.
+    busy: bool,
+}
+
+/// Devices to connect to by address, persisted in `static_peers.json`.
+pub struct StaticPeers {
+    app: AppHandle,
+    peers: Vec<StaticPeer>,
+    state: HashMap<String, Reachability>,
+}
+
+impl StaticPeers {
+    pub fn load(app: &AppHandle) -> Result<Self, anyhow::Error> {
+        let store = app
+            .store(STATIC_PEERS_STORE)
+            .with_context(|| "failed to get or create static peers store")?;
+        let peers = match store.get("peers") {
+            Some(value) => serde_json::from_value::<Vec<StaticPeer>>(value)
+                .with_context(|| "static peers store contents were malformed")?,
+            None => vec![],
+        };
+        store.close_resource();
+
+        Ok(Self {
+            app: app.clone(),
+            peers,
+            state: HashMap::new(),
+        })
+    }
+
+    pub fn list(&self) -> Vec<StaticPeerStatus> {
+        self.peers
+            .iter()
+            .map(|peer| {
+                let state = self.state.get(&peer.address);
+                StaticPeerStatus {
+                    peer: peer.clone(),
+                    online: state.is_some_and(|s| s.online.is_some()),
+                    last_error: state.and_then(|s| s.last_error.clone()),
+                }
+            })
+            .collect()
+    }
+
+    pub fn add(&mut self, address: &str) -> Result<(), anyhow::Error> {
+        let address = address.trim();
+        validate(address)?;
+        if self.peers.iter().any(|p| p.address == address) {
+            return Err(anyhow!("{} was already added", address));
+        }
+        log::info!("added static peer {}", address);
+        self.peers.push(StaticPeer {
+            address: address.to_string(),
+            device_id: None,
+            name: None,
+            os: None,
+        });
+        self.save()
+    }
+
+    pub fn remove(&mut self, address: &str) -> Result<bool, anyhow::Error> {
+        let before = self.peers.len();
+        self.peers.retain(|p| p.address != address);
+        if self.peers.len() == before {
+            return Ok(false);
+        }
+        self.state.remove(address);
+        log::info!("removed static peer {}", address);
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Static peers that answered the last time they were tried.
+    pub fn online(&self) -> impl Iterator<Item = &Peer> {
+        self.state.values().filter_map(|s| s.online.as_ref())
+    }
+
+    pub fn peer(&self, device_id: &str) -> Option<Peer> {
+        self.online().find(|p| p.id == device_id).cloned()
+    }
+
+    /// Discovered devices followed by the static peers that are reachable and weren't discovered.
+    pub fn merge(&self, mut discovered: Vec<Peer>) -> Vec<Peer> {
+        let mut extra = self
+            .online()
+            .filter(|p| !discovered.iter().any(|d| d.id == p.id))
+            .cloned()
+            .collect::<Vec<_>>();
+        extra.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        discovered.extend(extra);
+        discovered
+    }
+
+    /// Addresses that are due for another try, with the device last seen at each.
+    pub fn due(&self) -> Vec<(String, Option<String>)> {
+        let now = Instant::now();
+        self.peers
+            .iter()
+            .filter(|p| {
+                self.state
+                    .get(&p.address)
+                    .is_none_or(|s| !s.busy && s.next_attempt.is_none_or(|t| t <= now))
+            })
+            .map(|p| (p.address.clone(), p.device_id.clone()))
+            .collect()
+    }
+
+    /// Mark an address as in use, returning false if something else already is.
+    pub fn begin(&mut self, address: &str) -> bool {
+        let state = self.state.entry(address.to_string()).or_default();
+        !std::mem::replace(&mut state.busy, true)
+    }
+
+    fn succeeded(&mut self, address: &str, remote: &Hello, socket: SocketAddr) {
+        let Some(peer) = self.peers.iter_mut().find(|p| p.address == address) else {
+            return;
+        };
+        if peer.device_id.as_deref() != Some(remote.device_id.as_str())
+            || peer.name.as_deref() != Some(remote.name.as_str())
+        {
+            log::info!(
+                "static peer {} is {} ({})",
+                address,
+                remote.name,
+                remote.device_id
+            );
+            peer.device_id = Some(remote.device_id.clone());
+            peer.name = Some(remote.name.clone());
+            peer.os = Some(remote.os.clone());
+            if let Err(e) = self.save() {
+                log::error!("Could not save static peers: {:#}", e);
+            }
+        }
+
+        let state = self.state.entry(address.to_string()).or_default();
+        state.online = Some(Peer {
+            id: remote.device_id.clone(),
+            name: remote.name.clone(),
+            os: remote.os.clone(),
+            version: remote.version,
+            addresses: vec![socket.ip()],
+            port: socket.port(),
+            static_address: Some(address.to_string()),
+            // added by a TCP address
+            transports: vec![TransportKind::Tcp],
+            socket: None,
+        });
+        state.last_error = None;
+        state.failures = 0;
+    }
+
+    /// Returns the device last seen at the address.
+    fn failed(&mut self, address: &str, error: String) -> Option<String> {
+        let state = self.state.entry(address.to_string()).or_default();
+        if state.failures == 0 {
+            log::warn!("Could not reach static peer {}: {}", address, error);
+        }
+        state.online = None;
+        state.last_error = Some(error);
+        state.failures = state.failures.saturating_add(1);
+        state.busy = false;
+        state.next_attempt = Some(Instant::now() + backoff(state.failures));
+        self.peers
+            .iter()
+            .find(|p| p.address == address)
+            .and_then(|p| p.device_id.clone())
+    }
+
+    fn finish(&mut self, address: &str, retry_in: Duration) {
+        if let Some(state) = self.state.get_mut(address) {
+            state.busy = false;
+            state.next_attempt = Some(Instant::now() + retry_in);
+        }
+    }
+
+    fn save(&self) -> Result<(), anyhow::Error> {
+        let store = self
+            .app
+            .store(STATIC_PEERS_STORE)
+            .with_context(|| "failed to get static peers store")?;
+        store.set("peers", serde_json::to_value(&self.peers)?);
+        Ok(())
+    }
+}
+
+/// Connect to a static peer with the same handshake as a discovered device. A trusted device gets a sync link,
+/// which this runs until it closes. Anything else is only noted as reachable so it can be listed and paired with.
+pub fn dial(shared: &Arc<Shared>, address: &str) {
+    let (mut stream, remote, capabilities, socket) = match handshake(shared, address) {
+        Ok(res) => res,
+        Err(e) => {
+            let error = format!("{:#}", e);
+            let device_id = lock(&shared.static_peers)
+                .ok()
+                .and_then(|mut peers| peers.failed(address, error.clone()));
+            if let Some(id) =
+                device_id.filter(|id| lock(&shared.trust).is_ok_and(|t| t.contains(id)))
+            {
+                presence::failed(shared, &id, error);
+            }
+            return;
+        }
+    };
+    if let Ok(mut peers) = lock(&shared.static_peers) {
+        peers.succeeded(address, &remote, socket);
+    }
+
+    let trusted =
+        lock(&shared.trust).is_ok_and(|t| t.is_trusted(&remote.device_id, &remote.public_key));
+    let connected = lock(&shared.links).is_ok_and(|l| l.contains_key(&remote.device_id));
+    let retry_in = if !trusted && super::revocation::deliver_wipe(shared, &mut stream, &remote) {
+        PROBE_INTERVAL
+    } else if trusted && !connected {
+        super::link::run(stream, remote, capabilities, Arc::clone(shared), true);
+        // the link went down, find out straight away whether the device is still there
+        Duration::ZERO
+    } else {
+        PROBE_INTERVAL
+    };
+    if let Ok(mut peers) = lock(&shared.static_peers) {
+        peers.finish(address, retry_in);
+    }
+}
+
+fn handshake(
+    shared: &Shared,
+    address: &str,
+) -> Result<(SecureStream, Hello, Capabilities, SocketAddr), anyhow::Error> {
+    let socket = address
+        .to_socket_addrs()
+        .with_context(|| format!("could not resolve {}", address))?
+        .next()
+        .ok_or_else(|| anyhow!("{} did not resolve to any address", address))?;
+    let stream = TcpStream::connect_timeout(&socket, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let stream = SecureStream::initiate(Box::new(stream), &shared.identity)?;
+    let (stream, remote, capabilities) = super::open(stream, &shared.hello)?;
+    Ok((stream, remote, capabilities, socket))
+}
+
+/// Addresses are checked when they're added rather than resolved, the host may not be reachable yet.
+fn validate(address: &str) -> Result<(), anyhow::Error> {
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("{} should look like host:port", address))?;
+    if host.is_empty() {
+        return Err(anyhow!("{} has no host", address));
+    }
+    match port.parse::<u16>() {
+        Ok(port) if port != 0 => Ok(()),
+        _ => Err(anyhow!("{} is not a valid port", port)),
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
@@ -0,0 +1,737 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context};
+use sha2::{Digest, Sha256};
+use tauri::{Emitter, Manager};
+use uuid::Uuid;
+
+use super::protocol::{FileEntry, Message};
+use super::{lock, Shared};
+use crate::contents::Contents;
+use crate::settings::Settings;
+
+/// Bytes of file data per chunk. The next chunk is only requested once the last one is written,
+/// so a dropped connection costs at most one chunk.
+const CHUNK_LEN: u64 = 1024 * 1024;
+/// How many shared paths items we keep serving the files of.
+const MAX_OFFERS: usize = 64;
+/// Partial downloads live in here, inside the downloads folder so finished files can be moved out with a rename.
+const STAGING_DIR: &str = ".purple-clip-partial";
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum TransferState {
+    /// Waiting for the sender to list the files.
+    Waiting,
+    /// Larger than the confirmation threshold, waiting for the user to accept it.
+    Confirm,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct TransferProgress {
+    id: String,
+    device_id: String,
+    name: String,
+    received: u64,
+    total: u64,
+    state: TransferState,
+    error: Option<String>,
+}
+
+/// File transfers in both directions: paths items we sent and will serve files for, and ones we are downloading.
+/// Both only live in memory. A download resumes when its device connects again, but not after either app restarts,
+/// since neither side would know about it any more.
+#[derive(Default)]
+pub struct Transfers {
+    /// Oldest first.
+    offers: VecDeque<Offer>,
+    downloads: HashMap<Uuid, Download>,
+}
+
+/// A paths item we sent. Only the devices it was sent to may fetch its files.
+struct Offer {
+    uid: Uuid,
+    paths: Vec<PathBuf>,
+    devices: HashSet<String>,
+    /// Built when first requested, with the local path of every entry.
+    manifest: Option<Vec<(FileEntry, PathBuf)>>,
+}
+
+struct Download {
+    device_id: String,
+    item: Arc<Contents>,
+    /// Sent on purpose rather than synced, see `Message::Push`.
+    pushed: bool,
+    name: String,
+    downloads: PathBuf,
+    staging: PathBuf,
+    /// Empty until the sender's manifest arrives.
+    entries: Vec<FileEntry>,
+    /// Where each entry goes, relative to `staging`.
+    paths: Vec<PathBuf>,
+    awaiting_confirm: bool,
+    /// Entry currently being downloaded, and how many of its bytes we have.
+    index: usize,
+    offset: u64,
+    /// Bytes of the entries before `index`.
+    done: u64,
+    total: u64,
+}
+
+impl Download {
+    /// Work out what to ask the sender for next, checking off every file that is complete on disk. A file
+    /// left over from an earlier attempt carries on from where it stopped. Returns `None` once every file is here.
+    fn next_request(&mut self, uid: Uuid) -> Result<Option<Message>, anyhow::Error> {
+        while let Some(entry) = self.entries.get(self.index) {
+            let path = self.staging.join(&self.paths[self.index]);
+            if entry.dir {
+                fs::create_dir_all(&path)
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                self.index += 1;
+                continue;
+            }
+
+            let len = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            if len < entry.size {
+                self.offset = len;
+                return Ok(Some(Message::ChunkRequest {
+                    uid,
+                    index: self.index as u32,
+                    offset: len,
+                }));
+            }
+            if len > entry.size || hash_file(&path)? != entry.hash {
+                _ = fs::remove_file(&path);
+                return Err(anyhow!("{} did not match the sender's copy", entry.path));
+            }
+            self.done += entry.size;
+            self.offset = 0;
+            self.index += 1;
+        }
+        Ok(None)
+    }
+
+    fn write_chunk(&mut self, offset: u64, data: &[u8]) -> Result<(), anyhow::Error> {
+        let entry = &self.entries[self.index];
+        if data.is_empty() {
+            return Err(anyhow!(
+                "the sender's copy of {} is shorter than it said",
+                entry.path
+            ));
+        }
+        // a sender that keeps going past the size it listed could otherwise fill the disk
+        if offset
+            .checked_add(data.len() as u64)
+            .is_none_or(|end| end > entry.size)
+        {
+            return Err(anyhow!(
+                "the sender's copy of {} is longer than it said",
+                entry.path
+            ));
+        }
+        let path = self.staging.join(&self.paths[self.index]);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        self.offset = offset + data.len() as u64;
+        Ok(())
+    }
+
+    fn emit(&self, shared: &Shared, uid: Uuid, state: TransferState, error: Option<String>) {
+        let progress = TransferProgress {
+            id: uid.to_string(),
+            device_id: self.device_id.clone(),
+            name: self.name.clone(),
+            received: self.done + self.offset,
+            total: self.total,
+            state,
+            error,
+        };
+        if let Err(e) = shared.app.emit("transfer-progress", progress) {
+            log::error!("Could not emit transfer-progress event: {}", e);
+        }
+    }
+}
+
+/// Remember that a paths item was sent to a device, so it can fetch the files behind it.
+pub fn offer(shared: &Shared, device_id: &str, item: &Contents) {
+    let Contents::FilePath { paths, meta, .. } = item else {
+        return;
+    };
+    let Ok(mut transfers) = lock(&shared.transfers) else {
+        return;
+    };
+    if let Some(offer) = transfers.offers.iter_mut().find(|o| o.uid == meta.uid) {
+        offer.devices.insert(device_id.to_string());
+        return;
+    }
+    if transfers.offers.len() == MAX_OFFERS {
+        transfers.offers.pop_front();
+    }
+    transfers.offers.push_back(Offer {
+        uid: meta.uid,
+        paths: paths.clone(),
+        devices: HashSet::from([device_id.to_string()]),
+        manifest: None,
+    });
+}
+
+/// Start downloading the files behind a paths item from the device that sent it.
+/// The item goes into history, pointing at the local copies, once every file is here.
+pub fn start(shared: &Shared, from: &str, item: Arc<Contents>, pushed: bool) {
+    let Contents::FilePath { paths, meta, .. } = item.as_ref() else {
+        return;
+    };
+    let uid = meta.uid;
+    let downloads = match downloads_dir(shared) {
+        Ok(dir) => dir,
+        Err(e) => {
+            log::error!("Could not receive files from {}: {:#}", from, e);
+            return;
+        }
+    };
+    let name = match paths.first().and_then(|p| p.file_name()) {
+        Some(first) if paths.len() > 1 => {
+            format!("{} and {} more", first.to_string_lossy(), paths.len() - 1)
+        }
+        Some(first) => first.to_string_lossy().into_owned(),
+        None => "files".to_string(),
+    };
+    let download = Download {
+        device_id: from.to_string(),
+        item: Arc::clone(&item),
+        pushed,
+        name,
+        staging: downloads.join(STAGING_DIR).join(uid.to_string()),
+        downloads,
+        entries: vec![],
+        paths: vec![],
+        awaiting_confirm: false,
+        index: 0,
+        offset: 0,
+        done: 0,
+        total: 0,
+    };
+
+    {
+        let Ok(mut transfers) = lock(&shared.transfers) else {
+            return;
+        };
+        if transfers.downloads.contains_key(&uid) {
+            log::debug!("already downloading the files of {}", uid);
+            return;
+        }
+        download.emit(shared, uid, TransferState::Waiting, None);
+        transfers.downloads.insert(uid, download);
+    }
+    send(shared, from, Message::ManifestRequest(uid));
+}
+
+/// Answer the user about a download waiting on confirmation.
+pub fn confirm(shared: &Shared, uid: Uuid, accept: bool) -> Result<(), anyhow::Error> {
+    {
+        let mut transfers = lock(&shared.transfers)?;
+        let download = transfers
+            .downloads
+            .get_mut(&uid)
+            .filter(|d| d.awaiting_confirm)
+            .ok_or_else(|| anyhow!("no transfer {} is waiting to be confirmed", uid))?;
+        download.awaiting_confirm = false;
+    }
+    match accept {
+        true => advance(shared, uid),
+        false => fail(shared, uid, "declined"),
+    }
+    Ok(())
+}
+
+/// Pick up unfinished downloads from a device that has just connected again.
+pub fn resume(shared: &Shared, device_id: &str) {
+    let pending = match lock(&shared.transfers) {
+        Ok(transfers) => transfers
+            .downloads
+            .iter()
+            .filter(|(_, d)| d.device_id == device_id && !d.awaiting_confirm)
+            .map(|(uid, d)| (*uid, d.entries.is_empty()))
+            .collect::<Vec<_>>(),
+        Err(_) => return,
+    };
+    for (uid, needs_manifest) in pending {
+        log::info!("resuming transfer {} from {}", uid, device_id);
+        match needs_manifest {
+            true => send(shared, device_id, Message::ManifestRequest(uid)),
+            false => advance(shared, uid),
+        }
+    }
+}
+
+/// Handle a file transfer message from a connected device.
+pub fn handle(shared: &Arc<Shared>, from: &str, msg: Message) {
+    match msg {
+        Message::ManifestRequest(uid) => {
+            // hashing every file can take a while, don't hold up the connection
+            let shared = Arc::clone(shared);
+            let from = from.to_string();
+            std::thread::spawn(move || {
+                let reply = match manifest(&shared, &from, uid) {
+                    Ok(entries) => Message::Manifest(uid, entries),
+                    Err(e) => {
+                        log::warn!("Could not list files of {} for {}: {:#}", uid, from, e);
+                        Message::TransferFailed(uid, format!("{:#}", e))
+                    }
+                };
+                send(&shared, &from, reply);
+            });
+        }
+        Message::ChunkRequest { uid, index, offset } => {
+            let reply = match read_chunk(shared, from, uid, index, offset) {
+                Ok(data) => Message::Chunk {
+                    uid,
+                    index,
+                    offset,
+                    data,
+                },
+                Err(e) => {
+                    log::warn!("Could not send file chunk of {} to {}: {:#}", uid, from, e);
+                    Message::TransferFailed(uid, format!("{:#}", e))
+                }
+            };
+            send(shared, from, reply);
+        }
+        Message::Manifest(uid, entries) => receive_manifest(shared, from, uid, entries),
+        Message::Chunk {
+            uid,
+            index,
+            offset,
+            data,
+        } => receive_chunk(shared, from, uid, index, offset, &data),
+        Message::TransferFailed(uid, reason) => {
+            let ours = lock(&shared.transfers)
+                .is_ok_and(|t| t.downloads.get(&uid).is_some_and(|d| d.device_id == from));
+            if ours {
+                fail(shared, uid, &reason);
+            }
+        }
+        other => log::warn!("{} is not a file transfer message", other.name()),
+    }
+}
+
+/// List the files behind an offered item, for a device it was offered to.
+fn manifest(shared: &Shared, from: &str, uid: Uuid) -> Result<Vec<FileEntry>, anyhow::Error> {
+    let paths = {
+        let transfers = lock(&shared.transfers)?;
+        let offer = find_offer(&transfers, from, uid)?;
+        if let Some(manifest) = &offer.manifest {
+            return Ok(manifest.iter().map(|(entry, _)| entry.clone()).collect());
+        }
+        offer.paths.clone()
+    };
+
+    let manifest = build_manifest(&paths)?;
+    if manifest.is_empty() {
+        return Err(anyhow!("none of the files are available any more"));
+    }
+    let total: u64 = manifest.iter().map(|(entry, _)| entry.size).sum();
+    let max_size = lock(&shared.trust)?.policy(from).and_then(|p| p.max_size);
+    if max_size.is_some_and(|max| total > max as u64) {
+        return Err(anyhow!(
+            "the files are larger than the sharing policy allows"
+        ));
+    }
+
+    let entries = manifest.iter().map(|(entry, _)| entry.clone()).collect();
+    let mut transfers = lock(&shared.transfers)?;
+    if let Some(offer) = transfers.offers.iter_mut().find(|o| o.uid == uid) {
+        offer.manifest = Some(manifest);
+    }
+    Ok(entries)
+}
+
+fn read_chunk(
+    shared: &Shared,
+    from: &str,
+    uid: Uuid,
+    index: u32,
+    offset: u64,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let source = {
+        let transfers = lock(&shared.transfers)?;
+        let offer = find_offer(&transfers, from, uid)?;
+        match offer.manifest.as_ref().and_then(|m| m.get(index as usize)) {
+            Some((entry, source)) if !entry.dir => source.clone(),
+            _ => return Err(anyhow!("there is no file {} in {}", index, uid)),
+        }
+    };
+    let mut file =
+        File::open(&source).with_context(|| format!("failed to open {}", source.display()))?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    file.take(CHUNK_LEN)
+        .read_to_end(&mut data)
+        .with_context(|| format!("failed to read {}", source.display()))?;
+    Ok(data)
+}
+
+fn find_offer<'a>(
+    transfers: &'a Transfers,
+    from: &str,
+    uid: Uuid,
+) -> Result<&'a Offer, anyhow::Error> {
+    transfers
+        .offers
+        .iter()
+        .find(|o| o.uid == uid && o.devices.contains(from))
+        .ok_or_else(|| anyhow!("the files of {} are no longer shared", uid))
+}
+
+fn receive_manifest(shared: &Shared, from: &str, uid: Uuid, entries: Vec<FileEntry>) {
+    let paths = entries
+        .iter()
+        .map(|e| local_path(&e.path))
+        .collect::<Option<Vec<_>>>();
+    let total: u64 = entries.iter().map(|e| e.size).sum();
+    let max_size = lock(&shared.trust)
+        .ok()
+        .and_then(|t| t.policy(from).and_then(|p| p.max_size));
+    let confirm_above = shared
+        .app
+        .state::<Mutex<Settings>>()
+        .lock()
+        .map(|s| s.confirm_transfer_size)
+        .unwrap_or_default();
+
+    let error = match &paths {
+        None => Some("the sender listed a file outside the transfer"),
+        Some(_) if entries.is_empty() => Some("the sender listed no files"),
+        Some(_) if max_size.is_some_and(|max| total > max as u64) => {
+            Some("the files are larger than the sharing policy allows")
+        }
+        Some(_) => None,
+    };
+
+    {
+        let Ok(mut transfers) = lock(&shared.transfers) else {
+            return;
+        };
+        let Some(download) = transfers.downloads.get_mut(&uid) else {
+            return;
+        };
+        if download.device_id != from || !download.entries.is_empty() {
+            return;
+        }
+        if error.is_none() {
+            download.entries = entries;
+            download.paths = paths.unwrap_or_default();
+            download.total = total;
+            if total > confirm_above {
+                download.awaiting_confirm = true;
+                download.emit(shared, uid, TransferState::Confirm, None);
+                return;
+            }
+        }
+    }
+
+    match error {
+        Some(reason) => fail(shared, uid, reason),
+        None => advance(shared, uid),
+    }
+}
+
+fn receive_chunk(shared: &Shared, from: &str, uid: Uuid, index: u32, offset: u64, data: &[u8]) {
+    let written = {
+        let Ok(mut transfers) = lock(&shared.transfers) else {
+            return;
+        };
+        let Some(download) = transfers.downloads.get_mut(&uid) else {
+            return;
+        };
+        if download.device_id != from
+            || download.awaiting_confirm
+            || download.index != index as usize
+            || download.offset != offset
+        {
+            log::debug!("ignoring stale chunk {}@{} of {}", index, offset, uid);
+            return;
+        }
+        download.write_chunk(offset, data)
+    };
+    match written {
+        Ok(()) => advance(shared, uid),
+        Err(e) => fail(shared, uid, &format!("{:#}", e)),
+    }
+}
+
+/// Ask for the next chunk of a download, or finish it once every file is here.
+fn advance(shared: &Shared, uid: Uuid) {
+    let Ok(mut transfers) = lock(&shared.transfers) else {
+        return;
+    };
+    let Some(download) = transfers.downloads.get_mut(&uid) else {
+        return;
+    };
+    match download.next_request(uid) {
+        Ok(Some(request)) => {
+            download.emit(shared, uid, TransferState::Running, None);
+            let device_id = download.device_id.clone();
+            drop(transfers);
+            send(shared, &device_id, request);
+        }
+        Ok(None) => {
+            let download = transfers.downloads.remove(&uid);
+            drop(transfers);
+            if let Some(download) = download {
+                finish(shared, uid, download);
+            }
+        }
+        Err(e) => {
+            drop(transfers);
+            fail(shared, uid, &format!("{:#}", e));
+        }
+    }
+}
+
+/// Move the downloaded files into the downloads folder and add the item, pointing at them, to history.
+fn finish(shared: &Shared, uid: Uuid, download: Download) {
+    let moved = download
+        .entries
+        .iter()
+        .zip(&download.paths)
+        .filter(|(entry, _)| !entry.path.contains('/'))
+        .map(|(entry, path)| {
+            let target = unique_path(&download.downloads, &entry.path);
+            fs::rename(download.staging.join(path), &target).with_context(|| {
+                format!("failed to move {} to {}", entry.path, target.display())
+            })?;
+            Ok(target)
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>();
+    _ = fs::remove_dir_all(&download.staging);
+
+    match moved {
+        Ok(paths) => {
+            log::info!("received {} from {}", download.name, download.device_id);
+            download.emit(shared, uid, TransferState::Done, None);
+            let item = Contents::from_paths(paths).with_meta(download.item.meta().clone());
+            super::deliver_item(shared, &download.device_id, Arc::new(item), download.pushed);
+        }
+        Err(e) => {
+            log::error!("Could not save {}: {:#}", download.name, e);
+            download.emit(shared, uid, TransferState::Failed, Some(format!("{:#}", e)));
+        }
+    }
+}
+
+fn fail(shared: &Shared, uid: Uuid, reason: &str) {
+    let download = lock(&shared.transfers)
+        .ok()
+        .and_then(|mut t| t.downloads.remove(&uid));
+    if let Some(download) = download {
+        log::warn!(
+            "transfer of {} from {} failed: {}",
+            download.name,
+            download.device_id,
+            reason
+        );
+        _ = fs::remove_dir_all(&download.staging);
+        download.emit(shared, uid, TransferState::Failed, Some(reason.to_string()));
+    }
+}
+
+fn send(shared: &Shared, device_id: &str, msg: Message) {
+    let sent =
+        lock(&shared.links).is_ok_and(|links| links.get(device_id).is_some_and(|l| l.send(msg)));
+    if !sent {
+        log::debug!("{} is not connected, transfer paused", device_id);
+    }
+}
+
+fn downloads_dir(shared: &Shared) -> Result<PathBuf, anyhow::Error> {
+    let configured = shared
+        .app
+        .state::<Mutex<Settings>>()
+        .lock()
+        .map_err(|e| anyhow!("settings lock was poisoned: {}", e))?
+        .downloads_dir
+        .clone();
+    match configured {
+        Some(dir) => Ok(dir),
+        None => shared
+            .app
+            .path()
+            .download_dir()
+            .with_context(|| "failed to get the downloads folder"),
+    }
+}
+
+/// List every file and directory under `paths`, directories before their contents. Symlinks are skipped.
+fn build_manifest(paths: &[PathBuf]) -> Result<Vec<(FileEntry, PathBuf)>, anyhow::Error> {
+    let mut manifest = vec![];
+    let mut names = HashSet::new();
+    for path in paths {
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        let name = name.to_string_lossy().into_owned();
+        // two paths with the same name from different folders
+        let name = (0..)
+            .map(|n| match n {
+                0 => name.clone(),
+                n => format!("{} ({})", name, n),
+            })
+            .find(|n| !names.contains(n))
+            .unwrap();
+        names.insert(name.clone());
+        add_entry(path, name, &mut manifest)?;
+    }
+    Ok(manifest)
+}
+
+fn add_entry(
+    source: &Path,
+    path: String,
+    manifest: &mut Vec<(FileEntry, PathBuf)>,
+) -> Result<(), anyhow::Error> {
+    let metadata = match fs::symlink_metadata(source) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            log::warn!("{} no longer exists, leaving it out", source.display());
+            return Ok(());
+        }
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", source.display())),
+    };
+
+    if metadata.is_dir() {
+        manifest.push((
+            FileEntry {
+                path: path.clone(),
+                dir: true,
+                size: 0,
+                hash: [0; 32],
+            },
+            source.to_path_buf(),
+        ));
+        let mut children = fs::read_dir(source)
+            .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("failed to list {}", source.display()))?;
+        children.sort_by_key(|c| c.file_name());
+        for child in children {
+            let child_path = format!("{}/{}", path, child.file_name().to_string_lossy());
+            add_entry(&child.path(), child_path, manifest)?;
+        }
+    } else if metadata.is_file() {
+        manifest.push((
+            FileEntry {
+                path,
+                dir: false,
+                size: metadata.len(),
+                hash: hash_file(source)?,
+            },
+            source.to_path_buf(),
+        ));
+    } else {
+        log::debug!(
+            "leaving out {}, it is not a file or directory",
+            source.display()
+        );
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> Result<[u8; 32], anyhow::Error> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(hasher.finalize().into())
+}
+
+/// Turn a `/` separated path from a manifest into a relative local one, refusing anything that could
+/// point outside the folder the transfer is saved in.
+fn local_path(path: &str) -> Option<PathBuf> {
+    let mut local = PathBuf::new();
+    for part in path.split('/') {
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => local.push(name),
+            _ => return None,
+        }
+    }
+    Some(local)
+}
+
+/// `dir/name`, or `dir/name (1).ext` and so on if that is taken.
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name);
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name.extension().map(|e| e.to_string_lossy());
+    (0..)
+        .map(|n| match (n, &extension) {
+            (0, _) => dir.join(name),
+            (n, Some(extension)) => dir.join(format!("{} ({}).{}", stem, n, extension)),
+            (n, None) => dir.join(format!("{} ({})", stem, n)),
+        })
+        .find(|p| !p.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download(size: u64) -> Download {
+        let staging = std::env::temp_dir().join(format!("purple-clip-test-{}", Uuid::new_v4()));
+        Download {
+            device_id: "sender".into(),
+            item: Arc::new(Contents::from_paths(vec![PathBuf::from("file")])),
+            pushed: false,
+            name: "file".into(),
+            downloads: staging.clone(),
+            staging,
+            entries: vec![FileEntry {
+                path: "file".into(),
+                dir: false,
+                size,
+                hash: [0; 32],
+            }],
+            paths: vec![PathBuf::from("file")],
+            awaiting_confirm: false,
+            index: 0,
+            offset: 0,
+            done: 0,
+            total: size,
+        }
+    }
+
+    #[test]
+    fn chunks_past_the_end_are_rejected() {
+        let mut download = download(10);
+        download.write_chunk(0, &[1; 6]).unwrap();
+        assert!(download.write_chunk(6, &[1; 5]).is_err());
+        assert!(download.write_chunk(u64::MAX, &[1]).is_err());
+        download.write_chunk(6, &[1; 4]).unwrap();
+        let path = download.staging.join("file");
+        assert_eq!(fs::metadata(&path).unwrap().len(), 10);
+        assert!(download.write_chunk(10, &[1]).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 10);
+        _ = fs::remove_dir_all(&download.staging);
+    }
+}
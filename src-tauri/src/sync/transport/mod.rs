@@ -0,0 +1,388 @@
+//! How bytes get to another device. Everything above this, the Noise handshake included, runs the same over any
+//! transport. Each device advertises the transports it listens on, and the side that dials picks the first one in
+//! `PREFERENCE` that both devices have, falling back to the next one if it can't connect.
+//!
+//! That is all the negotiation there is, and it happens before any connection is made: a device accepts on every
+//! transport it advertises, so whichever one the dialer picks is good for both sides, and the handshake only
+//! starts once a transport is up.
+
+mod quic;
+mod tcp;
+#[cfg(unix)]
+mod unix;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::discovery::Peer;
+
+pub use tcp::connect as connect_tcp;
+
+/// Local sockets only reach other instances on the same machine, QUIC copes better than TCP with lossy Wi-Fi
+/// and devices changing networks, and TCP works everywhere.
+const PREFERENCE: [TransportKind; 3] =
+    [TransportKind::Unix, TransportKind::Quic, TransportKind::Tcp];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    Tcp,
+    Quic,
+    Unix,
+}
+
+impl TransportKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            TransportKind::Tcp => "tcp",
+            TransportKind::Quic => "quic",
+            TransportKind::Unix => "unix",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        PREFERENCE.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+/// An open connection to another device, over any transport.
+pub trait Connection: Read + Write + Send {
+    /// Another handle to the same connection, so one thread can read while another writes.
+    fn try_clone(&self) -> io::Result<Box<dyn Connection>>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Close the connection, which makes reads and writes through every handle to it fail.
+    fn shutdown(&self);
+    fn kind(&self) -> TransportKind;
+    /// The other end of the connection, for logging.
+    fn remote(&self) -> String;
+}
+
+/// A way of reaching other devices and being reached by them.
+pub trait Transport: Send + Sync {
+    fn kind(&self) -> TransportKind;
+    /// Wait for the next connection from another device.
+    fn accept(&self) -> io::Result<Box<dyn Connection>>;
+    /// Connect to a device found on the network, with reads timing out after `timeout` until the handshake is done.
+    fn connect(&self, peer: &Peer, timeout: Duration)
+        -> Result<Box<dyn Connection>, anyhow::Error>;
+}
+
+/// Every transport this device listens on.
+pub struct Transports {
+    listening: Vec<Arc<dyn Transport>>,
+    port: u16,
+    socket: Option<PathBuf>,
+}
+
+impl Transports {
+    /// Listen on TCP, and on QUIC and a local socket where possible. QUIC listens on the UDP port with the same
+    /// number as the TCP one, so one port is all there is to advertise or open in a firewall.
+    pub fn bind(data_dir: &Path, port: u16) -> Result<Self, anyhow::Error> {
+        let tcp = tcp::TcpTransport::bind(port)?;
+        let port = tcp.port();
+        let mut listening: Vec<Arc<dyn Transport>> = vec![Arc::new(tcp)];
+
+        match quic::QuicTransport::bind(port) {
+            Ok(quic) => listening.push(Arc::new(quic)),
+            Err(e) => log::warn!("Not accepting connections over QUIC: {:#}", e),
+        }
+
+        #[cfg(unix)]
+        let socket = match unix::UnixTransport::bind(&data_dir.join("sync.sock")) {
+            Ok(unix) => {
+                let path = unix.path().to_path_buf();
+                listening.push(Arc::new(unix));
+                Some(path)
+            }
+            Err(e) => {
+                log::warn!("Not accepting connections over a local socket: {:#}", e);
+                None
+            }
+        };
+        #[cfg(not(unix))]
+        let socket = {
+            _ = data_dir;
+            None
+        };
+
+        listening.sort_by_key(|t| rank(t.kind()));
+        Ok(Self {
+            listening,
+            port,
+            socket,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn kinds(&self) -> Vec<TransportKind> {
+        self.listening.iter().map(|t| t.kind()).collect()
+    }
+
+    pub fn socket(&self) -> Option<&Path> {
+        self.socket.as_deref()
+    }
+
+    pub fn listening(&self) -> impl Iterator<Item = &Arc<dyn Transport>> {
+        self.listening.iter()
+    }
+
+    /// Connect to a device over the most preferred transport we both have that works, going by the transports it
+    /// advertised.
+    pub fn connect(
+        &self,
+        peer: &Peer,
+        timeout: Duration,
+    ) -> Result<Box<dyn Connection>, anyhow::Error> {
+        let mut last_error = None;
+        for transport in self
+            .listening
+            .iter()
+            .filter(|t| peer.transports.contains(&t.kind()))
+        {
+            match transport.connect(peer, timeout) {
+                Ok(connection) => return Ok(connection),
+                Err(e) => {
+                    log::debug!(
+                        "could not reach {} over {}: {:#}",
+                        peer.name,
+                        transport.kind().name(),
+                        e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| anyhow!("{} has no transport in common with us", peer.name)))
+    }
+}
+
+fn rank(kind: TransportKind) -> usize {
+    PREFERENCE
+        .iter()
+        .position(|k| *k == kind)
+        .unwrap_or(PREFERENCE.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contents::Contents;
+    use crate::sync::identity::Identity;
+    use crate::sync::protocol::{self, Capabilities, Hello, Intent, Message, PROTOCOL_VERSION};
+    use crate::sync::secure::SecureStream;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::thread;
+    use uuid::Uuid;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// An instance with a data dir of its own, listening on every transport it can.
+    struct Instance {
+        dir: PathBuf,
+        identity: Identity,
+        transports: Transports,
+    }
+
+    impl Instance {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("purple-clip-test-{}", Uuid::new_v4()));
+            let identity = Identity::load_or_create(&dir).unwrap();
+            let transports = Transports::bind(&dir, 0).unwrap();
+            Self {
+                dir,
+                identity,
+                transports,
+            }
+        }
+
+        fn hello(&self) -> Hello {
+            Hello {
+                version: PROTOCOL_VERSION,
+                intent: Intent::Sync,
+                device_id: self.identity.device_id(),
+                public_key: self.identity.public.clone(),
+                name: tauri_plugin_os::hostname(),
+                os: "test".into(),
+                capabilities: Capabilities::ALL,
+                signing_key: self
+                    .identity
+                    .signing_key()
+                    .verifying_key()
+                    .to_bytes()
+                    .to_vec(),
+            }
+        }
+
+        /// How another instance on this machine would find this one, with only `transports` advertised.
+        fn peer(&self, transports: &[TransportKind]) -> Peer {
+            Peer {
+                id: self.identity.device_id(),
+                name: tauri_plugin_os::hostname(),
+                os: "test".into(),
+                version: PROTOCOL_VERSION,
+                addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+                port: self.transports.port(),
+                static_address: None,
+                transports: transports.to_vec(),
+                socket: self.transports.socket().map(Path::to_path_buf),
+            }
+        }
+
+        fn transport(&self, kind: TransportKind) -> Arc<dyn Transport> {
+            Arc::clone(
+                self.transports
+                    .listening()
+                    .find(|t| t.kind() == kind)
+                    .unwrap(),
+            )
+        }
+    }
+
+    impl Drop for Instance {
+        fn drop(&mut self) {
+            _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn item(text: &str) -> Message {
+        Message::Item(Arc::new(Contents::from_text(text.into())))
+    }
+
+    fn text(msg: Message) -> String {
+        match msg {
+            Message::Item(item) => match item.as_ref() {
+                Contents::Text { text, .. } => text.clone(),
+                _ => panic!("not a text item"),
+            },
+            other => panic!("unexpected {}", other.name()),
+        }
+    }
+
+    /// Connect two instances over `kind` and run a sync connection the way links do: the Noise handshake, hellos,
+    /// then both sides sending items at the same time, one of them larger than a Noise message.
+    fn sync_over(kind: TransportKind) {
+        let server = Instance::new();
+        let client = Instance::new();
+        let listener = server.transport(kind);
+        let server_hello = server.hello();
+        let server_key = server.identity.public.clone();
+        let accepted = thread::spawn({
+            let identity = Identity {
+                private: server.identity.private.clone(),
+                public: server.identity.public.clone(),
+            };
+            move || {
+                let connection = listener.accept().unwrap();
+                assert_eq!(connection.kind(), kind);
+                connection.set_read_timeout(Some(TIMEOUT)).unwrap();
+                let stream = SecureStream::respond(connection, &identity).unwrap();
+                exchange(stream, &server_hello, "from server")
+            }
+        });
+
+        let connection = client
+            .transports
+            .connect(&server.peer(&[kind]), TIMEOUT)
+            .unwrap();
+        assert_eq!(connection.kind(), kind);
+        let stream = SecureStream::initiate(connection, &client.identity).unwrap();
+        assert_eq!(stream.remote_key(), server_key);
+        let (remote, received) = exchange(stream, &client.hello(), "from client");
+        assert_eq!(remote.device_id, server.identity.device_id());
+        assert_eq!(received, expected("from server"));
+
+        let (remote, received) = accepted.join().unwrap();
+        assert_eq!(remote.device_id, client.identity.device_id());
+        assert_eq!(received, expected("from client"));
+    }
+
+    fn expected(prefix: &str) -> Vec<String> {
+        let mut texts: Vec<_> = (0..20).map(|i| format!("{prefix} {i}")).collect();
+        texts.push(prefix.repeat(100_000));
+        texts
+    }
+
+    /// Send `expected(prefix)` while reading what the other side sends, and hand back what was read.
+    fn exchange(mut stream: SecureStream, hello: &Hello, prefix: &str) -> (Hello, Vec<String>) {
+        let (remote, _) = protocol::handshake(&mut stream, hello).unwrap();
+        assert_eq!(remote.public_key, stream.remote_key());
+        let mut reader = stream.try_clone().unwrap();
+        let reading = thread::spawn(move || {
+            (0..21)
+                .map(|_| text(protocol::read_message(&mut reader).unwrap()))
+                .collect::<Vec<_>>()
+        });
+        for text in expected(prefix) {
+            protocol::write_message(&mut stream, &item(&text)).unwrap();
+        }
+        let received = reading.join().unwrap();
+        stream.stream().shutdown();
+        (remote, received)
+    }
+
+    #[test]
+    fn sync_over_tcp() {
+        sync_over(TransportKind::Tcp);
+    }
+
+    #[test]
+    fn sync_over_quic() {
+        sync_over(TransportKind::Quic);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sync_over_unix_socket() {
+        sync_over(TransportKind::Unix);
+    }
+
+    #[test]
+    fn listens_on_every_transport_in_order() {
+        let instance = Instance::new();
+        let mut expected = vec![TransportKind::Quic, TransportKind::Tcp];
+        if cfg!(unix) {
+            expected.insert(0, TransportKind::Unix);
+        }
+        assert_eq!(instance.transports.kinds(), expected);
+    }
+
+    #[test]
+    fn dials_the_preferred_transport() {
+        let server = Instance::new();
+        let client = Instance::new();
+        let peer = server.peer(&[TransportKind::Tcp, TransportKind::Quic]);
+        let connection = client.transports.connect(&peer, TIMEOUT).unwrap();
+        assert_eq!(connection.kind(), TransportKind::Quic);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn falls_back_to_the_next_transport() {
+        let server = Instance::new();
+        let client = Instance::new();
+        let mut peer = server.peer(&[TransportKind::Unix, TransportKind::Tcp]);
+        peer.socket = Some(server.dir.join("gone.sock"));
+        let connection = client.transports.connect(&peer, TIMEOUT).unwrap();
+        assert_eq!(connection.kind(), TransportKind::Tcp);
+    }
+
+    #[test]
+    fn no_transport_in_common() {
+        let server = Instance::new();
+        let client = Instance::new();
+        let e = client
+            .transports
+            .connect(&server.peer(&[]), TIMEOUT)
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("no transport in common"), "{e:#}");
+    }
+}
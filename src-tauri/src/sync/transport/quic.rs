@@ -0,0 +1,283 @@
+use anyhow::{anyhow, Context};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use quinn::rustls::crypto::{self, CryptoProvider};
+use quinn::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use quinn::rustls::{self, DigitallySignedStruct, SignatureScheme};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tauri::async_runtime::{block_on, spawn};
+
+use super::{Connection, Transport, TransportKind};
+use crate::sync::discovery::Peer;
+use crate::sync::{CONNECT_TIMEOUT, HANDSHAKE_TIMEOUT};
+
+/// Name the self-signed certificate is made out to. Nothing checks it: who is on the other end is proven by the
+/// Noise handshake run over the QUIC stream like over any other transport, so TLS only has to get the stream up.
+const SERVER_NAME: &str = "purple-clip";
+/// Longer than the link heartbeat timeout, so a dead link is noticed by the heartbeats like on other transports.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// QUIC on the async runtime, with blocking reads and writes so links can use it from their own threads like a
+/// socket. Each connection carries a single bidirectional stream.
+pub struct QuicTransport {
+    endpoint: quinn::Endpoint,
+    client: quinn::ClientConfig,
+    incoming: Mutex<mpsc::Receiver<QuicConnection>>,
+}
+
+impl QuicTransport {
+    pub fn bind(port: u16) -> Result<Self, anyhow::Error> {
+        let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])
+            .context("failed to make a certificate")?;
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+        let mut server = quinn::ServerConfig::with_single_cert(
+            vec![CertificateDer::from(certified.cert)],
+            key.into(),
+        )?;
+        server.transport_config(transport_config());
+
+        let address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port);
+        // the endpoint has to be made inside the runtime it runs on
+        let endpoint = block_on(async { quinn::Endpoint::server(server, address) })
+            .with_context(|| format!("failed to bind QUIC endpoint on UDP port {}", port))?;
+
+        let (tx, rx) = mpsc::channel();
+        let accepting = endpoint.clone();
+        spawn(async move {
+            while let Some(incoming) = accepting.accept().await {
+                let tx = tx.clone();
+                spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, accept(incoming)).await {
+                        Ok(Ok(connection)) => _ = tx.send(connection),
+                        Ok(Err(e)) => log::warn!("Could not accept QUIC connection: {:#}", e),
+                        Err(_) => log::warn!("QUIC connection timed out before opening a stream"),
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            endpoint,
+            client: client_config()?,
+            incoming: Mutex::new(rx),
+        })
+    }
+
+    async fn open(&self, address: SocketAddr) -> Result<QuicConnection, anyhow::Error> {
+        let connection = self
+            .endpoint
+            .connect_with(self.client.clone(), address, SERVER_NAME)?
+            .await?;
+        let (send, recv) = connection.open_bi().await?;
+        Ok(QuicConnection::new(connection, send, recv))
+    }
+}
+
+impl Transport for QuicTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Quic
+    }
+
+    fn accept(&self) -> io::Result<Box<dyn Connection>> {
+        let connection = lock(&self.incoming)?
+            .recv()
+            .map_err(|_| io::Error::other("QUIC endpoint closed"))?;
+        Ok(Box::new(connection))
+    }
+
+    fn connect(
+        &self,
+        peer: &Peer,
+        timeout: Duration,
+    ) -> Result<Box<dyn Connection>, anyhow::Error> {
+        let mut last_error = None;
+        for address in &peer.addresses {
+            let address = SocketAddr::new(*address, peer.port);
+            // the timer has to be made inside the runtime too
+            match block_on(async {
+                tokio::time::timeout(CONNECT_TIMEOUT, self.open(address)).await
+            }) {
+                Ok(Ok(connection)) => {
+                    connection.set_read_timeout(Some(timeout))?;
+                    return Ok(Box::new(connection));
+                }
+                Ok(Err(e)) => last_error = Some(e),
+                Err(_) => last_error = Some(anyhow!("timed out")),
+            }
+        }
+        Err(match last_error {
+            Some(e) => anyhow!("could not connect to {} over QUIC: {:#}", peer.name, e),
+            None => anyhow!("{} has no known addresses", peer.name),
+        })
+    }
+}
+
+impl Drop for QuicTransport {
+    fn drop(&mut self) {
+        self.endpoint.close(0u32.into(), b"shutting down");
+    }
+}
+
+async fn accept(incoming: quinn::Incoming) -> Result<QuicConnection, anyhow::Error> {
+    let connection = incoming.await?;
+    // the stream only shows up here once the other side writes to it, which the Noise initiator does first
+    let (send, recv) = connection.accept_bi().await?;
+    Ok(QuicConnection::new(connection, send, recv))
+}
+
+#[derive(Clone)]
+struct QuicConnection {
+    connection: quinn::Connection,
+    send: Arc<Mutex<quinn::SendStream>>,
+    recv: Arc<Mutex<quinn::RecvStream>>,
+    /// Shared by every handle, like a socket option.
+    read_timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+impl QuicConnection {
+    fn new(
+        connection: quinn::Connection,
+        send: quinn::SendStream,
+        recv: quinn::RecvStream,
+    ) -> Self {
+        Self {
+            connection,
+            send: Arc::new(Mutex::new(send)),
+            recv: Arc::new(Mutex::new(recv)),
+            read_timeout: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Read for QuicConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *lock(&self.read_timeout)?;
+        let mut recv = lock(&self.recv)?;
+        let read = block_on(async {
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, recv.read(buf)).await.ok(),
+                None => Some(recv.read(buf).await),
+            }
+        });
+        match read {
+            Some(read) => Ok(read?.unwrap_or(0)),
+            None => Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out")),
+        }
+    }
+}
+
+impl Write for QuicConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut send = lock(&self.send)?;
+        block_on(send.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for QuicConnection {
+    fn try_clone(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *lock(&self.read_timeout)? = timeout;
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        self.connection.close(0u32.into(), b"closed");
+    }
+
+    fn kind(&self) -> TransportKind {
+        TransportKind::Quic
+    }
+
+    fn remote(&self) -> String {
+        self.connection.remote_address().to_string()
+    }
+}
+
+fn transport_config() -> Arc<quinn::TransportConfig> {
+    let mut config = quinn::TransportConfig::default();
+    config
+        .max_idle_timeout(IDLE_TIMEOUT.try_into().ok())
+        .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    Arc::new(config)
+}
+
+fn client_config() -> Result<quinn::ClientConfig, anyhow::Error> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let tls = rustls::ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+/// Takes any certificate, see `SERVER_NAME`. Signatures are still checked, so the TLS handshake itself is sound.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        _: &[u8],
+        _: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> io::Result<MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| io::Error::other("QUIC connection lock was poisoned"))
+}
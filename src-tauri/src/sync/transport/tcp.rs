@@ -0,0 +1,89 @@
+use anyhow::{anyhow, Context};
+use std::io;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+use super::{Connection, Transport, TransportKind};
+use crate::sync::discovery::Peer;
+use crate::sync::CONNECT_TIMEOUT;
+
+pub struct TcpTransport {
+    listener: TcpListener,
+}
+
+impl TcpTransport {
+    /// Listen on every interface, on a random free port if `port` is 0.
+    pub fn bind(port: u16) -> Result<Self, anyhow::Error> {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))
+            .with_context(|| format!("failed to bind sync listener on port {}", port))?;
+        Ok(Self { listener })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.listener
+            .local_addr()
+            .map(|a| a.port())
+            .unwrap_or_default()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Tcp
+    }
+
+    fn accept(&self) -> io::Result<Box<dyn Connection>> {
+        let (stream, _) = self.listener.accept()?;
+        Ok(Box::new(stream))
+    }
+
+    fn connect(
+        &self,
+        peer: &Peer,
+        timeout: Duration,
+    ) -> Result<Box<dyn Connection>, anyhow::Error> {
+        Ok(Box::new(connect(peer, timeout)?))
+    }
+}
+
+/// Connect to the first of a device's addresses that answers.
+pub fn connect(peer: &Peer, timeout: Duration) -> Result<TcpStream, anyhow::Error> {
+    let mut last_error = None;
+    for address in &peer.addresses {
+        match TcpStream::connect_timeout(&SocketAddr::new(*address, peer.port), CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(match last_error {
+        Some(e) => anyhow!("could not connect to {}: {}", peer.name, e),
+        None => anyhow!("{} has no known addresses", peer.name),
+    })
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) {
+        _ = TcpStream::shutdown(self, Shutdown::Both);
+    }
+
+    fn kind(&self) -> TransportKind {
+        TransportKind::Tcp
+    }
+
+    fn remote(&self) -> String {
+        self.peer_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_else(|e| e.to_string())
+    }
+}
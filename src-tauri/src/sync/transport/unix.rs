@@ -0,0 +1,94 @@
+use anyhow::{anyhow, Context};
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{Connection, Transport, TransportKind};
+use crate::sync::discovery::Peer;
+
+/// Connections between instances on the same machine, each with its own data directory, without going through
+/// the network stack. Handy for running several instances side by side while testing.
+pub struct UnixTransport {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixTransport {
+    pub fn bind(path: &Path) -> Result<Self, anyhow::Error> {
+        // left behind by an instance that didn't exit cleanly
+        if path.exists() && UnixStream::connect(path).is_err() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("failed to remove stale socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("failed to bind sync socket {}", path.display()))?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Transport for UnixTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Unix
+    }
+
+    fn accept(&self) -> io::Result<Box<dyn Connection>> {
+        let (stream, _) = self.listener.accept()?;
+        Ok(Box::new(stream))
+    }
+
+    /// Only tried for devices with our host name, the socket path means nothing on another machine.
+    fn connect(
+        &self,
+        peer: &Peer,
+        timeout: Duration,
+    ) -> Result<Box<dyn Connection>, anyhow::Error> {
+        let path = peer
+            .socket
+            .as_ref()
+            .ok_or_else(|| anyhow!("{} has no local socket", peer.name))?;
+        if peer.name != tauri_plugin_os::hostname() {
+            return Err(anyhow!("{} is on another machine", peer.name));
+        }
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("could not connect to {}", path.display()))?;
+        stream.set_read_timeout(Some(timeout))?;
+        Ok(Box::new(stream))
+    }
+}
+
+impl Drop for UnixTransport {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Connection for UnixStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) {
+        _ = UnixStream::shutdown(self, Shutdown::Both);
+    }
+
+    fn kind(&self) -> TransportKind {
+        TransportKind::Unix
+    }
+
+    fn remote(&self) -> String {
+        "local socket".to_string()
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::AppHandle;
+use tauri_plugin_store::StoreExt;
+
+use super::policy::SharingPolicy;
+
+const TRUST_STORE: &str = "trusted.json";
+
+/// A device we have paired with, and the long-term key it proved during pairing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedDevice {
+    pub id: String,
+    pub name: String,
+    pub os: String,
+    pub public_key: Vec<u8>,
+    /// The key its revocations are signed with, empty until it connects with a version that sends one.
+    #[serde(default)]
+    pub signing_key: Vec<u8>,
+    pub paired_at: u64,
+    #[serde(default)]
+    pub policy: SharingPolicy,
+    /// Don't show notifications for items from this device.
+    #[serde(default)]
+    pub muted: bool,
+}
+
+impl TrustedDevice {
+    pub fn new(
+        id: String,
+        name: String,
+        os: String,
+        public_key: Vec<u8>,
+        signing_key: Vec<u8>,
+    ) -> Self {
+        let paired_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self {
+            id,
+            name,
+            os,
+            public_key,
+            signing_key,
+            paired_at,
+            policy: SharingPolicy::default(),
+            muted: false,
+        }
+    }
+}
+
+/// Devices allowed to connect to us, persisted in `trusted.json` next to the pinned items store.
+pub struct TrustStore {
+    app: AppHandle,
+    devices: HashMap<String, TrustedDevice>,
+}
+
+impl TrustStore {
+    pub fn load(app: &AppHandle) -> Result<Self, anyhow::Error> {
+        let store = app
+            .store(TRUST_STORE)
+            .with_context(|| "failed to get or create trust store")?;
+
+        let devices = match store.get("devices") {
+            Some(value) => serde_json::from_value::<Vec<TrustedDevice>>(value)
+                .with_context(|| "trust store contents were malformed")?,
+            None => vec![],
+        };
+        store.close_resource();
+
+        Ok(Self {
+            app: app.clone(),
+            devices: devices.into_iter().map(|d| (d.id.clone(), d)).collect(),
+        })
+    }
+
+    /// A device is trusted only if both its id and the key it presented match what was paired.
+    pub fn is_trusted(&self, id: &str, public_key: &[u8]) -> bool {
+        self.devices
+            .get(id)
+            .is_some_and(|d| d.public_key == public_key)
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.devices.contains_key(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TrustedDevice> {
+        self.devices.values()
+    }
+
+    pub fn list(&self) -> Vec<TrustedDevice> {
+        let mut devices = self.devices.values().cloned().collect::<Vec<_>>();
+        devices.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        devices
+    }
+
+    /// Sharing policy for a trusted device, `None` if it isn't trusted.
+    pub fn policy(&self, id: &str) -> Option<&SharingPolicy> {
+        self.devices.get(id).map(|d| &d.policy)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&TrustedDevice> {
+        self.devices.get(id)
+    }
+
+    /// Re-pairing a device keeps the sharing policy and mute it already had.
+    pub fn add(&mut self, mut device: TrustedDevice) -> Result<(), anyhow::Error> {
+        log::info!("trusting device {} ({})", device.name, device.id);
+        if let Some(old) = self.devices.get(&device.id) {
+            device.policy = old.policy.clone();
+            device.muted = old.muted;
+        }
+        self.devices.insert(device.id.clone(), device);
+        self.save()
+    }
+
+    pub fn set_policy(&mut self, id: &str, policy: SharingPolicy) -> Result<bool, anyhow::Error> {
+        let Some(device) = self.devices.get_mut(id) else {
+            return Ok(false);
+        };
+        log::info!("sharing policy for {} is now {:?}", device.name, policy);
+        device.policy = policy;
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn set_muted(&mut self, id: &str, muted: bool) -> Result<bool, anyhow::Error> {
+        let Some(device) = self.devices.get_mut(id) else {
+            return Ok(false);
+        };
+        log::info!(
+            "notifications from {} are now {}",
+            device.name,
+            if muted { "muted" } else { "on" }
+        );
+        device.muted = muted;
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Remember the signing key a device sent in its hello. Devices paired before it had one send it later.
+    pub fn set_signing_key(&mut self, id: &str, key: &[u8]) -> Result<(), anyhow::Error> {
+        let Some(device) = self.devices.get_mut(id) else {
+            return Ok(());
+        };
+        if device.signing_key == key {
+            return Ok(());
+        }
+        device.signing_key = key.to_vec();
+        self.save()
+    }
+
+    pub fn remove(&mut self, id: &str) -> Result<bool, anyhow::Error> {
+        if self.devices.remove(id).is_none() {
+            return Ok(false);
+        }
+        log::info!("forgot device {}", id);
+        self.save()?;
+        Ok(true)
+    }
+
+    fn save(&self) -> Result<(), anyhow::Error> {
+        let store = self
+            .app
+            .store(TRUST_STORE)
+            .with_context(|| "failed to get trust store")?;
+        store.set("devices", serde_json::to_value(self.list())?);
+        Ok(())
+    }
+}
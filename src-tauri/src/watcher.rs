@@ -16,10 +16,9 @@ impl Watcher {
             handle: app.clone(),
         }
     }
-}
 
-impl ClipboardHandler for Watcher {
-    fn on_clipboard_change(&mut self) -> CallbackResult {
+    /// Have the clipboard manager take in what was just copied.
+    pub fn changed(&self) {
         let state = self.handle.state::<Mutex<Option<ClipboardManager>>>();
         match state.lock() {
             Ok(mut manager) => {
@@ -29,6 +28,12 @@ impl ClipboardHandler for Watcher {
                 log::error!("Couldn't access clipboard manager: {}", e);
             }
         };
+    }
+}
+
+impl ClipboardHandler for Watcher {
+    fn on_clipboard_change(&mut self) -> CallbackResult {
+        self.changed();
         CallbackResult::Next
     }
 
@@ -0,0 +1,345 @@
+//! Clipboard access on Wayland through the data control protocols, `ext-data-control-v1` or the older
+//! `wlr-data-control`, which let a client with no focused window see and set the clipboard. clipboard-master and GTK
+//! only manage that through XWayland, which misses changes made by Wayland apps while we're in the background.
+//!
+//! This covers watching for changes and file paths; text and images go through the clipboard plugin, which uses the
+//! same protocols when it finds them.
+
+use anyhow::Context;
+use std::sync::OnceLock;
+use wayland_client::globals::{registry_queue_init, GlobalListContents};
+use wayland_client::protocol::wl_registry::WlRegistry;
+use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_client::{event_created_child, Connection, Dispatch, Proxy, QueueHandle};
+use wayland_protocols::ext::data_control::v1::client::ext_data_control_device_v1::{
+    self, ExtDataControlDeviceV1,
+};
+use wayland_protocols::ext::data_control::v1::client::ext_data_control_manager_v1::ExtDataControlManagerV1;
+use wayland_protocols::ext::data_control::v1::client::ext_data_control_offer_v1::ExtDataControlOfferV1;
+use wayland_protocols_wlr::data_control::v1::client::zwlr_data_control_device_v1::{
+    self, ZwlrDataControlDeviceV1,
+};
+use wayland_protocols_wlr::data_control::v1::client::zwlr_data_control_manager_v1::ZwlrDataControlManagerV1;
+use wayland_protocols_wlr::data_control::v1::client::zwlr_data_control_offer_v1::ZwlrDataControlOfferV1;
+use wl_clipboard_rs::paste::{self, ClipboardType, Seat};
+
+static ACTIVE: OnceLock<bool> = OnceLock::new();
+
+/// Whether this is a Wayland session, going by `WAYLAND_DISPLAY`, with a compositor that supports data control.
+/// Checked once, compositors don't gain or lose protocols while running.
+pub fn active() -> bool {
+    *ACTIVE.get_or_init(|| {
+        if std::env::var_os("WAYLAND_DISPLAY").is_none() {
+            return false;
+        }
+        match paste::get_mime_types(ClipboardType::Regular, Seat::Unspecified) {
+            Ok(_)
+            | Err(
+                paste::Error::NoSeats | paste::Error::ClipboardEmpty | paste::Error::NoMimeType,
+            ) => true,
+            Err(e) => {
+                log::warn!(
+                    "Can't use Wayland data control, going through X11 instead: {}",
+                    e
+                );
+                false
+            }
+        }
+    })
+}
+
+/// Call `on_change` every time the clipboard changes, until the connection to the compositor fails. Blocks, so it
+/// gets a thread of its own.
+pub fn watch(on_change: impl FnMut() + 'static) -> Result<(), anyhow::Error> {
+    let connection =
+        Connection::connect_to_env().context("could not connect to the Wayland compositor")?;
+    let (globals, mut queue) = registry_queue_init::<State>(&connection)
+        .context("could not list the compositor's globals")?;
+    let qh = queue.handle();
+
+    // the first seat, which is the one the clipboard plugin reads from too
+    let seat: WlSeat = globals
+        .bind(&qh, 1..=1, ())
+        .context("the compositor has no seat")?;
+    match globals.bind::<ExtDataControlManagerV1, _, _>(&qh, 1..=1, ()) {
+        Ok(manager) => {
+            manager.get_data_device(&seat, &qh, ());
+            log::info!("watching the clipboard through ext-data-control");
+        }
+        Err(_) => {
+            let manager: ZwlrDataControlManagerV1 = globals
+                .bind(&qh, 1..=2, ())
+                .context("the compositor supports neither ext-data-control nor wlr-data-control")?;
+            manager.get_data_device(&seat, &qh, ());
+            log::info!("watching the clipboard through wlr-data-control");
+        }
+    }
+
+    let mut state = State {
+        on_change: Box::new(on_change),
+        started: false,
+    };
+    loop {
+        queue
+            .blocking_dispatch(&mut state)
+            .context("lost the connection to the Wayland compositor")?;
+    }
+}
+
+struct State {
+    on_change: Box<dyn FnMut()>,
+    /// The device reports what is on the clipboard as soon as it is made, which isn't a change.
+    started: bool,
+}
+
+impl State {
+    fn selection(&mut self) {
+        if !self.started {
+            self.started = true;
+            return;
+        }
+        (self.on_change)();
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for State {
+    fn event(
+        _: &mut Self,
+        _: &WlRegistry,
+        _: <WlRegistry as Proxy>::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlSeat, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &WlSeat,
+        _: <WlSeat as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtDataControlManagerV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &ExtDataControlManagerV1,
+        _: <ExtDataControlManagerV1 as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrDataControlManagerV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &ZwlrDataControlManagerV1,
+        _: <ZwlrDataControlManagerV1 as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+// The contents are read through a connection of their own, so offers are only there to say the clipboard changed
+// and are let go of right away.
+impl Dispatch<ExtDataControlDeviceV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &ExtDataControlDeviceV1,
+        event: ext_data_control_device_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_data_control_device_v1::Event::Selection { id } => {
+                if let Some(offer) = id {
+                    offer.destroy();
+                }
+                state.selection();
+            }
+            ext_data_control_device_v1::Event::PrimarySelection { id: Some(offer) } => {
+                offer.destroy()
+            }
+            ext_data_control_device_v1::Event::Finished => {
+                log::warn!("the compositor stopped reporting clipboard changes")
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(State, ExtDataControlDeviceV1, [
+        ext_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ExtDataControlOfferV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrDataControlDeviceV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrDataControlDeviceV1,
+        event: zwlr_data_control_device_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_data_control_device_v1::Event::Selection { id } => {
+                if let Some(offer) = id {
+                    offer.destroy();
+                }
+                state.selection();
+            }
+            zwlr_data_control_device_v1::Event::PrimarySelection { id: Some(offer) } => {
+                offer.destroy()
+            }
+            zwlr_data_control_device_v1::Event::Finished => {
+                log::warn!("the compositor stopped reporting clipboard changes")
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(State, ZwlrDataControlDeviceV1, [
+        zwlr_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ZwlrDataControlOfferV1, ()),
+    ]);
+}
+
+impl Dispatch<ExtDataControlOfferV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &ExtDataControlOfferV1,
+        _: <ExtDataControlOfferV1 as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrDataControlOfferV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &ZwlrDataControlOfferV1,
+        _: <ZwlrDataControlOfferV1 as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::process::{Child, Command};
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+    use wl_clipboard_rs::copy::{self, MimeType, Source};
+
+    /// A headless weston on a socket of its own, stopped when dropped.
+    struct Compositor {
+        child: Child,
+    }
+
+    impl Compositor {
+        fn start(display: &str) -> Self {
+            let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| {
+                    let dir = std::env::temp_dir()
+                        .join(format!("purple-clip-xdg-{}", std::process::id()));
+                    std::fs::create_dir_all(&dir).unwrap();
+                    std::env::set_var("XDG_RUNTIME_DIR", &dir);
+                    dir
+                });
+            let child = Command::new("weston")
+                .arg("--backend=headless")
+                .arg(format!("--socket={}", display))
+                .spawn()
+                .expect("could not start weston");
+            let socket = runtime_dir.join(display);
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !socket.exists() {
+                assert!(
+                    Instant::now() < deadline,
+                    "weston didn't open {}",
+                    socket.display()
+                );
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Self { child }
+        }
+    }
+
+    impl Drop for Compositor {
+        fn drop(&mut self) {
+            _ = self.child.kill();
+            _ = self.child.wait();
+        }
+    }
+
+    fn set_clipboard(text: &str) {
+        copy::Options::new()
+            .copy(Source::Bytes(text.as_bytes().into()), MimeType::Text)
+            .unwrap();
+    }
+
+    fn clipboard() -> String {
+        let (mut pipe, _) = paste::get_contents(
+            ClipboardType::Regular,
+            Seat::Unspecified,
+            paste::MimeType::Text,
+        )
+        .unwrap();
+        let mut text = String::new();
+        pipe.read_to_string(&mut text).unwrap();
+        text
+    }
+
+    /// Runs against a headless weston, which has to support one of the data control protocols. Set
+    /// `PURPLE_TEST_WAYLAND_DISPLAY` to use a compositor that is already running instead, like a headless sway.
+    /// `cargo test wayland -- --ignored`
+    #[test]
+    #[ignore = "needs weston"]
+    fn picks_up_selection_changes() {
+        let _compositor = match std::env::var("PURPLE_TEST_WAYLAND_DISPLAY") {
+            Ok(display) => {
+                std::env::set_var("WAYLAND_DISPLAY", display);
+                None
+            }
+            Err(_) => {
+                let display = format!("purple-clip-test-{}", std::process::id());
+                let compositor = Compositor::start(&display);
+                std::env::set_var("WAYLAND_DISPLAY", display);
+                Some(compositor)
+            }
+        };
+        assert!(active(), "the compositor doesn't support data control");
+
+        let (changed, changes) = mpsc::channel();
+        std::thread::spawn(move || watch(move || _ = changed.send(())));
+        // let the watcher see what's there to begin with, which isn't a change
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(changes.try_recv().is_err());
+
+        for text in ["first", "second"] {
+            set_clipboard(text);
+            changes
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap_or_else(|_| panic!("copying {:?} wasn't noticed", text));
+            assert_eq!(clipboard(), text);
+        }
+    }
+}
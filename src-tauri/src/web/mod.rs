@@ -0,0 +1,372 @@
+//! Web client for phones and other devices that can't run the app. When turned on, a small HTTP server on the local
+//! network serves a page showing history and pinned items, where items can be copied out and text or images added
+//! to history. A browser gets in by opening a link with a one-time token, shown as a QR code in the app, which it
+//! trades for a session cookie. Sessions last until the server is stopped.
+
+use std::collections::HashSet;
+use std::io::{Cursor, Read};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rand::RngCore;
+use serde::Serialize;
+use tauri::{image::Image, AppHandle, Manager};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::clipboard_manager::ClipboardManager;
+use crate::contents::{Contents, Source};
+use crate::limits;
+use crate::settings::Settings;
+
+const PAGE: &str = include_str!("page.html");
+const FORBIDDEN: &str = "Open the link shown in the app to use the web client";
+const SESSION_COOKIE: &str = "session";
+/// How long a link can go unopened before its token stops working.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+const MAX_TEXT_LEN: usize = 1024 * 1024;
+const MAX_UPLOAD_LEN: usize = 32 * 1024 * 1024;
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebLink {
+    pub url: String,
+    /// The url as a QR code, an SVG document.
+    pub qr_svg: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub address: IpAddr,
+}
+
+#[derive(Default)]
+struct Auth {
+    /// The token in the last link handed out and when it was made, until it is used.
+    token: Option<(String, Instant)>,
+    sessions: HashSet<String>,
+}
+
+impl Auth {
+    /// Trade the one-time token for a new session.
+    fn redeem(&mut self, token: &str) -> Option<String> {
+        match self.token.take() {
+            Some((expected, made)) if expected == token && made.elapsed() < TOKEN_LIFETIME => {
+                let session = random_hex();
+                self.sessions.insert(session.clone());
+                Some(session)
+            }
+            // a wrong guess doesn't burn the real token
+            Some(other) if other.0 != token => {
+                self.token = Some(other);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct WebClient {
+    server: Arc<Server>,
+    /// Address put in links, the one the server is bound to unless it listens on all interfaces.
+    host: IpAddr,
+    port: u16,
+    auth: Arc<Mutex<Auth>>,
+}
+
+impl WebClient {
+    pub fn start(app: &AppHandle, settings: &Settings) -> Result<Self, anyhow::Error> {
+        let bind = match &settings.web_interface {
+            Some(address) => address
+                .parse()
+                .with_context(|| format!("{} is not an IP address", address))?,
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        let server = Server::http(SocketAddr::new(bind, settings.web_port))
+            .map_err(|e| anyhow!("failed to listen on {}:{}: {}", bind, settings.web_port, e))?;
+        let port = server
+            .server_addr()
+            .to_ip()
+            .map(|a| a.port())
+            .unwrap_or(settings.web_port);
+        let host = match bind.is_unspecified() {
+            true => interfaces()?
+                .into_iter()
+                .map(|i| i.address)
+                .find(IpAddr::is_ipv4)
+                .ok_or_else(|| anyhow!("no network interface to reach the web client on"))?,
+            false => bind,
+        };
+        log::info!("web client listening on {}:{}", bind, port);
+
+        let server = Arc::new(server);
+        let auth = Arc::new(Mutex::new(Auth::default()));
+        let (app, listener, requests_auth) = (app.clone(), Arc::clone(&server), Arc::clone(&auth));
+        std::thread::spawn(move || {
+            // ends when the server is unblocked
+            for request in listener.incoming_requests() {
+                let (app, auth) = (app.clone(), Arc::clone(&requests_auth));
+                std::thread::spawn(move || handle(&app, &auth, request));
+            }
+            log::info!("web client stopped");
+        });
+
+        Ok(Self {
+            server,
+            host,
+            port,
+            auth,
+        })
+    }
+
+    /// A new link for a browser to get in with, which replaces any link handed out before.
+    pub fn link(&self) -> Result<WebLink, anyhow::Error> {
+        let token = random_hex();
+        let url = format!(
+            "http://{}/?token={}",
+            SocketAddr::new(self.host, self.port),
+            token
+        );
+        self.auth
+            .lock()
+            .map_err(|e| anyhow!("Could not access web client sessions {}", e))?
+            .token = Some((token, Instant::now()));
+        let qr_svg = QrCode::new(&url)?
+            .render::<svg::Color>()
+            .min_dimensions(240, 240)
+            .build();
+        Ok(WebLink { url, qr_svg })
+    }
+}
+
+impl Drop for WebClient {
+    fn drop(&mut self) {
+        self.server.unblock();
+    }
+}
+
+/// Start or stop the web client to match the settings.
+pub fn apply(app: &AppHandle, settings: &Settings) {
+    let state = app.state::<Mutex<Option<WebClient>>>();
+    let Ok(mut web) = state
+        .lock()
+        .map_err(|e| log::error!("Could not access the web client {}", e))
+    else {
+        return;
+    };
+    // the old server has to let go of its port before a new one can take it
+    *web = None;
+    if settings.web_client {
+        *web = WebClient::start(app, settings)
+            .map_err(|e| log::error!("Could not start the web client: {:#}", e))
+            .ok();
+    }
+}
+
+/// Non-loopback addresses this device has, to choose which one the web client listens on.
+pub fn interfaces() -> Result<Vec<NetworkInterface>, anyhow::Error> {
+    Ok(if_addrs::get_if_addrs()
+        .context("failed to list network interfaces")?
+        .into_iter()
+        .filter(|i| !i.is_loopback())
+        .map(|i| NetworkInterface {
+            address: i.ip(),
+            name: i.name,
+        })
+        .collect())
+}
+
+fn handle(app: &AppHandle, auth: &Mutex<Auth>, mut request: Request) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let response = if *request.method() == Method::Get && path == "/" {
+        match query_param(query, "token") {
+            Some(token) => login(auth, token),
+            None if authorized(auth, &request) => Response::from_string(PAGE)
+                .with_header(header("Content-Type", "text/html; charset=utf-8")),
+            None => text(403, FORBIDDEN),
+        }
+    } else if !authorized(auth, &request) {
+        text(403, FORBIDDEN)
+    } else {
+        let method = request.method().clone();
+        api(app, &method, path, &mut request).unwrap_or_else(|e| {
+            log::warn!("web client request {} {} failed: {:#}", method, path, e);
+            text(400, &format!("{:#}", e))
+        })
+    };
+    if let Err(e) = request.respond(response) {
+        log::debug!("Could not respond to web client: {}", e);
+    }
+}
+
+fn api(
+    app: &AppHandle,
+    method: &Method,
+    path: &str,
+    request: &mut Request,
+) -> Result<HttpResponse, anyhow::Error> {
+    match (method, path.strip_prefix("/api/")) {
+        (Method::Get, Some("items")) => {
+            let items = with_manager(app, |cm| cm.snapshot())?;
+            Ok(Response::from_data(serde_json::to_vec(&items)?)
+                .with_header(header("Content-Type", "application/json")))
+        }
+        (Method::Get, Some(item)) if item.starts_with("items/") && item.ends_with("/image") => {
+            let id = item["items/".len()..item.len() - "/image".len()]
+                .parse()
+                .context("bad item id")?;
+            match with_manager(app, |cm| cm.get(id))? {
+                Some(item) => match item.as_ref() {
+                    Contents::Image { image, .. } => Ok(Response::from_data(encode_png(image)?)
+                        .with_header(header("Content-Type", "image/png"))),
+                    _ => Ok(text(404, "Item is not an image")),
+                },
+                None => Ok(text(404, "No such item")),
+            }
+        }
+        (Method::Post, Some("text")) => {
+            let text = String::from_utf8(read_body(request, MAX_TEXT_LEN)?)
+                .context("text was not UTF-8")?;
+            if text.is_empty() {
+                return Err(anyhow!("text was empty"));
+            }
+            let item = Contents::from_text(text).with_source(Source::Web);
+            with_manager(app, |cm| cm.add(item))?;
+            Ok(Response::from_string("").with_status_code(204))
+        }
+        (Method::Post, Some("image")) => {
+            let body = read_body(request, MAX_UPLOAD_LEN)?;
+            let image = decode_image(&body)?;
+            let (width, height) = image.dimensions();
+            let item = Contents::from_image(Image::new_owned(image.into_raw(), width, height))?;
+            with_manager(app, |cm| cm.add(item.with_source(Source::Web)))?;
+            Ok(Response::from_string("").with_status_code(204))
+        }
+        _ => Ok(text(404, "Not found")),
+    }
+}
+
+/// Decode an uploaded image within the limits on incoming items, checking its size before decoding the pixels.
+fn decode_image(body: &[u8]) -> Result<image::RgbaImage, anyhow::Error> {
+    let mut reader = ImageReader::new(Cursor::new(body))
+        .with_guessed_format()
+        .context("could not read image")?;
+    reader.limits(limits::image_limits());
+    let decoder = reader.into_decoder().context("could not read image")?;
+    let (width, height) = decoder.dimensions();
+    limits::check_image(width, height)?;
+    Ok(DynamicImage::from_decoder(decoder)
+        .context("could not read image")?
+        .into_rgba8())
+}
+
+fn login(auth: &Mutex<Auth>, token: &str) -> HttpResponse {
+    let session = match auth.lock() {
+        Ok(mut auth) => auth.redeem(token),
+        Err(e) => {
+            log::error!("Could not access web client sessions {}", e);
+            None
+        }
+    };
+    match session {
+        // redirect so the token doesn't stay in the address bar or history
+        Some(session) => text(303, "")
+            .with_header(header("Location", "/"))
+            .with_header(header(
+                "Set-Cookie",
+                &format!(
+                    "{}={}; HttpOnly; SameSite=Strict; Path=/",
+                    SESSION_COOKIE, session
+                ),
+            )),
+        None => text(
+            403,
+            "This link has already been used or has expired, show a new one in the app",
+        ),
+    }
+}
+
+fn authorized(auth: &Mutex<Auth>, request: &Request) -> bool {
+    let Some(session) = request
+        .headers()
+        .iter()
+        .filter(|h| h.field.equiv("Cookie"))
+        .flat_map(|h| h.value.as_str().split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+    else {
+        return false;
+    };
+    auth.lock()
+        .is_ok_and(|auth| auth.sessions.contains(session))
+}
+
+fn with_manager<T>(
+    app: &AppHandle,
+    f: impl FnOnce(&mut ClipboardManager) -> T,
+) -> Result<T, anyhow::Error> {
+    let state = app.state::<Mutex<Option<ClipboardManager>>>();
+    let mut cm = state
+        .lock()
+        .map_err(|e| anyhow!("Could not access the clipboard manager {}", e))?;
+    let cm = cm
+        .as_mut()
+        .ok_or_else(|| anyhow!("Clipboard manager is not running"))?;
+    Ok(f(cm))
+}
+
+fn read_body(request: &mut Request, limit: usize) -> Result<Vec<u8>, anyhow::Error> {
+    if request.body_length().is_some_and(|len| len > limit) {
+        return Err(anyhow!("upload is larger than {} bytes", limit));
+    }
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(limit as u64 + 1)
+        .read_to_end(&mut body)?;
+    if body.len() > limit {
+        return Err(anyhow!("upload is larger than {} bytes", limit));
+    }
+    Ok(body)
+}
+
+fn encode_png(image: &Image) -> Result<Vec<u8>, anyhow::Error> {
+    let buffer: image::RgbaImage =
+        image::ImageBuffer::from_raw(image.width(), image.height(), image.rgba().to_vec())
+            .ok_or_else(|| anyhow!("Could not convert image to an image buffer"))?;
+    let mut encoded = Cursor::new(Vec::new());
+    buffer.write_to(&mut encoded, ImageFormat::Png)?;
+    Ok(encoded.into_inner())
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn text(status: u16, body: &str) -> HttpResponse {
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "text/plain; charset=utf-8"))
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("header should be valid")
+}
+
+fn random_hex() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,211 @@
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+
+export type DeviceData = {
+    id: string;
+    name: string;
+    os: string;
+    version: number;
+    addresses: string[];
+    port: number;
+    static_address: string | null;
+}
+
+export type StaticPeerData = {
+    address: string;
+    device_id: string | null;
+    name: string | null;
+    os: string | null;
+    online: boolean;
+    last_error: string | null;
+}
+
+export type Direction = "send" | "receive" | "both" | "none";
+
+export type SharingPolicy = {
+    direction: Direction;
+    text: boolean;
+    image: boolean;
+    paths: boolean;
+    max_size: number | null;
+}
+
+export type TrustedDeviceData = {
+    id: string;
+    name: string;
+    os: string;
+    paired_at: number;
+    policy: SharingPolicy;
+    muted: boolean;
+}
+
+export type PairingCode = {
+    code: string;
+    words: string[];
+}
+
+export type QueuedItemData = {
+    device_id: string;
+    uid: string;
+    kind: "text" | "image" | "paths";
+    size: number;
+    queued_at: number;
+    pushed: boolean;
+}
+
+export type DeviceStatusData = {
+    device_id: string;
+    state: "connected" | "disconnected";
+    transport: "tcp" | "quic" | "unix" | null;
+    last_seen: number | null;
+    rtt_ms: number | null;
+    bytes_sent: number;
+    bytes_received: number;
+    last_error: string | null;
+}
+
+export type PublishedBoardData = {
+    id: string;
+    name: string;
+    items: number;
+    subscribers: string[];
+}
+
+export type AvailableBoardData = {
+    device_id: string;
+    id: string;
+    name: string;
+    items: number;
+    subscribed: boolean;
+}
+
+export type Delivery = "sent" | "queued";
+
+export type SendError = {
+    kind: "item_not_found" | "not_running" | "not_trusted" | "unsupported" | "not_allowed" | "too_large" | "other";
+    message: string;
+}
+
+type PairingRequest = {
+    device_id: string;
+    name: string;
+    words: string[];
+}
+
+type ItemRejected = {
+    device_id: string;
+    reason: string;
+}
+
+type DeviceRevoked = {
+    device_id: string;
+    issuer: string;
+}
+
+type PairingResult = {
+    device_id: string;
+    accepted: boolean;
+}
+
+export const devices = $state<DeviceData[]>([])
+export const trusted = $state<TrustedDeviceData[]>([])
+export const outbox = $state<QueuedItemData[]>([])
+export const staticPeers = $state<StaticPeerData[]>([])
+export const statuses = $state<Record<string, DeviceStatusData>>({})
+// why each device last turned away an item we sent it
+export const rejections = $state<Record<string, string>>({})
+export const publishedBoards = $state<PublishedBoardData[]>([])
+export const availableBoards = $state<AvailableBoardData[]>([])
+export const pairing = $state<{ request: PairingRequest | null, shown: (PairingCode & { device_id: string }) | null }>({
+    request: null,
+    shown: null
+})
+
+function replace<T>(target: T[], list: T[]) {
+    target.splice(0, target.length, ...list)
+}
+
+listen<DeviceData[]>("devices-update", (e) => {
+    replace(devices, e.payload)
+    requestStaticPeers()
+});
+
+listen<DeviceStatusData>("device-status", (e) => {
+    statuses[e.payload.device_id] = e.payload
+});
+
+listen<AvailableBoardData[]>("boards-available", (e) => {
+    replace(availableBoards, e.payload)
+});
+
+listen<PairingRequest>("pairing-request", (e) => {
+    pairing.request = e.payload
+});
+
+listen<ItemRejected>("item-rejected", (e) => {
+    rejections[e.payload.device_id] = e.payload.reason
+});
+
+// another device revoked one we trusted
+listen<DeviceRevoked>("device-revoked", () => {
+    requestTrusted()
+});
+
+listen<PairingResult>("pairing-complete", (e) => {
+    if (pairing.shown?.device_id === e.payload.device_id) {
+        pairing.shown = null
+    }
+    if (pairing.request?.device_id === e.payload.device_id) {
+        pairing.request = null
+    }
+    requestTrusted()
+});
+
+export const requestDevices = () => invoke<DeviceData[]>("list_devices").then((list) => replace(devices, list))
+export const requestTrusted = () => invoke<TrustedDeviceData[]>("list_trusted_devices").then((list) => replace(trusted, list))
+export const requestStatus = (deviceId: string) =>
+    invoke<DeviceStatusData>("get_device_status", { deviceId }).then((status) => (statuses[deviceId] = status))
+
+export const pairDevice = (deviceId: string, code?: string) =>
+    invoke<PairingCode | null>("pair_device", { deviceId, code }).then((shown) => {
+        if (shown) {
+            pairing.shown = { ...shown, device_id: deviceId }
+        }
+    })
+
+// rejects with a SendError
+export const sendItem = (id: number, deviceId: string) => invoke<Delivery>("send_item", { id, deviceId })
+
+export const requestOutbox = () => invoke<QueuedItemData[]>("list_outbox").then((list) => replace(outbox, list))
+export const clearOutbox = (deviceId?: string) => invoke("clear_outbox", { deviceId }).then(requestOutbox)
+
+export const requestStaticPeers = () => invoke<StaticPeerData[]>("list_static_peers").then((list) => replace(staticPeers, list))
+export const addStaticPeer = (address: string) => invoke("add_static_peer", { address }).then(requestStaticPeers)
+export const removeStaticPeer = (address: string) => invoke("remove_static_peer", { address }).then(requestStaticPeers)
+
+export const setDevicePolicy = (deviceId: string, policy: SharingPolicy) =>
+    invoke("set_device_policy", { deviceId, policy }).then(requestTrusted)
+
+export const setDeviceMuted = (deviceId: string, muted: boolean) =>
+    invoke("set_device_muted", { deviceId, muted }).then(requestTrusted)
+
+export const forgetDevice = (deviceId: string) => invoke("forget_device", { deviceId }).then(requestTrusted)
+export const revokeDevice = (deviceId: string, wipe: boolean) =>
+    invoke("revoke_device", { deviceId, wipe }).then(requestTrusted)
+
+export const requestPublishedBoards = () =>
+    invoke<PublishedBoardData[]>("list_published_boards").then((list) => replace(publishedBoards, list))
+export const publishBoard = (name: string, itemIds: number[]) =>
+    invoke<string>("publish_board", { name, itemIds }).then(requestPublishedBoards)
+export const updateBoard = (boardId: string, itemIds: number[], name?: string) =>
+    invoke("update_board", { boardId, name, itemIds }).then(requestPublishedBoards)
+export const deleteBoard = (boardId: string) => invoke("delete_board", { boardId }).then(requestPublishedBoards)
+export const revokeBoardSubscriber = (boardId: string, deviceId: string) =>
+    invoke("revoke_board_subscriber", { boardId, deviceId }).then(requestPublishedBoards)
+
+export const requestAvailableBoards = () =>
+    invoke<AvailableBoardData[]>("list_available_boards").then((list) => replace(availableBoards, list))
+export const subscribeBoard = (deviceId: string, boardId: string) =>
+    invoke("subscribe_board", { deviceId, boardId }).then(requestAvailableBoards)
+export const unsubscribeBoard = (boardId: string) =>
+    invoke("unsubscribe_board", { boardId }).then(requestAvailableBoards)
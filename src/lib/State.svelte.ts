@@ -1,6 +1,20 @@
 import { listen } from "@tauri-apps/api/event";
 
-export type ItemData = {
+// how an item got here, a device is named as it was when the item arrived
+export type Source =
+    | { type: "local" }
+    | { type: "device"; id: string; name: string }
+    | { type: "web" }
+    | { type: "cli" }
+
+// history and pinned entries say where and when they were copied, origin is null for items copied on this device
+type Copied = {
+    origin?: string | null;
+    time?: { wall: number; counter: number };
+    source?: Source;
+}
+
+export type ItemData = ({
     kind: "image" | "text";
     content: string;
     id: number;
@@ -8,18 +22,31 @@ export type ItemData = {
 } | {
     kind: "paths";
     content: [string];
+    // paths from another system that no path mapping covers, which can't be put on the clipboard
+    unmapped: string[];
     id: number;
     is_pinned: boolean
+}) & Copied
+
+// a board another device publishes that we subscribe to
+export type BoardData = {
+    id: string;
+    name: string;
+    device_id: string;
+    device_name: string;
+    items: ItemData[];
 }
 
 type UpdateMessage = {
     pinned: ItemData[],
-    history: ItemData[]
+    history: ItemData[],
+    boards: BoardData[]
 }
 
 export const state = $state<UpdateMessage>({
     pinned: [],
-    history: []
+    history: [],
+    boards: []
 }) 
 
 
@@ -35,6 +62,7 @@ listen<UpdateMessage>("update", (e) => {
 
     state.pinned.splice(0, state.pinned.length, ...e.payload.pinned)
     state.history.splice(0, state.history.length, ...e.payload.history)
+    state.boards.splice(0, state.boards.length, ...e.payload.boards)
 
     console.log(state)
 });
@@ -0,0 +1,49 @@
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+
+export type TransferState = "waiting" | "confirm" | "running" | "done" | "failed";
+
+export type TransferData = {
+    id: string;
+    device_id: string;
+    name: string;
+    received: number;
+    total: number;
+    state: TransferState;
+    error: string | null;
+}
+
+// how long a finished transfer stays on screen
+const FINISHED_TIMEOUT = 5000
+
+export const transfers = $state<TransferData[]>([])
+
+const remove = (id: string) => {
+    const index = transfers.findIndex((t) => t.id === id)
+    if (index !== -1) transfers.splice(index, 1)
+}
+
+listen<TransferData>("transfer-progress", ({ payload }) => {
+    const index = transfers.findIndex((t) => t.id === payload.id)
+    if (index === -1) {
+        transfers.push(payload)
+    } else {
+        transfers[index] = payload
+    }
+    if (payload.state === "done" || payload.state === "failed") {
+        setTimeout(() => remove(payload.id), FINISHED_TIMEOUT)
+    }
+});
+
+export const confirmTransfer = (transferId: string, accept: boolean) =>
+    invoke("confirm_transfer", { transferId, accept })
+
+export const formatSize = (bytes: number) => {
+    const units = ["B", "KB", "MB", "GB"]
+    let unit = 0
+    while (bytes >= 1024 && unit < units.length - 1) {
+        bytes /= 1024
+        unit++
+    }
+    return `${bytes.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`
+}